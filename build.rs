@@ -0,0 +1,18 @@
+// Passes build details to `version_info`: the target triple, the profile, and the git
+// commit when building from a checkout.
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rustc-env=RUBIN_TARGET={}", std::env::var("TARGET").unwrap_or_default());
+    println!("cargo:rustc-env=RUBIN_PROFILE={}", std::env::var("PROFILE").unwrap_or_default());
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=RUBIN_COMMIT={}", commit);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
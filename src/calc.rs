@@ -0,0 +1,243 @@
+// Arithmetic for `calc`: `+ - * / %`, unary minus, parentheses, and integer or decimal
+// numbers. Integers stay exact until a division doesn't come out even or a decimal
+// joins in; after that the result is a float.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl Value {
+    fn as_float(self) -> f64 {
+        match self {
+            Value::Int(n) => n as f64,
+            Value::Float(x) => x,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token {
+    Number(Value),
+    Operator(char),
+    Open,
+    Close,
+}
+
+pub fn evaluate(expression: &str) -> Result<Value, String> {
+    let tokens = tokenize(expression)?;
+    if tokens.is_empty() {
+        return Err("empty expression".to_string());
+    }
+    let mut parser = Parser { tokens: &tokens, position: 0 };
+    let value = parser.expression()?;
+    match parser.peek() {
+        None => Ok(value),
+        Some((Token::Close, column)) => Err(format!("unmatched `)` at column {}", column)),
+        Some((token, column)) => Err(format!("unexpected {} at column {}", describe(token), column)),
+    }
+}
+
+// Each token with the (1-based) column it starts at.
+fn tokenize(expression: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let column = index + 1;
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '+' | '-' | '*' | '/' | '%' => Token::Operator(c),
+            '(' => Token::Open,
+            ')' => Token::Close,
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut literal = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                    literal.push(c);
+                }
+                let value = if literal.contains('.') {
+                    literal.parse().map(Value::Float).ok()
+                } else {
+                    literal.parse().map(Value::Int).ok()
+                };
+                Token::Number(value.ok_or_else(|| format!("invalid number `{}` at column {}", literal, column))?)
+            }
+            c => return Err(format!("unexpected `{}` at column {}", c, column)),
+        };
+        tokens.push((token, column));
+    }
+    Ok(tokens)
+}
+
+fn describe(token: Token) -> String {
+    match token {
+        Token::Number(value) => format!("number `{}`", value),
+        Token::Operator(c) => format!("`{}`", c),
+        Token::Open => "`(`".to_string(),
+        Token::Close => "`)`".to_string(),
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [(Token, usize)],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<(Token, usize)> {
+        self.tokens.get(self.position).copied()
+    }
+
+    fn next_operator(&mut self, operators: &[char]) -> Option<char> {
+        match self.peek() {
+            Some((Token::Operator(c), _)) if operators.contains(&c) => {
+                self.position += 1;
+                Some(c)
+            }
+            _ => None,
+        }
+    }
+
+    // expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<Value, String> {
+        let mut value = self.term()?;
+        while let Some(operator) = self.next_operator(&['+', '-']) {
+            value = apply(operator, value, self.term()?)?;
+        }
+        Ok(value)
+    }
+
+    // term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<Value, String> {
+        let mut value = self.unary()?;
+        while let Some(operator) = self.next_operator(&['*', '/', '%']) {
+            value = apply(operator, value, self.unary()?)?;
+        }
+        Ok(value)
+    }
+
+    // unary := ('-' | '+') unary | primary
+    fn unary(&mut self) -> Result<Value, String> {
+        match self.next_operator(&['-', '+']) {
+            Some('-') => match self.unary()? {
+                Value::Int(n) => n.checked_neg().map(Value::Int).ok_or_else(overflow),
+                Value::Float(x) => Ok(Value::Float(-x)),
+            },
+            Some(_) => self.unary(),
+            None => self.primary(),
+        }
+    }
+
+    // primary := number | '(' expression ')'
+    fn primary(&mut self) -> Result<Value, String> {
+        let Some((token, column)) = self.peek() else {
+            return Err("unexpected end of expression".to_string());
+        };
+        self.position += 1;
+        match token {
+            Token::Number(value) => Ok(value),
+            Token::Open => {
+                let value = self.expression()?;
+                match self.peek() {
+                    Some((Token::Close, _)) => {
+                        self.position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(format!("unmatched `(` at column {}", column)),
+                }
+            }
+            token => Err(format!("unexpected {} at column {}", describe(token), column)),
+        }
+    }
+}
+
+fn overflow() -> String {
+    "integer overflow".to_string()
+}
+
+fn apply(operator: char, left: Value, right: Value) -> Result<Value, String> {
+    if matches!(operator, '/' | '%') && right.as_float() == 0.0 {
+        return Err("division by zero".to_string());
+    }
+    if let (Value::Int(a), Value::Int(b)) = (left, right) {
+        let exact = match operator {
+            '+' => a.checked_add(b),
+            '-' => a.checked_sub(b),
+            '*' => a.checked_mul(b),
+            '%' => a.checked_rem(b),
+            _ if a.checked_rem(b) == Some(0) => a.checked_div(b),
+            // An uneven division falls through to floats.
+            _ => return Ok(Value::Float(a as f64 / b as f64)),
+        };
+        return exact.map(Value::Int).ok_or_else(overflow);
+    }
+    let (a, b) = (left.as_float(), right.as_float());
+    Ok(Value::Float(match operator {
+        '+' => a + b,
+        '-' => a - b,
+        '*' => a * b,
+        '/' => a / b,
+        _ => a % b,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(Value::Int(7)));
+        assert_eq!(evaluate("10 - 4 - 3"), Ok(Value::Int(3)));
+        assert_eq!(evaluate("2 * 3 % 4"), Ok(Value::Int(2)));
+        assert_eq!(evaluate("-2 * -3"), Ok(Value::Int(6)));
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(Value::Int(9)));
+        assert_eq!(evaluate("-(2 + 3)"), Ok(Value::Int(-5)));
+        assert_eq!(evaluate("((4))"), Ok(Value::Int(4)));
+    }
+
+    #[test]
+    fn division_stays_exact_when_it_can() {
+        assert_eq!(evaluate("8 / 2"), Ok(Value::Int(4)));
+        assert_eq!(evaluate("7 / 2"), Ok(Value::Float(3.5)));
+        assert_eq!(evaluate("1.5 + 1"), Ok(Value::Float(2.5)));
+    }
+
+    #[test]
+    fn mismatched_parentheses() {
+        assert_eq!(evaluate("(1 + 2"), Err("unmatched `(` at column 1".to_string()));
+        assert_eq!(evaluate("1 + 2)"), Err("unmatched `)` at column 6".to_string()));
+    }
+
+    #[test]
+    fn unexpected_tokens() {
+        assert_eq!(evaluate("2 3"), Err("unexpected number `3` at column 3".to_string()));
+        assert_eq!(evaluate("2 +"), Err("unexpected end of expression".to_string()));
+        assert_eq!(evaluate("2 * * 3"), Err("unexpected `*` at column 5".to_string()));
+        assert_eq!(evaluate("2 ^ 3"), Err("unexpected `^` at column 3".to_string()));
+        assert_eq!(evaluate("1.2.3"), Err("invalid number `1.2.3` at column 1".to_string()));
+        assert_eq!(evaluate("  "), Err("empty expression".to_string()));
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        assert_eq!(evaluate("1 / 0"), Err("division by zero".to_string()));
+        assert_eq!(evaluate("1 % (2 - 2)"), Err("division by zero".to_string()));
+        assert_eq!(evaluate("9223372036854775807 + 1"), Err("integer overflow".to_string()));
+    }
+}
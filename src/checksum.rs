@@ -0,0 +1,81 @@
+// File checksums, used by `copy --verify`. Files are hashed in chunks so large ones
+// never have to fit in memory. Hashing goes through `Digest`, so the algorithm can be
+// swapped without touching the callers.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub trait Digest: Default {
+    // Short name for messages, like "sha256".
+    const NAME: &'static str;
+
+    fn update(&mut self, data: &[u8]);
+    fn finish(self) -> Vec<u8>;
+}
+
+pub fn file_digest<D: Digest>(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut digest = D::default();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => digest.update(&buffer[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(digest.finish())
+}
+
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub use sha2::Sha256;
+
+impl Digest for Sha256 {
+    const NAME: &'static str = "sha256";
+
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+
+    fn finish(self) -> Vec<u8> {
+        sha2::Digest::finalize(self).to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256_hex(data: &[u8]) -> String {
+        let mut digest = Sha256::default();
+        digest.update(data);
+        to_hex(&digest.finish())
+    }
+
+    #[test]
+    fn sha256_known_digests() {
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(
+            sha256_hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_same_digest_however_input_is_split() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let whole = sha256_hex(&data);
+        for split in [1, 55, 56, 63, 64, 65, 999] {
+            let mut digest = Sha256::default();
+            digest.update(&data[..split]);
+            digest.update(&data[split..]);
+            assert_eq!(to_hex(&digest.finish()), whole, "split at {}", split);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+// Tab completion for the interactive prompt. The shell refreshes the snapshot in
+// `ShellHelper` before every prompt, since the editor can't borrow the shell itself.
+
+use std::fs;
+use std::path::PathBuf;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::parser::ESCAPABLE;
+use crate::{CustomCommand, custom_arg_candidates};
+
+#[derive(Default)]
+pub struct ShellHelper {
+    pub current_dir: PathBuf,
+    pub builtins: Vec<&'static str>,
+    pub custom_commands: Vec<CustomCommand>,
+    pub aliases: Vec<String>,
+}
+
+// The word under the cursor, as the parser would see it.
+struct PartialWord {
+    // Byte offset in the line where the word (including any opening quote) starts.
+    start: usize,
+    // The word with quotes and escapes removed.
+    value: String,
+    // Set while the cursor is still inside an unterminated quote.
+    open_quote: Option<char>,
+    // Name of the command this word is an argument to, or `None` if it is the command.
+    command: Option<String>,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let word = partial_word(&line[..pos]);
+        let mut candidates = Vec::new();
+
+        match &word.command {
+            None if !word.value.contains(['/', '\\']) => {
+                let mut names: Vec<&str> = self
+                    .builtins
+                    .iter()
+                    .copied()
+                    .chain(self.custom_commands.iter().map(|c| c.name.as_str()))
+                    .chain(self.aliases.iter().map(String::as_str))
+                    .filter(|name| name.starts_with(&word.value))
+                    .collect();
+                names.sort();
+                names.dedup();
+                for name in names {
+                    candidates.push(Pair { display: name.to_string(), replacement: quote_word(name, word.open_quote, true) });
+                }
+            }
+            Some(command) if self.custom_commands.iter().any(|c| &c.name == command && c.completion.is_some()) => {
+                for candidate in custom_arg_candidates(&self.custom_commands, &self.current_dir, command, &word.value) {
+                    let replacement = quote_word(&candidate, word.open_quote, true);
+                    candidates.push(Pair { display: candidate, replacement });
+                }
+            }
+            _ => candidates = self.complete_path(&word),
+        }
+        Ok((word.start, candidates))
+    }
+}
+
+impl ShellHelper {
+    // Entries of the directory named by everything up to the last separator, with a `/`
+    // after directories so completion can carry on into them.
+    fn complete_path(&self, word: &PartialWord) -> Vec<Pair> {
+        let (dir, prefix) = match word.value.rfind(['/', '\\']) {
+            Some(index) => word.value.split_at(index + 1),
+            None => ("", word.value.as_str()),
+        };
+        let Ok(entries) = fs::read_dir(self.current_dir.join(dir)) else {
+            return Vec::new();
+        };
+
+        let mut matches: Vec<(String, bool)> = entries
+            .filter_map(Result::ok)
+            .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.path().is_dir()))
+            .filter(|(name, _)| name.starts_with(prefix) && (!name.starts_with('.') || prefix.starts_with('.')))
+            .collect();
+        matches.sort();
+
+        matches
+            .into_iter()
+            .map(|(name, is_dir)| {
+                let display = if is_dir { format!("{}/", name) } else { name.clone() };
+                let full = format!("{}{}", dir, display);
+                Pair { display, replacement: quote_word(&full, word.open_quote, !is_dir) }
+            })
+            .collect()
+    }
+}
+
+// Re-applies the quoting the user started with, or escapes special characters if they
+// didn't quote. `finished` closes the quote and adds a space, ready for the next word.
+fn quote_word(text: &str, open_quote: Option<char>, finished: bool) -> String {
+    let mut quoted = String::new();
+    match open_quote {
+        Some(quote) => {
+            quoted.push(quote);
+            for c in text.chars() {
+                if quote == '"' && matches!(c, '"' | '\\' | '$') {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            if finished {
+                quoted.push(quote);
+            }
+        }
+        None => {
+            for c in text.chars() {
+                if ESCAPABLE.contains(&c) {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+        }
+    }
+    if finished {
+        quoted.push(' ');
+    }
+    quoted
+}
+
+// Follows the same quoting rules as `parser::tokenize`, without variable expansion, to
+// find where the word being completed begins.
+fn partial_word(line: &str) -> PartialWord {
+    let mut word = PartialWord { start: 0, value: String::new(), open_quote: None, command: None };
+    let mut in_word = false;
+    let mut words_in_stage: Vec<String> = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        if let Some(quote) = word.open_quote {
+            match c {
+                _ if c == quote => word.open_quote = None,
+                '\\' if quote == '"' && chars.peek().is_some_and(|(_, next)| matches!(next, '"' | '\\' | '$')) => {
+                    word.value.push(chars.next().map_or(c, |(_, next)| next));
+                }
+                _ => word.value.push(c),
+            }
+            continue;
+        }
+        match c {
+            ' ' | '\t' | '|' | '>' | ';' | '&' => {
+                if in_word {
+                    words_in_stage.push(std::mem::take(&mut word.value));
+                    in_word = false;
+                }
+                // After `|`, `;`, `&&` or `||` the next word is a command again.
+                if c != ' ' && c != '\t' && c != '>' {
+                    words_in_stage.clear();
+                }
+            }
+            _ => {
+                if !in_word {
+                    in_word = true;
+                    word.start = index;
+                }
+                match c {
+                    '\'' | '"' => word.open_quote = Some(c),
+                    '\\' if chars.peek().is_some_and(|(_, next)| ESCAPABLE.contains(next)) => {
+                        word.value.push(chars.next().map_or(c, |(_, next)| next));
+                    }
+                    _ => word.value.push(c),
+                }
+            }
+        }
+    }
+    if !in_word {
+        word.start = line.len();
+    }
+    word.command = words_in_stage.first().cloned();
+    word
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CompletionSpec;
+    use rustyline::history::DefaultHistory;
+
+    fn complete(helper: &ShellHelper, line: &str) -> (usize, Vec<String>) {
+        let history = DefaultHistory::new();
+        let (start, pairs) = helper.complete(line, line.len(), &Context::new(&history)).unwrap();
+        (start, pairs.into_iter().map(|pair| pair.replacement).collect())
+    }
+
+    #[test]
+    fn command_names_complete_from_builtins_and_custom_commands() {
+        let helper = ShellHelper {
+            builtins: vec!["copy", "cd"],
+            custom_commands: vec![CustomCommand {
+                name: "co".to_string(),
+                definition: "echo".to_string(),
+                description: String::new(),
+                completion: None,
+            }],
+            ..ShellHelper::default()
+        };
+        assert_eq!(complete(&helper, "co"), (0, vec!["co ".to_string(), "copy ".to_string()]));
+        assert_eq!(complete(&helper, "dir | c"), (6, vec!["cd ".to_string(), "co ".to_string(), "copy ".to_string()]));
+    }
+
+    #[test]
+    fn custom_command_arguments_come_from_its_spec() {
+        let helper = ShellHelper {
+            custom_commands: vec![CustomCommand {
+                name: "deploy".to_string(),
+                definition: "echo".to_string(),
+                description: String::new(),
+                completion: Some(CompletionSpec::Words(vec!["staging".to_string(), "production".to_string(), "preview".to_string()])),
+            }],
+            ..ShellHelper::default()
+        };
+        assert_eq!(complete(&helper, "deploy pr"), (7, vec!["preview ".to_string(), "production ".to_string()]));
+        assert_eq!(complete(&helper, "deploy x"), (7, Vec::new()));
+    }
+
+    #[test]
+    fn paths_complete_with_the_quoting_already_typed() {
+        let dir = std::env::temp_dir().join(format!("rubin-completion-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("my docs")).unwrap();
+        fs::write(dir.join("my file.txt"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+        let helper = ShellHelper { current_dir: dir.clone(), ..ShellHelper::default() };
+
+        assert_eq!(complete(&helper, "type my"), (5, vec!["my\\ docs/".to_string(), "my\\ file.txt ".to_string()]));
+        assert_eq!(complete(&helper, "type \"my f"), (5, vec!["\"my file.txt\" ".to_string()]));
+        assert_eq!(complete(&helper, "type 'my d"), (5, vec!["'my docs/".to_string()]));
+        assert_eq!(complete(&helper, "type ").1.len(), 2);
+        assert_eq!(complete(&helper, "type .h").1, [".hidden "]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn aliases_complete_as_commands_after_connectors() {
+        let helper = ShellHelper { builtins: vec!["copy"], aliases: vec!["co".to_string()], ..ShellHelper::default() };
+        assert_eq!(complete(&helper, "co"), (0, vec!["co ".to_string(), "copy ".to_string()]));
+        assert_eq!(complete(&helper, "cd x && co"), (8, vec!["co ".to_string(), "copy ".to_string()]));
+        assert_eq!(complete(&helper, "cd x; co"), (6, vec!["co ".to_string(), "copy ".to_string()]));
+    }
+}
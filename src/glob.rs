@@ -0,0 +1,123 @@
+// Minimal shell-style wildcard matching: `*` matches any run of characters, `?` matches
+// exactly one, and `[abc]` / `[a-z]` / `[!abc]` match one character from a set.
+
+pub fn has_wildcards(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    // Iterative with a single backtrack point for the most recent `*`, which keeps this
+    // linear-ish instead of exponential on patterns like `*a*a*a*`.
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+            continue;
+        }
+        if p < pattern.len() {
+            if let Some((matched, consumed)) = match_single(&pattern[p..], text[t]) {
+                if matched {
+                    p += consumed;
+                    t += 1;
+                    continue;
+                }
+            }
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Matches one pattern element against `c`, returning whether it matched and how many
+// pattern characters the element spans. `None` means the element was `*`.
+fn match_single(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    match pattern[0] {
+        '*' => None,
+        '?' => Some((true, 1)),
+        '[' => match pattern.iter().skip(1).position(|ch| *ch == ']') {
+            // `[]` or an unterminated `[` is treated as a literal bracket.
+            Some(end) if end > 0 => {
+                let set = &pattern[1..=end];
+                let (negated, set) = match set.first() {
+                    Some('!') | Some('^') => (true, &set[1..]),
+                    _ => (false, set),
+                };
+                Some((set_contains(set, c) != negated, end + 2))
+            }
+            _ => Some((c == '[', 1)),
+        },
+        literal => Some((literal == c, 1)),
+    }
+}
+
+fn set_contains(set: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if set[i] <= c && c <= set[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", ".rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+        assert!(matches("*a*a*", "banana"));
+        assert!(!matches("*a*a*a*a*", "banana"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file.txt"));
+        assert!(!matches("file?.txt", "file10.txt"));
+    }
+
+    #[test]
+    fn brackets_match_sets_and_ranges() {
+        assert!(matches("[abc].md", "b.md"));
+        assert!(matches("log[0-9]", "log7"));
+        assert!(!matches("log[!0-9]", "log7"));
+        assert!(matches("log[^0-9]", "logx"));
+        // An unterminated `[` is just a character.
+        assert!(matches("a[b", "a[b"));
+    }
+
+    #[test]
+    fn no_match() {
+        assert!(!matches("*.rs", "README.md"));
+        assert!(!has_wildcards("plain.txt"));
+        assert!(has_wildcards("*.txt"));
+    }
+}
@@ -1,313 +1,8958 @@
-use std::collections::HashMap;
+mod calc;
+mod checksum;
+mod completion;
+mod glob;
+mod parser;
+mod progress;
+mod script;
+
+use checksum::Digest;
+use completion::ShellHelper;
+use parser::{ChainCommand, Connector, ESCAPABLE, Token};
+use progress::Progress;
+use script::Statement;
+
+// Built-in output goes through `Shell::out` so it can be redirected. Write errors (say,
+// a full disk behind `>`) are deliberately ignored, as with `println!` on a closed pipe.
+macro_rules! out {
+    ($shell:expr, $($arg:tt)*) => {{
+        let _ = write!($shell.out, $($arg)*);
+    }};
+}
+
+// Reports an error from a built-in and marks the command as failed, which is the status
+// `&&`, `||` and `$?` see.
+macro_rules! fail {
+    ($shell:expr, $($arg:tt)*) => {{
+        eprintln!($($arg)*);
+        $shell.status = 1;
+    }};
+}
+
+macro_rules! outln {
+    ($shell:expr) => {{
+        let _ = writeln!($shell.out);
+    }};
+    ($shell:expr, $($arg:tt)*) => {{
+        let _ = writeln!($shell.out, $($arg)*);
+    }};
+}
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::process::{Command, exit};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::process::{Child, ChildStdout, Command, ExitStatus, Stdio, exit};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use regex::Regex;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::config::Configurer;
+use rustyline::{CompletionType, Config, Editor};
+use serde::{Deserialize, Serialize};
 
 struct Shell {
+    out: Output,                         // Where built-ins write; swapped out for `>`/`>>`
+    input: Input,                        // Where the prompt and built-ins read from
+    piped_input: Option<Vec<u8>>,        // Output of the previous pipeline stage, if any
     current_dir: PathBuf,
     history: Vec<PathBuf>,
     history_index: usize,
+    previous_dir: Option<PathBuf>,       // Where we were before the last directory change, for `cd -`
+    dir_stack: Vec<PathBuf>,             // Directories saved by `pushd`, most recent last
     custom_commands: Vec<CustomCommand>, // Store custom commands in a vector
     env_vars: HashMap<String, String>,   // Store custom environment variables
+    exported: BTreeSet<String>,          // Names in env_vars also set in the process environment
+    aliases: BTreeMap<String, String>,   // alias name -> the text it expands to
+    hooks: Hooks,                        // Run before and after each interactive command
+    running_hooks: bool,                 // Set while hooks run, so nothing they do sets off more
+    hook_command: Option<String>,        // The command hooks are running around, as `$RUBIN_LAST_CMD`
+    undo_stack: Vec<FileOperation>,      // Most recent file operation last
+    safe_mode: bool,                     // Set by --safe or RUBIN_SAFE_MODE at startup
+    guard_override: bool,                // The current command was given --override
+    options: ShellOptions,               // Toggled with `set`
+    state_dirty: bool,                   // Custom commands or env vars changed since the last save
+    status: i32,                         // Exit status of the command being run; set by `fail!`
+    last_status: i32,                    // Exit status of the previous command, as `$?`
+    command_history: Vec<String>,        // Entered command lines, oldest first
+    command_history_dirty: bool,         // command_history changed since it was last written out
+    editor: Option<Editor<ShellHelper, DefaultHistory>>, // Line editor for interactive input; None when stdin isn't a terminal
+    jobs: Vec<Job>,                      // Background commands started with `&`, oldest first
+    exit_warned: bool,                   // `exit` was refused because of running jobs; the next one goes ahead
+    log_failed: bool,                    // Writing the command log failed once; no more attempts
+    clipboard: Option<arboard::Clipboard>, // The system clipboard, once `copy-clip` or `paste-clip` opens it
+}
+
+// Shell-wide switches, set with `set <name> on|off` and `unset <name>`; the first three
+// also with `set -e`, `set -x` and `set -u`.
+struct ShellOptions {
+    errexit: bool,   // Stop a script at the first failing command
+    xtrace: bool,    // Print each command before running it
+    nounset: bool,   // Treat expanding an unset variable as an error
+    autocd: bool,    // A directory name on its own means `cd` into it
+    noclobber: bool, // `>` and `copy` refuse to overwrite existing files
+    color: bool,     // Color output on a terminal (NO_COLOR also turns it off)
+    trash: bool,     // `rm` and `rmdir -r` move things to the trash instead of deleting them
+    logging: bool,   // Append each command line run to the command log
+}
+
+impl Default for ShellOptions {
+    fn default() -> Self {
+        ShellOptions { errexit: false, xtrace: false, nounset: false, autocd: false, noclobber: false, color: true, trash: false, logging: false }
+    }
+}
+
+impl ShellOptions {
+    const NAMES: [&'static str; 8] = ["errexit", "xtrace", "nounset", "autocd", "noclobber", "color", "trash", "logging"];
+
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "errexit" => Some(&mut self.errexit),
+            "xtrace" => Some(&mut self.xtrace),
+            "nounset" => Some(&mut self.nounset),
+            "autocd" => Some(&mut self.autocd),
+            "noclobber" => Some(&mut self.noclobber),
+            "color" => Some(&mut self.color),
+            "trash" => Some(&mut self.trash),
+            "logging" => Some(&mut self.logging),
+            _ => None,
+        }
+    }
+
+    fn flag(&self, name: &str) -> Option<bool> {
+        match name {
+            "errexit" => Some(self.errexit),
+            "xtrace" => Some(self.xtrace),
+            "nounset" => Some(self.nounset),
+            "autocd" => Some(self.autocd),
+            "noclobber" => Some(self.noclobber),
+            "color" => Some(self.color),
+            "trash" => Some(self.trash),
+            "logging" => Some(self.logging),
+            _ => None,
+        }
+    }
+}
+
+struct Job {
+    id: usize,
+    command: String,
+    child: Child,
+}
+
+// What survives between sessions, stored as JSON at `state_file_path()`.
+#[derive(Default, Serialize, Deserialize)]
+struct PersistedState {
+    #[serde(default)]
+    custom_commands: Vec<CustomCommand>,
+    #[serde(default)]
+    env_vars: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    exported: BTreeSet<String>,
+    #[serde(default)]
+    aliases: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    hooks: Hooks,
+}
+
+// Command lines run around each interactively entered one, added with `hook add`.
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Hooks {
+    #[serde(default)]
+    pre: Vec<String>,
+    #[serde(default)]
+    post: Vec<String>,
+}
+
+impl Hooks {
+    fn is_empty(&self) -> bool {
+        self.pre.is_empty() && self.post.is_empty()
+    }
+
+    fn stage_mut(&mut self, stage: &str) -> Option<&mut Vec<String>> {
+        match stage {
+            "pre" => Some(&mut self.pre),
+            "post" => Some(&mut self.post),
+            _ => None,
+        }
+    }
+}
+
+// What's in the trash, kept as `index.json` inside it.
+#[derive(Default, Serialize, Deserialize)]
+struct TrashIndex {
+    #[serde(default)]
+    entries: Vec<TrashEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrashEntry {
+    // Where it was deleted from, as an absolute path.
+    original: PathBuf,
+    // Its name in the trash: the original file name, timestamped if that was taken.
+    stored: String,
+    // Seconds since the Unix epoch.
+    deleted: u64,
+}
+
+// Guards against runaway alias chains; a chain this long is almost certainly a loop.
+const ALIAS_DEPTH_LIMIT: usize = 16;
+
+// Entries kept in the command and directory histories unless RUBIN_HISTORY_SIZE says otherwise.
+const DEFAULT_HISTORY_SIZE: usize = 1000;
+
+// How many file operations `undo` can step back through.
+const UNDO_LIMIT: usize = 20;
+
+// Predicates for `find`, ordered from cheapest to most expensive to evaluate.
+#[derive(Default)]
+struct FindQuery {
+    name: Option<String>,
+    kind: Option<EntryKind>,
+    max_depth: Option<usize>,
+    newer_than: Option<Duration>,
+    size: Option<SizeFilter>,
+    contains: Option<String>,
+}
+
+#[derive(Default)]
+struct GrepOptions {
+    line_numbers: bool,
+    recursive: bool,
+    // Prefix matches with the file they came from; on when searching more than one.
+    show_names: bool,
+    // Print only the matched text, one match to a line (`-o`).
+    only_matching: bool,
+    // Color the matched text; on when printing to a terminal.
+    highlight: bool,
+    matched_lines: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum EntryKind {
+    File,
+    Dir,
+}
+
+enum SizeFilter {
+    Larger(u64),
+    Smaller(u64),
+    Exactly(u64),
+}
+
+impl SizeFilter {
+    fn parse(text: &str) -> Option<Self> {
+        if let Some(size) = text.strip_prefix('+') {
+            parse_size(size).map(SizeFilter::Larger)
+        } else if let Some(size) = text.strip_prefix('-') {
+            parse_size(size).map(SizeFilter::Smaller)
+        } else {
+            parse_size(text).map(SizeFilter::Exactly)
+        }
+    }
+
+    fn accepts(&self, len: u64) -> bool {
+        match *self {
+            SizeFilter::Larger(size) => len > size,
+            SizeFilter::Smaller(size) => len < size,
+            SizeFilter::Exactly(size) => len == size,
+        }
+    }
+}
+
+enum Output {
+    Stdout,
+    File(fs::File),
+    // Captured for the next stage of a pipeline.
+    Buffer(Vec<u8>),
+}
+
+impl Output {
+    // What a spawned child should use as stdout so it honours the same redirection.
+    // Buffered output has to be read back from a pipe, which `run_child` takes care of.
+    fn child_stdio(&self) -> Stdio {
+        match self {
+            Output::Stdout => Stdio::inherit(),
+            Output::File(file) => file.try_clone().map(Stdio::from).unwrap_or_else(|_| Stdio::inherit()),
+            Output::Buffer(_) => Stdio::piped(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Stdout => io::stdout().write(buf),
+            Output::File(file) => file.write(buf),
+            Output::Buffer(buffer) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().flush(),
+            Output::File(file) => file.flush(),
+            Output::Buffer(_) => Ok(()),
+        }
+    }
+}
+
+// Where the prompt and built-ins read from when nothing is piped in. A `Reader` stands
+// in for stdin, so a shell can be driven from a script in memory.
+enum Input {
+    Stdin,
+    // Only the tests build one, so outside them it's unused.
+    #[cfg_attr(not(test), allow(dead_code))]
+    Reader(Rc<RefCell<Box<dyn BufRead>>>),
+}
+
+impl Input {
+    fn read_line(&self, line: &mut String) -> io::Result<usize> {
+        match self {
+            Input::Stdin => io::stdin().read_line(line),
+            Input::Reader(reader) => reader.borrow_mut().read_line(line),
+        }
+    }
+
+    fn reader(&self) -> Box<dyn BufRead> {
+        match self {
+            Input::Stdin => Box::new(io::stdin().lock()),
+            Input::Reader(reader) => Box::new(SharedReader { inner: Rc::clone(reader), buffer: Vec::new() }),
+        }
+    }
+}
+
+// A handle on an `Input::Reader` that built-ins can own. It only consumes what the
+// caller consumes, so whatever a command leaves unread is still there for the prompt.
+struct SharedReader {
+    inner: Rc<RefCell<Box<dyn BufRead>>>,
+    buffer: Vec<u8>,
+}
+
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl BufRead for SharedReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffer.is_empty() {
+            self.buffer = self.inner.borrow_mut().fill_buf()?.to_vec();
+        }
+        Ok(&self.buffer)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.borrow_mut().consume(amount);
+        self.buffer.drain(..amount.min(self.buffer.len()));
+    }
+}
+
+// One command of a pipeline, with its optional `>`/`>>` target.
+struct Stage {
+    words: Vec<String>,
+    redirect: Option<(String, bool)>,
+}
+
+enum PipeInput {
+    Inherit,
+    Bytes(Vec<u8>),
+    Child(ChildStdout),
+}
+
+// Things safe mode refuses to do without `--override`.
+enum Risk<'a> {
+    Overwrite(&'a Path),
+    // Any path a destructive command writes to or removes; checked against `current_dir`.
+    Modify(&'a Path),
+    // Deleting a whole tree; always blocked in safe mode.
+    RecursiveDelete(&'a Path),
 }
 
-#[derive(Debug)]
+// What `move` and `rename` do when the destination already exists.
+#[derive(Clone, Copy)]
+enum Clobber {
+    Refuse,
+    // `-f`/`--force`.
+    Force,
+    // `-i`: ask before each overwrite.
+    Ask,
+}
+
+enum FileOperation {
+    Moved { from: PathBuf, to: PathBuf },
+    CreatedFile(PathBuf),
+    // Listed deepest first, so they can be removed in order.
+    CreatedDirs(Vec<PathBuf>),
+    // Brought back out of the trash; undoing it trashes the path again, index entry and all.
+    Restored(PathBuf),
+    Irreversible(String),
+}
+
+struct CommandSpec {
+    name: &'static str,
+    // One line for the `help` overview; `help <name>` shows the rest.
+    summary: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    examples: &'static [&'static str],
+    // Bounds on the arguments after the command name, checked by `dispatch` before the
+    // handler runs so handlers can index the ones they require. `None` is unbounded.
+    min_args: usize,
+    max_args: Option<usize>,
+    handler: fn(&mut Shell, &[&str]),
+}
+
+// Every built-in: what `dispatch` runs, and the single source of truth for `help` and
+// the per-command `--help` flag.
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "cd",
+        summary: "Change directory",
+        usage: "cd [path | -]",
+        description: "Change the current directory (home when no path is given); `cd -` goes back to the previous one",
+        examples: &["cd projects", "cd ..", "cd", "cd -"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: |shell, args| shell.change_dir(args.first().copied()),
+    },
+    CommandSpec {
+        name: "dir",
+        summary: "List the current directory",
+        usage: "dir [-l] [-h] [-a] [--git]",
+        description: "List the current directory; -l shows kind, size and modification time (local) with directories \
+                      first, -h makes sizes human-readable, -a includes hidden entries in the long listing, \
+                      --git annotates entries with their git status",
+        examples: &["dir", "dir -lh", "dir -la --git"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::list_dir,
+    },
+    CommandSpec {
+        name: "mkdir",
+        summary: "Create a directory",
+        usage: "mkdir [-p] <directory_name>",
+        description: "Create a directory. Its parent must already exist unless -p is given, which creates any missing parents and accepts a directory that already exists",
+        examples: &["mkdir notes", "mkdir -p build/output"],
+        min_args: 1,
+        max_args: Some(2),
+        handler: Shell::make_dir,
+    },
+    CommandSpec {
+        name: "mkcd",
+        summary: "Create a directory and enter it",
+        usage: "mkcd <directory_name>",
+        description: "Create a directory, along with any missing parents, and cd into it",
+        examples: &["mkcd build/output"],
+        min_args: 1,
+        max_args: Some(1),
+        handler: |shell, args| {
+            if shell.create_directory(args[0], true) {
+                shell.change_dir(Some(args[0]));
+            }
+        },
+    },
+    CommandSpec {
+        name: "rmdir",
+        summary: "Remove a directory",
+        usage: "rmdir [-r|-rf] [--trash] [--quiet] <directory_name>",
+        description: "Remove a directory; -r removes its contents too (-f skips the prompt), showing a running \
+                      count on a terminal if that takes a while (--quiet turns it off). With --trash, or \
+                      `set trash on`, -r moves it to the trash instead, for `restore`",
+        examples: &["rmdir empty", "rmdir -r old_build"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::remove_dir,
+    },
+    CommandSpec {
+        name: "rm",
+        summary: "Remove files",
+        usage: "rm [-i] [-f] [-r] [--trash] [--quiet] <file>...",
+        description: "Remove files; files may use * ? [..] wildcards. -i asks before each one, -f ignores missing files, \
+                      -r removes directories and their contents (with a running count for big trees, unless --quiet). \
+                      --trash, or `set trash on`, moves them to the trash instead, for `restore` to bring back",
+        examples: &["rm notes.txt", "rm -i *.log", "rm -r build", "rm --trash draft.txt"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::remove_files,
+    },
+    CommandSpec {
+        name: "<-",
+        summary: "Go back in directory history",
+        usage: "<-",
+        description: "Go back in directory history",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.go_backward(),
+    },
+    CommandSpec {
+        name: "->",
+        summary: "Go forward in directory history",
+        usage: "->",
+        description: "Go forward in directory history",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.go_forward(),
+    },
+    CommandSpec {
+        name: "pushd",
+        summary: "Save the current directory and cd",
+        usage: "pushd [path]",
+        description: "Push the current directory onto the directory stack and cd into the path; with no path, \
+                      swap the current directory with the top of the stack",
+        examples: &["pushd /tmp", "pushd"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: |shell, args| shell.push_dir(args.first().copied()),
+    },
+    CommandSpec {
+        name: "popd",
+        summary: "Return to the last pushed directory",
+        usage: "popd",
+        description: "Take the top directory off the directory stack and cd into it",
+        examples: &["popd"],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.pop_dir(),
+    },
+    CommandSpec {
+        name: "dirs",
+        summary: "List the directory stack",
+        usage: "dirs",
+        description: "List the current directory (0) and the directory stack, most recently pushed first",
+        examples: &["dirs"],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.list_dir_stack(),
+    },
+    CommandSpec {
+        name: "clear",
+        summary: "Clear the screen",
+        usage: "clear",
+        description: "Clear the screen",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.clear_screen(),
+    },
+    CommandSpec {
+        name: "restore",
+        summary: "Bring files back from the trash",
+        usage: "restore [path]...",
+        description: "Move files and directories deleted with `rm --trash` back to where they were, the most \
+                      recently deleted first if there are several; without arguments, list what's in the trash",
+        examples: &["restore", "restore notes.txt", "restore ~/projects/old"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::restore_from_trash,
+    },
+    CommandSpec {
+        name: "emptytrash",
+        summary: "Permanently delete the trash",
+        usage: "emptytrash",
+        description: "Permanently delete everything in the trash",
+        examples: &["emptytrash"],
+        min_args: 0,
+        max_args: Some(0),
+        handler: Shell::empty_trash,
+    },
+    CommandSpec {
+        name: "rename",
+        summary: "Rename a file or directory",
+        usage: "rename [-f | -i] <old_name> <new_name> | rename --sub <from> <to> [-g] [--apply] <pattern>...",
+        description: "Rename a file or directory; an existing <new_name> is only replaced with -f (--force), or after asking with -i. \
+                      With --sub, replace the first <from> (every one with -g) in the names of the files matching the patterns: \
+                      the renames are only listed unless --apply is given, and any whose new name is taken is skipped",
+        examples: &["rename draft.txt final.txt", "rename -f draft.txt final.txt", "rename --sub .jpeg .jpg \"*.jpeg\" --apply"],
+        min_args: 2,
+        max_args: None,
+        handler: Shell::rename_dir,
+    },
+    CommandSpec {
+        name: "move",
+        summary: "Move files",
+        usage: "move [-f | -i] <source>... <destination>",
+        description: "Move files; sources may use * ? [..] wildcards. Existing files are only replaced with -f (--force), or after asking with -i",
+        examples: &["move notes.txt archive/", "move *.log logs", "move -i draft.txt final.txt"],
+        min_args: 2,
+        max_args: None,
+        handler: |shell, args| shell.transfer_files("move", args),
+    },
+    CommandSpec {
+        name: "copy",
+        summary: "Copy files",
+        usage: "copy [-n | -f] [-v] [--verify] [--quiet] <source>... <destination>",
+        description: "Copy files, and directories recursively; sources may use * ? [..] wildcards. Copying onto an existing directory merges into it; -n never overwrites existing files (the default with `set noclobber on`, which -f overrides); -v lists each file copied, with a total at the end, on stderr; --verify compares sha256 checksums of every file and its copy afterwards. A long directory copy shows a running count on a terminal, which --quiet turns off",
+        examples: &["copy config.toml config.toml.orig", "copy *.txt backup", "copy -n src backup/src", "copy -v photos /mnt/backup", "copy --verify disk.img /mnt/backup"],
+        min_args: 2,
+        max_args: None,
+        handler: |shell, args| shell.transfer_files("copy", args),
+    },
+    CommandSpec {
+        name: "type",
+        summary: "Print file contents",
+        usage: "type [-n] [--force | --hex] <file_name>...",
+        description: "Print the contents of files one after another (wildcards allowed); -n numbers the lines. \
+                      Binary files are refused unless --force is given; --hex shows any file as hex and ASCII",
+        examples: &["type README.md", "type -n *.cfg", "type --hex logo.png"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::type_files,
+    },
+    CommandSpec {
+        name: "cc",
+        summary: "Manage custom commands",
+        usage: "cc <create/list/delete/refactor/complete/export/import>",
+        description: "Manage custom commands; `cc create` refuses a built-in's name unless given --force, which makes the \
+                      custom command replace the built-in. `cc complete <name> <words:a,b|dir:path>` registers argument \
+                      completions, `cc export <file>` / `cc import <file> [--overwrite]` share them as JSON",
+        examples: &["cc create gs \"git status\" \"Show git status\"", "cc list", "cc delete gs", "cc export commands.json"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::handle_custom_command,
+    },
+    CommandSpec {
+        name: "hook",
+        summary: "Run commands around every command",
+        usage: "hook add pre|post <command> | hook list | hook remove pre|post <number>",
+        description: "Register a command line to run before (pre) or after (post) each command typed at the \
+                      prompt; it sees that command as $RUBIN_LAST_CMD and, after it, its exit status as $?. \
+                      Quote the command so its variables expand when the hook runs. Hooks are saved between \
+                      sessions, and don't run for scripts or for each other",
+        examples: &["hook add post 'echo \"$RUBIN_LAST_CMD -> $?\" >> ~/rubin.log'", "hook list", "hook remove post 1"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::manage_hooks,
+    },
+    CommandSpec {
+        name: "alias",
+        summary: "Define or list aliases",
+        usage: "alias [name[=command]]...",
+        description: "Define a shorthand that replaces the first word of a command line, or list aliases; \
+                      aliases are saved between sessions",
+        examples: &["alias ll=\"dir --git\"", "alias", "alias ll"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::define_alias,
+    },
+    CommandSpec {
+        name: "unalias",
+        summary: "Remove aliases",
+        usage: "unalias <name>...",
+        description: "Remove aliases defined with `alias`",
+        examples: &["unalias ll"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::remove_alias,
+    },
+    CommandSpec {
+        name: "which",
+        summary: "Show how a name would run",
+        usage: "which <name>...",
+        description: "Report whether each name is an alias, a built-in, a custom command or a program on PATH, \
+                      checked in the order the shell itself uses (custom commands made with --force replace built-ins)",
+        examples: &["which ll", "which git"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::which,
+    },
+    CommandSpec {
+        name: "run",
+        summary: "Run a script",
+        usage: "run <script_path> [args...]",
+        description: "Run a script with its #! interpreter (sh if none). A .rubin script is run by the shell \
+                      itself, and may use `set name=value`, `if <command>; then ... else ... fi` and \
+                      `for name in words; do ... done`",
+        examples: &["run build.sh", "run deploy.py staging", "run setup.rubin"],
+        min_args: 1,
+        max_args: None,
+        handler: |shell, args| {
+            shell.run_script(args[0], &args[1..]);
+        },
+    },
+    CommandSpec {
+        name: "source",
+        summary: "Load variables from a file",
+        usage: "source <env_file_path>",
+        description: "Load environment variables from KEY=value lines in a file. Values may be quoted and may \
+                      refer to earlier variables as $NAME; a leading `export` also exports the variable, and \
+                      blank lines and # comments are skipped",
+        examples: &["source .env"],
+        min_args: 1,
+        max_args: Some(1),
+        handler: |shell, args| shell.source_env_file(args[0]),
+    },
+    CommandSpec {
+        name: "setenv",
+        summary: "Set an environment variable",
+        usage: "setenv [--export] <key> <value>",
+        description: "Set a variable for the shell and the programs it runs; --export also sets it in the shell's \
+                      own process environment, like `export`. PROMPT sets the prompt: \\w is the directory, \\W its \
+                      last part, \\u the user, \\h the host, \\$? the last exit status and \\n a new line",
+        examples: &["setenv EDITOR vim", "setenv --export LANG C.UTF-8", "setenv PROMPT '\\u@\\h \\W [\\$?]\\n> '"],
+        min_args: 2,
+        max_args: Some(3),
+        handler: Shell::handle_setenv,
+    },
+    CommandSpec {
+        name: "export",
+        summary: "Set and export environment variables",
+        usage: "export [key[=value]]...",
+        description: "Set variables in the shell's process environment as well as the shell, so everything the \
+                      process starts inherits them, or list exported variables; exports are saved between sessions",
+        examples: &["export RUST_LOG=debug", "export EDITOR", "export"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::export_vars,
+    },
+    CommandSpec {
+        name: "env",
+        summary: "List environment variables",
+        usage: "env [-a]",
+        description: "List the shell's variables as KEY=VALUE, sorted; -a merges in the inherited process environment, \
+                      with the shell's values winning",
+        examples: &["env", "env -a"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: |shell, args| shell.list_env_vars(args, false),
+    },
+    CommandSpec {
+        name: "printenv",
+        summary: "Print an environment variable",
+        usage: "printenv [-a | key]",
+        description: "Print one variable's value, failing if it isn't set; with no key, list variables like `env`, \
+                      marking the exported ones",
+        examples: &["printenv HOME", "printenv"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: Shell::print_env_var,
+    },
+    CommandSpec {
+        name: "unsetenv",
+        summary: "Remove an environment variable",
+        usage: "unsetenv <key>",
+        description: "Remove an environment variable",
+        examples: &["unsetenv EDITOR"],
+        min_args: 1,
+        max_args: Some(1),
+        handler: |shell, args| shell.unset_env_var(args[0]),
+    },
+    CommandSpec {
+        name: "replace",
+        summary: "Replace text in a file",
+        usage: "replace [--regex] [--apply | -i] [--no-backup] <pattern> <replacement> <file>...",
+        description: "Replace text within the lines of files, which may use * ? [..] wildcards. The pattern is \
+                      literal unless --regex is given, when $1 in the replacement refers to capture groups. \
+                      Without --apply (or -i) only the number of replacements in each file is shown; with it \
+                      the files are rewritten, keeping the original as <file>.bak unless --no-backup",
+        examples: &["replace foo bar notes.txt", "replace --apply 1.0.3 1.0.4 *.toml", "replace --regex -i \"(\\w+)@old\" \"$1@new\" users.txt"],
+        min_args: 3,
+        max_args: None,
+        handler: Shell::replace_in_file,
+    },
+    CommandSpec {
+        name: "echo",
+        summary: "Print arguments",
+        usage: "echo [-n] [-e] [text]...",
+        description: "Print the arguments separated by spaces (-n leaves off the newline, \
+                      -e interprets \\n, \\t, \\r, \\a, \\b, \\e, \\\\ and \\c, which stops the output)",
+        examples: &["echo Hello, $USER", "echo -n \"no newline\"", "echo -e \"a\\tb\""],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::echo,
+    },
+    CommandSpec {
+        name: "calc",
+        summary: "Evaluate arithmetic",
+        usage: "calc <expression>",
+        description: "Evaluate an expression of integers and decimals with + - * / %, unary minus and \
+                      parentheses; quote it if it contains spaces or `*` next to a word",
+        examples: &["calc 2 + 3 * 4", "calc '(1.5 + 2) * 4'", "calc 17 % 5"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::calculate,
+    },
+    CommandSpec {
+        name: "nl",
+        summary: "Number lines",
+        usage: "nl [-w <width>] [-s <sep>] [-b a|t] [file]",
+        description: "Print lines with line numbers (-b a numbers every line, -b t only non-empty ones); reads stdin without a file",
+        examples: &["nl main.rs", "dir | nl -b a"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::number_lines,
+    },
+    CommandSpec {
+        name: "head",
+        summary: "Print the start of a file",
+        usage: "head [-n <lines>] [file]",
+        description: "Print the first lines of a file, or of the input when no file is given (default 10)",
+        examples: &["head README.md", "head -n 3 notes.txt", "history | head -n 5"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::head_file,
+    },
+    CommandSpec {
+        name: "tail",
+        summary: "Print the end of a file",
+        usage: "tail [-n <lines>] [-f] [file]",
+        description: "Print the last lines of a file, or of the input when no file is given (default 10); \
+                      -f keeps printing lines as they are appended",
+        examples: &["tail -n 20 app.log", "tail -f app.log", "dir | tail -n 3"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::tail_file,
+    },
+    CommandSpec {
+        name: "wc",
+        summary: "Count lines, words and bytes",
+        usage: "wc [-l] [-w] [-c] [file]...",
+        description: "Count the lines (-l), words (-w) and bytes (-c) of files, or of the input when no file is \
+                      given; all three without flags, plus a total for several files",
+        examples: &["wc notes.txt", "wc -l *.rs", "dir | wc -l"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::word_count,
+    },
+    CommandSpec {
+        name: "hexdump",
+        summary: "Show a file's bytes in hex",
+        usage: "hexdump [-n <bytes>] [-s <offset>] [file]",
+        description: "Print a file, or the input when none is given, sixteen bytes a row as offset, hex and ASCII \
+                      columns, like `hexdump -C` or `xxd`; -s starts that far in and -n stops after that many bytes \
+                      (both take k, M and G suffixes)",
+        examples: &["hexdump image.png", "hexdump -n 64 a.out", "hexdump -s 1k -n 256 disk.img", "echo hi | hexdump"],
+        min_args: 0,
+        max_args: Some(5),
+        handler: Shell::hexdump,
+    },
+    CommandSpec {
+        name: "sort",
+        summary: "Sort lines",
+        usage: "sort [-r] [-n] [file]",
+        description: "Print the lines of a file, or of the input without one, in order: -n compares the numbers \
+                      lines start with (lines without one go first, ties fall back to the text) and -r reverses",
+        examples: &["sort names.txt", "dir | sort -r", "sort -rn sizes.txt"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::sort_lines,
+    },
+    CommandSpec {
+        name: "uniq",
+        summary: "Drop repeated lines",
+        usage: "uniq [-c] [file]",
+        description: "Print a file, or the input without one, collapsing runs of identical adjacent lines into one \
+                      (-c prefixes each with how many there were); sort first to catch every duplicate",
+        examples: &["uniq log.txt", "sort words.txt | uniq -c"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::unique_lines,
+    },
+    CommandSpec {
+        name: "split",
+        summary: "Split a file into parts",
+        usage: "split <file> <chunk size>",
+        description: "Write a file out as <file>.part001, <file>.part002, ... of the given size (suffixes k, M and G \
+                      are powers of 1024); the last part holds whatever is left. `join` puts them back together",
+        examples: &["split backup.tar 100M", "split notes.txt 512k"],
+        min_args: 2,
+        max_args: Some(2),
+        handler: Shell::split_file,
+    },
+    CommandSpec {
+        name: "join",
+        summary: "Reassemble a split file",
+        usage: "join <file>",
+        description: "Concatenate <file>.part001, <file>.part002, ... in order, as written by `split`, back into <file>",
+        examples: &["join backup.tar"],
+        min_args: 1,
+        max_args: Some(1),
+        handler: Shell::join_parts,
+    },
+    CommandSpec {
+        name: "copy-clip",
+        summary: "Copy to the clipboard",
+        usage: "copy-clip [file]",
+        description: "Copy a file's contents (or stdin) to the system clipboard",
+        examples: &["copy-clip notes.txt"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: |shell, args| shell.copy_to_clipboard(args.first().copied()),
+    },
+    CommandSpec {
+        name: "paste-clip",
+        summary: "Paste the clipboard into a file",
+        usage: "paste-clip <file>",
+        description: "Write the system clipboard into a file",
+        examples: &["paste-clip snippet.txt"],
+        min_args: 1,
+        max_args: Some(1),
+        handler: |shell, args| shell.paste_from_clipboard(args[0]),
+    },
+    CommandSpec {
+        name: "ln",
+        summary: "Create a link",
+        usage: "ln [-h] <target> <link_name>",
+        description: "Create a symbolic link to target, or a hard link with -h. A symlink's target is stored as given, \
+                      so a relative one is taken relative to the link's directory",
+        examples: &["ln ../shared/config.toml config.toml", "ln -h data.bin data-copy.bin"],
+        min_args: 2,
+        max_args: Some(3),
+        handler: Shell::make_link,
+    },
+    CommandSpec {
+        name: "chmod",
+        summary: "Change file permissions",
+        usage: "chmod <mode> <file>...",
+        description: "Set permissions from an octal mode like 644, or change them with [ugoa][+-=][rwx] like +x, \
+                      u-w or go=r (without u, g or o it applies to all three). On Windows only +w and -w, which \
+                      toggle the read-only attribute, are supported; files may use * ? [..] wildcards",
+        examples: &["chmod 755 build.sh", "chmod +x build.sh", "chmod go-w notes.txt", "chmod -w *.cfg"],
+        min_args: 2,
+        max_args: None,
+        handler: Shell::change_mode,
+    },
+    CommandSpec {
+        name: "edit",
+        summary: "Open a file in your editor",
+        usage: "edit <file>",
+        description: "Open a file in $EDITOR (vi or nano if unset, notepad on Windows) and wait for it to close; \
+                      a file that doesn't exist yet is created first",
+        examples: &["edit notes.txt", "setenv EDITOR \"code --wait\"; edit main.rs"],
+        min_args: 1,
+        max_args: Some(1),
+        handler: Shell::edit_file,
+    },
+    CommandSpec {
+        name: "stat",
+        summary: "Show file details",
+        usage: "stat <path>...",
+        description: "Show the type, size, permissions and timestamps (in local time) of files; a symlink is described itself, \
+                      along with its target, rather than followed",
+        examples: &["stat Cargo.toml", "stat src"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::stat_paths,
+    },
+    CommandSpec {
+        name: "file",
+        summary: "Identify file types",
+        usage: "file <path>...",
+        description: "Tell what kind of data files hold from their first bytes, whatever their extension says",
+        examples: &["file download.bin", "file *"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::identify_files,
+    },
+    CommandSpec {
+        name: "find",
+        summary: "Search for files",
+        usage: "find [pattern] [--name <glob>] [--type f|d] [--max-depth <n>] [--newer-than <age>] \
+                [--size [+|-]<n>[k|M|G]] [--contains <text>] [--quiet]",
+        description: "Recursively list paths under the current directory matching every given predicate; a bare \
+                      pattern is a glob if it has wildcards, otherwise a substring (ages like 30m, 3h, 2d, 1w; \
+                      +size means larger than, -size smaller than). A long search shows a running count on a \
+                      terminal, which --quiet turns off",
+        examples: &["find main", "find \"*.rs\" --type f", "find --newer-than 2d --size +1M"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::find_files,
+    },
+    CommandSpec {
+        name: "tree",
+        summary: "Show a directory tree",
+        usage: "tree [-a] [--max-depth <n>] [path]",
+        description: "Show the directories and files under a path (default: the current directory) as a tree, \
+                      followed by how many of each there are (-a includes hidden entries)",
+        examples: &["tree", "tree --max-depth 2 src", "tree -a"],
+        min_args: 0,
+        max_args: Some(4),
+        handler: Shell::show_tree,
+    },
+    CommandSpec {
+        name: "grep",
+        summary: "Search file contents",
+        usage: "grep [-i] [-n] [-r] [-o] <pattern> [path]...",
+        description: "Print lines matching a regular expression, from files or piped input, with the matches \
+                      highlighted on a terminal (-i ignore case, -n line numbers, -r search directories \
+                      recursively, -o print only the matched text, one match per line)",
+        examples: &["grep -n TODO main.rs", "grep -ri error logs", "grep -o '[0-9]+ms' build.log"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::grep,
+    },
+    CommandSpec {
+        name: "set",
+        summary: "Toggle shell options",
+        usage: "set [-e|-x|-u|+e|+x|+u] | set <option> on|off | set <name>=<value>",
+        description: "Toggle shell options, or with no arguments show them: errexit (-e) exits scripts on error, \
+                      xtrace (-x) traces commands, nounset (-u) errors on unset variables, autocd makes a directory \
+                      name typed on its own cd into it, noclobber stops `>` and copy overwriting files, color \
+                      colors listings, trash makes rm send things to the trash, and logging appends each command \
+                      line to $RUBIN_LOG (or commands.log beside the state file) as JSON (+ turns an option off). `set name=value` quietly sets a variable, as in scripts",
+        examples: &["set -x", "set +e", "set autocd on", "set noclobber on", "set target=release"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::set_shell_options,
+    },
+    CommandSpec {
+        name: "unset",
+        summary: "Turn shell options off",
+        usage: "unset <option>...",
+        description: "Turn off shell options, as `set <option> off` does (see `help set` for the list)",
+        examples: &["unset autocd", "unset errexit xtrace"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::unset_options,
+    },
+    CommandSpec {
+        name: "undo",
+        summary: "Undo the last file operation",
+        usage: "undo",
+        description: "Revert the most recent move, rename, mkdir, copy or restore",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.undo_last_operation(),
+    },
+    CommandSpec {
+        name: "commands",
+        summary: "List everything runnable",
+        usage: "commands [--external]",
+        description: "List built-ins, aliases with what they expand to, and custom commands with their \
+                      descriptions; --external adds the programs found on PATH",
+        examples: &["commands", "commands --external | grep git"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: Shell::list_commands,
+    },
+    CommandSpec {
+        name: "compgen",
+        summary: "Show custom command completions",
+        usage: "compgen <command_name> [partial]",
+        description: "List the completions a custom command offers for a partial argument",
+        examples: &["compgen deploy st"],
+        min_args: 1,
+        max_args: Some(2),
+        handler: |shell, args| shell.print_completions(args[0], args.get(1).copied()),
+    },
+    CommandSpec {
+        name: "reload",
+        summary: "Reload saved state and ~/.rubinrc",
+        usage: "reload",
+        description: "Re-read the saved aliases, custom commands and variables, replacing the ones in memory, \
+                      then run ~/.rubinrc again. If the state file can't be read, nothing is changed",
+        examples: &["reload"],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.reload(),
+    },
+    CommandSpec {
+        name: "history",
+        summary: "List entered commands",
+        usage: "history [clear]",
+        description: "List previously entered command lines; `history clear` forgets them along with the `<-`/`->` \
+                      directory history. RUBIN_HISTORY_SIZE caps both (default 1000 entries)",
+        examples: &["history", "history clear", "setenv RUBIN_HISTORY_SIZE 200"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: Shell::handle_history,
+    },
+    CommandSpec {
+        name: "search",
+        summary: "Search the command history",
+        usage: "search [-a] [-i] <text>",
+        description: "Print the most recent history entry containing the text, numbered so `!N` runs it again \
+                      (-a lists every match, newest first; -i ignores case). At the prompt, Ctrl-R searches \
+                      interactively",
+        examples: &["search cargo", "search -a -i TODO", "!42"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::search_history,
+    },
+    CommandSpec {
+        name: "jobs",
+        summary: "List background jobs",
+        usage: "jobs",
+        description: "List commands started with a trailing `&`; finished ones are shown once with their status",
+        examples: &["sleep 30 &", "jobs"],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.list_jobs(),
+    },
+    CommandSpec {
+        name: "wait",
+        summary: "Wait for background jobs",
+        usage: "wait [job_number]...",
+        description: "Block until the given background jobs, or all of them, finish; the status is the last job's exit status",
+        examples: &["wait", "wait 2"],
+        min_args: 0,
+        max_args: None,
+        handler: Shell::wait_for_jobs,
+    },
+    CommandSpec {
+        name: "time",
+        summary: "Time a command",
+        usage: "time <command> [args...]",
+        description: "Run a command, then print how long it took on stderr (with the CPU time of the programs \
+                      it ran, on Linux); the status is the command's own",
+        examples: &["time cargo build", "time find --name \"*.log\""],
+        min_args: 1,
+        max_args: None,
+        // Only reached without a command, since `run_chain` handles `time` as a prefix.
+        handler: |shell, args| {
+            let command = args.join(" ");
+            shell.run_timed(&command);
+        },
+    },
+    CommandSpec {
+        name: "repeat",
+        summary: "Run a command several times",
+        usage: "repeat [--stop-on-error] <count> <command> [args...]",
+        description: "Run a command the given number of times in a row; the status is that of the last run. \
+                      With --stop-on-error, a failing run ends it early",
+        examples: &["repeat 3 echo hello", "repeat --stop-on-error 10 cargo test"],
+        min_args: 2,
+        max_args: None,
+        handler: Shell::repeat,
+    },
+    CommandSpec {
+        name: "watch",
+        summary: "Re-run a command on an interval",
+        usage: "watch [-n <seconds>] <command> [args...]",
+        description: "Clear the screen and run a command every few seconds (default 2) under a header naming it, \
+                      until Ctrl-C",
+        examples: &["watch dir -l", "watch -n 0.5 tail -n 5 app.log"],
+        min_args: 1,
+        max_args: None,
+        handler: Shell::watch,
+    },
+    CommandSpec {
+        name: "pwd",
+        summary: "Print the current directory",
+        usage: "pwd",
+        description: "Print the current directory",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.print_working_dir(),
+    },
+    CommandSpec {
+        name: "whoami",
+        summary: "Print the user name",
+        usage: "whoami",
+        description: "Print the current user name",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| outln!(shell, "{}", current_username()),
+    },
+    CommandSpec {
+        name: "hostname",
+        summary: "Print the machine name",
+        usage: "hostname",
+        description: "Print the machine name",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| outln!(shell, "{}", current_hostname()),
+    },
+    CommandSpec {
+        name: "sysinfo",
+        summary: "Print system information",
+        usage: "sysinfo",
+        description: "Print OS, architecture and available memory",
+        examples: &[],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| shell.print_sysinfo(),
+    },
+    CommandSpec {
+        name: "version",
+        summary: "Show the shell's version",
+        usage: "version",
+        description: "Print the shell's version, the platform it was built for, the build profile and, \
+                      when known, the git commit",
+        examples: &["version"],
+        min_args: 0,
+        max_args: Some(0),
+        handler: |shell, _| outln!(shell, "{}", version_info()),
+    },
+    CommandSpec {
+        name: "help",
+        summary: "Show help",
+        usage: "help [command]",
+        description: "List all commands, or show the usage and examples for one",
+        examples: &["help", "help grep"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: |shell, args| shell.display_help(args.first().copied()),
+    },
+    CommandSpec {
+        name: "exit",
+        summary: "Exit the shell",
+        usage: "exit [-f]",
+        description: "Exit the shell. While background jobs are running it only warns, and a second `exit` \
+                      (or `exit -f`) quits and kills them",
+        examples: &["exit", "exit -f"],
+        min_args: 0,
+        max_args: Some(1),
+        handler: Shell::exit_shell,
+    },
+];
+
+fn find_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CustomCommand {
     name: String,
     definition: String,
     description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    completion: Option<CompletionSpec>,
+}
+
+// Argument completions offered for a custom command, set with `cc complete`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CompletionSpec {
+    Words(Vec<String>),
+    // Entries of this directory, resolved against `current_dir` at completion time.
+    Dir(String),
+}
+
+impl CompletionSpec {
+    fn parse(spec: &str) -> Option<Self> {
+        if let Some(words) = spec.strip_prefix("words:") {
+            let words: Vec<String> = words.split(',').filter(|w| !w.is_empty()).map(String::from).collect();
+            (!words.is_empty()).then_some(CompletionSpec::Words(words))
+        } else {
+            spec.strip_prefix("dir:")
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| CompletionSpec::Dir(dir.to_string()))
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            CompletionSpec::Words(words) => format!("words:{}", words.join(",")),
+            CompletionSpec::Dir(dir) => format!("dir:{}", dir),
+        }
+    }
 }
 
 impl Shell {
+    // A shell on the process's own stdin and stdout.
     fn new() -> Self {
-        let current_dir = env::current_dir().unwrap();
+        let shell = Shell::with_io(Output::Stdout, Input::Stdin);
+        for name in &shell.exported {
+            if let Some(value) = shell.env_vars.get(name) {
+                env::set_var(name, value);
+            }
+        }
+        shell
+    }
+
+    fn with_io(out: Output, input: Input) -> Self {
+        let current_dir = starting_dir();
+        let state = load_state();
+        let command_history = load_command_history();
         Shell {
+            out,
+            input,
+            piped_input: None,
             current_dir: current_dir.clone(),
             history: vec![current_dir],
             history_index: 0,
-            custom_commands: Vec::new(),
-            env_vars: HashMap::new(),
+            previous_dir: None,
+            dir_stack: Vec::new(),
+            custom_commands: state.custom_commands,
+            env_vars: state.env_vars.into_iter().collect(),
+            exported: state.exported,
+            aliases: state.aliases,
+            hooks: state.hooks,
+            running_hooks: false,
+            hook_command: None,
+            undo_stack: Vec::new(),
+            safe_mode: env::var("RUBIN_SAFE_MODE").is_ok_and(|value| is_truthy(&value)),
+            guard_override: false,
+            options: ShellOptions::default(),
+            state_dirty: false,
+            status: 0,
+            last_status: 0,
+            command_history,
+            command_history_dirty: false,
+            editor: None,
+            jobs: Vec::new(),
+            log_failed: false,
+            exit_warned: false,
+            clipboard: None,
         }
     }
 
     fn run(&mut self) {
+        install_signal_handlers();
+        let interactive = matches!(self.input, Input::Stdin) && io::stdin().is_terminal();
+        let history_limit = self.history_limit();
+        self.editor = interactive.then(create_editor).flatten().map(|mut editor| {
+            let _ = editor.set_max_history_size(history_limit);
+            for line in &self.command_history {
+                let _ = editor.add_history_entry(line.as_str());
+            }
+            editor
+        });
         loop {
-            print!("{} $> ", self.current_dir.display());
-            io::stdout().flush().unwrap();
+            // Everything is flushed before blocking on input, which is what lets the
+            // SIGTERM watcher exit straight away while we're idle at the prompt.
+            self.flush_state();
+            self.reap_jobs();
+            self.recover_current_dir();
+            INTERRUPTED.store(false, Ordering::SeqCst);
+            AT_PROMPT.store(true, Ordering::SeqCst);
+            if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                self.shutdown(SIGTERM_EXIT_CODE);
+            }
 
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-            let command = input.trim();
-            self.execute_command(command);
-        }
-    }
-
-    fn execute_command(&mut self, command: &str) {
-        let args: Vec<&str> = command.split_whitespace().collect();
-
-        if let Some(first_arg) = args.get(0).map(|&s| s) {
-            match first_arg {
-                "dir" => self.list_dir(),
-                "mkdir" => self.make_dir(args.get(1).copied()),
-                "rmdir" => self.remove_dir(args.get(1).copied()),
-                "help" => self.display_help(),
-                "<-" => self.go_backward(),
-                "->" => self.go_forward(),
-                "clear" => self.clear_screen(),
-                "rename" => self.rename_dir(args.get(1).copied(), args.get(2).copied()),
-                "move" => self.move_file(args.get(1).copied(), args.get(2).copied()),
-                "copy" => self.copy_file(args.get(1).copied(), args.get(2).copied()),
-                "type" => self.type_file(args.get(1).copied()),
-                "exit" => self.exit_shell(),
-                "cc" => self.handle_custom_command(&args[1..]),
-                "run" => self.run_script(args.get(1).copied()),      // New: run a script
-                "source" => self.source_env_file(args.get(1).copied()), // New: source environment variables
-                "setenv" => self.set_env_var(args.get(1).copied(), args.get(2).copied()), // Fix: use copied()
-                _ => self.handle_file_commands(first_arg, &args[1..]),
-            }
-        }
-    }
-
-    fn run_script(&self, script_path: Option<&str>) {
-        if let Some(path) = script_path {
-            let script_full_path = self.current_dir.join(path);
-            if script_full_path.exists() {
-                let status = Command::new("sh")
-                    .arg(script_full_path)
-                    .status();
-                if let Err(e) = status {
-                    println!("Failed to run script: {}", e);
-                }
-            } else {
-                println!("Script not found: {}", path);
+            // Input piped in from elsewhere gets no prompt, and isn't history.
+            let prompt = if interactive { self.prompt() } else { String::new() };
+            let input = self.read_input_line(&prompt);
+            AT_PROMPT.store(false, Ordering::SeqCst);
+            let Some(input) = input else {
+                self.shutdown(self.last_status);
+            };
+            let started_in = self.current_dir.clone();
+            let status = if interactive { self.execute_command(input.trim()) } else { self.run_line(input.trim()) };
+            self.log_command(input.trim(), &started_in, status);
+
+            if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                self.shutdown(SIGTERM_EXIT_CODE);
             }
-        } else {
-            println!("Usage: run <script_path>");
         }
     }
 
-    fn source_env_file(&mut self, file_path: Option<&str>) {
-        if let Some(path) = file_path {
-            let full_path = self.current_dir.join(path);
-            match fs::read_to_string(full_path) {
-                Ok(contents) => {
-                    for line in contents.lines() {
-                        if let Some((key, value)) = line.split_once('=') {
-                            self.env_vars.insert(key.trim().to_string(), value.trim().to_string());
-                        }
+    // The `PROMPT` variable rendered by `expand_prompt`, or `<dir> $> ` if it isn't set.
+    fn prompt(&self) -> String {
+        let home = home_dir().map(|home| home.display().to_string());
+        let shown = abbreviate_path(&self.current_dir.display().to_string(), home.as_deref(), PROMPT_PATH_WIDTH);
+        let Some(template) = self.env_vars.get("PROMPT") else {
+            return format!("{} $> ", shown);
+        };
+        expand_prompt(template, |field| match field {
+            'w' => shown.clone(),
+            'W' => match self.current_dir.file_name() {
+                _ if shown == "~" => shown.clone(),
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => self.current_dir.display().to_string(),
+            },
+            'u' => current_username(),
+            'h' => current_hostname(),
+            '?' => self.last_status.to_string(),
+            _ => String::new(),
+        })
+    }
+
+    // `None` at end of input.
+    fn read_input_line(&mut self, prompt: &str) -> Option<String> {
+        let Some(editor) = self.editor.as_mut() else {
+            let mut input = String::new();
+            return loop {
+                out!(self, "{}", prompt);
+                if let Err(e) = self.out.flush() {
+                    eprintln!("Warning: failed to write the prompt: {}", e);
+                }
+                match self.input.read_line(&mut input) {
+                    Ok(0) => break None,
+                    Ok(_) => break Some(input),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                        input.clear();
+                        outln!(self);
                     }
-                    println!("Environment variables sourced.");
+                    Err(_) => break None,
+                }
+            };
+        };
+        if let Some(helper) = editor.helper_mut() {
+            helper.current_dir.clone_from(&self.current_dir);
+            helper.custom_commands.clone_from(&self.custom_commands);
+            helper.aliases = self.aliases.keys().cloned().collect();
+        }
+        loop {
+            match editor.readline(prompt) {
+                Ok(line) => return Some(line),
+                // Ctrl-C abandons the line being typed, as in other shells.
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return None,
+                Err(e) => {
+                    eprintln!("Error reading input: {}", e);
+                    return None;
                 }
-                Err(_) => println!("Failed to read env file."),
             }
-        } else {
-            println!("Usage: source <env_file_path>");
         }
     }
 
-    fn set_env_var(&mut self, key: Option<&str>, value: Option<&str>) {
-        if let (Some(k), Some(v)) = (key, value) {
-            self.env_vars.insert(k.to_string(), v.to_string());
-            println!("Environment variable set: {}={}", k, v);
-        } else {
-            println!("Usage: setenv <key> <value>");
+    fn add_to_command_history(&mut self, line: &str) {
+        if line.is_empty() || self.command_history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.command_history.push(line.to_string());
+        let excess = self.command_history.len().saturating_sub(self.history_limit());
+        self.command_history.drain(..excess);
+        self.command_history_dirty = true;
+        if let Some(editor) = self.editor.as_mut() {
+            let _ = editor.add_history_entry(line);
         }
     }
 
-    fn handle_custom_command(&mut self, args: &[&str]) {
-        if let Some(action) = args.get(0) {
-            match *action {
-                "create" => self.create_custom_command(
-                    args.get(1).map(|v| *v),
-                    args.get(2).map(|v| *v),
-                    args.get(3).map(|v| *v)
-                ),
-                "list" => self.list_custom_commands(),
-                "delete" => self.delete_custom_command(args.get(1).map(|v| *v)),
-                "refactor" => self.refactor_custom_command(
-                    args.get(1).map(|v| *v),
-                    args.get(2).map(|v| *v),
-                    args.get(3).map(|v| *v)
-                ),
-                _ => println!("Unknown custom command action: {}", action),
-            }
-        } else {
-            println!("Usage: cc <create/list/delete/refactor>");
+    fn handle_history(&mut self, args: &[&str]) {
+        match args.first() {
+            None => self.print_command_history(),
+            Some(&"clear") => self.clear_history(),
+            Some(other) => fail!(self, "history: unknown action '{}'", other),
         }
     }
 
-    fn create_custom_command(&mut self, cmd_name: Option<&str>, cmd_definition: Option<&str>, cmd_description: Option<&str>) {
-        if let (Some(name), Some(definition), Some(description)) = (cmd_name, cmd_definition, cmd_description) {
-            let command = CustomCommand {
-                name: name.to_string(),
-                definition: definition.to_string(),
-                description: description.to_string(),
-            };
-            self.custom_commands.push(command);
-            println!("Custom command '{}' created.", name);
-        } else {
-            println!("Usage: cc create <command_name> <command_definition> <command_description>");
+    fn clear_history(&mut self) {
+        self.command_history.clear();
+        self.command_history_dirty = true;
+        if let Some(editor) = self.editor.as_mut() {
+            let _ = editor.clear_history();
         }
+        self.history = vec![self.current_dir.clone()];
+        self.history_index = 0;
     }
 
-    fn list_custom_commands(&self) {
-        if self.custom_commands.is_empty() {
-            println!("No custom commands defined.");
-        } else {
-            for (index, command) in self.custom_commands.iter().enumerate() {
-                println!("{}: {} - {} (Definition: {})", index + 1, command.name, command.description, command.definition);
-            }
+    // How many entries each history keeps before dropping the oldest.
+    fn history_limit(&self) -> usize {
+        self.lookup_variable("RUBIN_HISTORY_SIZE")
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(DEFAULT_HISTORY_SIZE)
+            .max(1)
+    }
+
+    fn print_command_history(&mut self) {
+        let width = self.command_history.len().to_string().len();
+        for (index, line) in self.command_history.iter().enumerate() {
+            outln!(self, "{:>width$}  {}", index + 1, line);
         }
     }
 
-    fn delete_custom_command(&mut self, cmd_number: Option<&str>) {
-        if let Some(num_str) = cmd_number {
-            if let Ok(index) = num_str.parse::<usize>() {
-                if index > 0 && index <= self.custom_commands.len() {
-                    let removed = self.custom_commands.remove(index - 1);
-                    println!("Custom command '{}' deleted.", removed.name);
-                } else {
-                    println!("Command number out of range.");
+    fn search_history(&mut self, args: &[&str]) {
+        let mut all = false;
+        let mut ignore_case = false;
+        let mut words = Vec::new();
+        for arg in args {
+            match *arg {
+                "-a" => all = true,
+                "-i" => ignore_case = true,
+                _ => words.push(*arg),
+            }
+        }
+        if words.is_empty() {
+            fail!(self, "Usage: search [-a] [-i] <text>");
+            return;
+        }
+        let text = words.join(" ");
+        let needle = if ignore_case { text.to_lowercase() } else { text.clone() };
+        let width = self.command_history.len().to_string().len();
+        let mut lines = Vec::new();
+        for (index, line) in self.command_history.iter().enumerate().rev() {
+            // Skip searches themselves, including the one running now.
+            if line.split_whitespace().next() == Some("search") {
+                continue;
+            }
+            let found = if ignore_case { line.to_lowercase().contains(&needle) } else { line.contains(&needle) };
+            if found {
+                lines.push(format!("{:>width$}  {}", index + 1, line));
+                if !all {
+                    break;
                 }
-            } else {
-                println!("Invalid command number.");
             }
-        } else {
-            println!("Usage: cc delete <command_number>");
+        }
+        if lines.is_empty() {
+            fail!(self, "search: no history entry contains '{}'", text);
+            return;
+        }
+        for line in lines {
+            outln!(self, "{}", line);
+        }
+    }
+
+    // Runs a file of commands (see `script` for `if` and `for`) and returns the status of
+    // the last one. With `set -e` the first failure ends the script.
+    fn run_command_file(&mut self, path: &Path) -> i32 {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                return if e.kind() == io::ErrorKind::NotFound { 127 } else { 1 };
+            }
+        };
+        let statements = match script::parse(&source) {
+            Ok(statements) => statements,
+            Err(e) => {
+                eprintln!("{}: line {}: syntax error: {}", path.display(), e.line, e.message);
+                return 2;
+            }
+        };
+        match self.run_statements(path, &statements, 0) {
+            ControlFlow::Continue(status) | ControlFlow::Break(status) => status,
         }
     }
 
-    fn refactor_custom_command(&mut self, cmd_number: Option<&str>, new_definition: Option<&str>, new_description: Option<&str>) {
-        if let Some(num_str) = cmd_number {
-            if let Ok(index) = num_str.parse::<usize>() {
-                if index > 0 && index <= self.custom_commands.len() {
-                    let command = &mut self.custom_commands[index - 1];
-                    if let Some(definition) = new_definition {
-                        command.definition = definition.to_string();
+    // `Break` stops the whole script: a failure under `set -e`, or Ctrl-C.
+    fn run_statements(&mut self, path: &Path, statements: &[Statement], mut status: i32) -> ControlFlow<i32, i32> {
+        for statement in statements {
+            status = match statement {
+                Statement::Command(text) => self.run_line(text),
+                Statement::If { condition, then_branch, else_branch } => {
+                    // As in `sh`, a failing condition is not an error for `set -e`.
+                    let branch = if self.run_line(condition) == 0 { then_branch } else { else_branch };
+                    if INTERRUPTED.load(Ordering::SeqCst) {
+                        return ControlFlow::Break(INTERRUPTED_EXIT_CODE);
                     }
-                    if let Some(description) = new_description {
-                        command.description = description.to_string();
+                    self.run_statements(path, branch, 0)?
+                }
+                Statement::For { line, variable, words, body } => {
+                    let words = match self.loop_words(words) {
+                        Ok(words) => words,
+                        Err(e) => {
+                            eprintln!("{}: line {}: {}", path.display(), line, e);
+                            return ControlFlow::Break(1);
+                        }
+                    };
+                    let mut loop_status = 0;
+                    for word in words {
+                        self.env_vars.insert(variable.clone(), word);
+                        loop_status = self.run_statements(path, body, loop_status)?;
                     }
-                    println!("Custom command '{}' updated.", command.name);
-                } else {
-                    println!("Command number out of range.");
+                    loop_status
                 }
-            } else {
-                println!("Invalid command number.");
+            };
+            if status != 0 && self.options.errexit {
+                return ControlFlow::Break(status);
+            }
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                return ControlFlow::Break(INTERRUPTED_EXIT_CODE);
+            }
+            if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+                self.shutdown(SIGTERM_EXIT_CODE);
             }
-        } else {
-            println!("Usage: cc refactor <command_number> <new_definition> <new_description>");
         }
+        ControlFlow::Continue(status)
     }
 
-    fn list_dir(&self) {
-        if let Ok(entries) = fs::read_dir(&self.current_dir) {
-            for entry in entries.filter_map(Result::ok) {
-                println!("{}", entry.file_name().to_string_lossy());
+    // The words a `for` loop runs over, with variables expanded and wildcards matched.
+    fn loop_words(&self, words: &str) -> Result<Vec<String>, String> {
+        let mut expanded = Vec::new();
+        for token in self.parse_line(words)? {
+            match token {
+                Token::Word(word) => expanded.push(word),
+                _ => return Err("for: only words can follow `in`".to_string()),
             }
         }
+        let words: Vec<&str> = expanded.iter().map(String::as_str).collect();
+        self.expand_globs(&words)
     }
 
-    fn make_dir(&self, dir_name: Option<&str>) {
-        if let Some(name) = dir_name {
-            let path = self.current_dir.join(name);
-            if fs::create_dir_all(path).is_err() {
-                println!("Failed to create directory: {}", name);
+    // `~/.rubinrc`, run before the first prompt. Unlike a script, a failing line only
+    // warns, so one bad line can't keep the shell from starting.
+    fn load_rc_file(&mut self) {
+        let Some(path) = home_dir().map(|home| home.join(".rubinrc")) else { return };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                eprintln!("Warning: could not read {}: {}", path.display(), e);
+                return;
+            }
+        };
+        for (index, line) in contents.lines().enumerate() {
+            let line = parser::strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let status = self.run_line(line);
+            if status != 0 {
+                eprintln!("Warning: {} line {} failed with status {}", path.display(), index + 1, status);
             }
-        } else {
-            println!("Usage: mkdir <directory_name>");
         }
     }
 
-    fn remove_dir(&self, dir_name: Option<&str>) {
-        if let Some(name) = dir_name {
-            let path = self.current_dir.join(name);
-            if fs::remove_dir(path).is_err() {
-                println!("Failed to remove directory: {}", name);
+    fn reload(&mut self) {
+        let state = match read_state() {
+            Ok(state) => state,
+            Err(e) => {
+                fail!(self, "reload: {}; keeping the current settings", e);
+                return;
+            }
+        };
+        for name in &state.exported {
+            if let Some(value) = state.env_vars.get(name) {
+                env::set_var(name, value);
             }
-        } else {
-            println!("Usage: rmdir <directory_name>");
         }
+        self.custom_commands = state.custom_commands;
+        self.env_vars = state.env_vars.into_iter().collect();
+        self.exported = state.exported;
+        self.aliases = state.aliases;
+        self.hooks = state.hooks;
+        self.state_dirty = false;
+        self.load_rc_file();
+        let line = format!(
+            "Reloaded {} alias{}, {} custom command{} and {} variable{}.",
+            self.aliases.len(),
+            if self.aliases.len() == 1 { "" } else { "es" },
+            self.custom_commands.len(),
+            if self.custom_commands.len() == 1 { "" } else { "s" },
+            self.env_vars.len(),
+            if self.env_vars.len() == 1 { "" } else { "s" },
+        );
+        outln!(self, "{}", line);
     }
 
-    fn go_backward(&mut self) {
-        if self.history_index > 0 {
-            self.history_index -= 1;
-            self.current_dir = self.history[self.history_index].clone();
+    // Writes out anything that should survive the shell exiting.
+    fn flush_state(&mut self) {
+        let _ = io::stdout().flush();
+        if self.state_dirty {
+            self.save_state();
+        }
+        if self.command_history_dirty {
+            self.save_command_history();
         }
     }
 
-    fn go_forward(&mut self) {
-        if self.history_index < self.history.len() - 1 {
-            self.history_index += 1;
-            self.current_dir = self.history[self.history_index].clone();
+    fn save_command_history(&mut self) {
+        let Some(path) = history_file_path() else { return };
+        let mut contents = self.command_history.join("\n");
+        contents.push('\n');
+        let result = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(&path, contents));
+        match result {
+            Ok(()) => self.command_history_dirty = false,
+            Err(e) => eprintln!("Warning: failed to save history to {}: {}", path.display(), e),
+        }
+    }
+
+    fn save_state(&mut self) {
+        let Some(path) = state_file_path() else { return };
+        let state = PersistedState {
+            custom_commands: self.custom_commands.clone(),
+            env_vars: self.env_vars.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            exported: self.exported.clone(),
+            aliases: self.aliases.clone(),
+            hooks: self.hooks.clone(),
+        };
+        let result = serde_json::to_string_pretty(&state)
+            .map_err(io::Error::from)
+            .and_then(|json| {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, json)
+            });
+        match result {
+            Ok(()) => self.state_dirty = false,
+            Err(e) => eprintln!("Warning: failed to save state to {}: {}", path.display(), e),
         }
     }
 
-    fn clear_screen(&self) {
-        Command::new("cmd").arg("/C").arg("cls").status().unwrap();
+    fn shutdown(&mut self, code: i32) -> ! {
+        self.flush_state();
+        exit(code);
     }
 
-    fn rename_dir(&self, old_name: Option<&str>, new_name: Option<&str>) {
-        if let (Some(old), Some(new)) = (old_name, new_name) {
-            let old_path = self.current_dir.join(old);
-            let new_path = self.current_dir.join(new);
-            if fs::rename(old_path, new_path).is_err() {
-                println!("Failed to rename directory.");
+    // An interactively entered line: history expansion, then into the history, then run.
+    fn execute_command(&mut self, command: &str) -> i32 {
+        // Blank and comment-only lines (pasted from a script, say) do nothing.
+        if parser::strip_comment(command).trim().is_empty() {
+            return self.last_status;
+        }
+        let expanded;
+        let command = if command.starts_with('!') {
+            match self.expand_history(command) {
+                Ok(line) => {
+                    // Show what `!!`/`!N` turned into before running it.
+                    outln!(self, "{}", line);
+                    expanded = line;
+                    expanded.as_str()
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
             }
         } else {
-            println!("Usage: rename <old_name> <new_name>");
+            command
+        };
+        self.add_to_command_history(command);
+        self.run_hooks("pre", command, self.last_status);
+        let status = self.run_line(command);
+        self.run_hooks("post", command, status);
+        status
+    }
+
+    // Appends a line to the command log, if logging is on: `set logging on`, or `RUBIN_LOG`
+    // naming the file. If it can't be written there's one warning, then no more logging.
+    // `dir` is where the command started, before any `cd` it did.
+    fn log_command(&mut self, command: &str, dir: &Path, status: i32) {
+        let log_var = self.lookup_variable("RUBIN_LOG").filter(|path| !path.is_empty());
+        if self.log_failed || parser::strip_comment(command).trim().is_empty() || !(self.options.logging || log_var.is_some()) {
+            return;
         }
+        let Some(path) = log_var.map(|path| self.current_dir.join(path)).or_else(command_log_path) else { return };
+        let entry = LogEntry {
+            time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+            cwd: &dir.to_string_lossy(),
+            command,
+            status,
+        };
+        let written = fs::OpenOptions::new().create(true).append(true).open(&path).and_then(|mut file| write_log_entry(&mut file, &entry));
+        if let Err(e) = written {
+            eprintln!("Warning: failed to write the command log {}: {}; logging is off for this session", path.display(), e);
+            self.log_failed = true;
+        }
+    }
+
+    // Each hook sees the command as `$RUBIN_LAST_CMD` and, after it, its status as `$?`.
+    // Hooks don't get to change what `$?` reports afterwards.
+    fn run_hooks(&mut self, stage: &str, command: &str, status: i32) {
+        if self.running_hooks {
+            return;
+        }
+        let hooks = if stage == "pre" { self.hooks.pre.clone() } else { self.hooks.post.clone() };
+        if hooks.is_empty() {
+            return;
+        }
+        self.running_hooks = true;
+        self.hook_command = Some(command.to_string());
+        for hook in &hooks {
+            self.last_status = status;
+            self.run_line(hook);
+        }
+        self.last_status = status;
+        self.hook_command = None;
+        self.running_hooks = false;
     }
 
-    fn move_file(&self, source: Option<&str>, destination: Option<&str>) {
-        if let (Some(src), Some(dest)) = (source, destination) {
-            let src_path = self.current_dir.join(src);
-            let dest_path = self.current_dir.join(dest);
-            if fs::rename(src_path, dest_path).is_err() {
-                println!("Failed to move file.");
+    // `!!` is the last entry and `!N` the Nth in the `history` listing; anything after
+    // the reference is appended to the recalled line.
+    fn expand_history(&self, command: &str) -> Result<String, String> {
+        let (reference, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+        let entry = match &reference[1..] {
+            "!" => self.command_history.last().ok_or_else(|| "!!: history is empty".to_string())?,
+            number => {
+                let index: usize = number.parse().map_err(|_| format!("{}: unsupported history reference", reference))?;
+                if self.command_history.is_empty() {
+                    return Err(format!("{}: history is empty", reference));
+                }
+                index
+                    .checked_sub(1)
+                    .and_then(|i| self.command_history.get(i))
+                    .ok_or_else(|| format!("{}: no such entry (history has {} entries)", reference, self.command_history.len()))?
             }
-        } else {
-            println!("Usage: move <source> <destination>");
+        };
+        let rest = rest.trim();
+        Ok(if rest.is_empty() { entry.clone() } else { format!("{} {}", entry, rest) })
+    }
+
+    // Runs each command of a `;`/`&&`/`||` chain in turn and returns the status of the
+    // last one that ran.
+    fn run_line(&mut self, line: &str) -> i32 {
+        self.run_chain(parser::strip_comment(line), true)
+    }
+
+    // An alias may expand to a chain of its own; its parts run without further alias
+    // expansion, which is what stops an alias that mentions itself from looping.
+    fn run_chain(&mut self, line: &str, expand_aliases: bool) -> i32 {
+        let chain = match parser::split_chain(line) {
+            Ok(chain) => chain,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        };
+
+        let mut status = 0;
+        for ChainCommand { connector, text: command, background } in chain {
+            let should_run = match connector {
+                Connector::Always => true,
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if !should_run {
+                continue;
+            }
+            // `time` is a prefix to the rest of the command, not a command of its own.
+            let timed = command.strip_prefix("time").filter(|rest| rest.starts_with(char::is_whitespace) && !background);
+            status = match (timed, self.expand_aliases(command).filter(|_| expand_aliases)) {
+                (Some(rest), _) => self.run_timed(rest.trim_start()),
+                (None, Some(Ok(expanded))) if background => self.run_background(&expanded),
+                (None, Some(Ok(expanded))) => self.run_chain(&expanded, false),
+                (None, Some(Err(e))) => {
+                    eprintln!("{}", e);
+                    1
+                }
+                (None, None) if background => self.run_background(command),
+                (None, None) => self.run_command(command),
+            };
+            self.last_status = status;
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+        status
+    }
+
+    // Runs a command and reports on stderr how long it took, along with the CPU time of
+    // the programs it started where the platform tells us.
+    fn run_timed(&mut self, command: &str) -> i32 {
+        let cpu_before = child_cpu_times();
+        let start = Instant::now();
+        let status = self.run_chain(command, true);
+        let elapsed = start.elapsed();
+        eprintln!("real {}", format_duration(elapsed));
+        if let (Some((user_before, sys_before)), Some((user, sys))) = (cpu_before, child_cpu_times()) {
+            eprintln!("user {}", format_duration(user.saturating_sub(user_before)));
+            eprintln!("sys  {}", format_duration(sys.saturating_sub(sys_before)));
+        }
+        status
+    }
+
+    // Rewrites the first word while it names an alias. An alias expanding to its own name
+    // (say `dir` -> `dir -l`) stops there, as in other shells. `None` if there's no alias.
+    fn expand_aliases(&self, command: &str) -> Option<Result<String, String>> {
+        let mut expanded = command.to_string();
+        let mut seen = Vec::new();
+        loop {
+            let (first, rest) = expanded.split_once(char::is_whitespace).unwrap_or((&expanded, ""));
+            let Some(replacement) = self.aliases.get(first).filter(|_| !seen.iter().any(|name| name == first)) else {
+                break;
+            };
+            if seen.len() == ALIAS_DEPTH_LIMIT {
+                return Some(Err(format!("alias: {}: expansion nested too deeply", seen[0])));
+            }
+            seen.push(first.to_string());
+            expanded = if rest.is_empty() { replacement.clone() } else { format!("{} {}", replacement, rest) };
         }
+        (!seen.is_empty()).then_some(Ok(expanded))
     }
 
-    fn copy_file(&self, source: Option<&str>, destination: Option<&str>) {
-        if let (Some(src), Some(dest)) = (source, destination) {
-            let src_path = self.current_dir.join(src);
-            let dest_path = self.current_dir.join(dest);
-            if fs::copy(src_path, dest_path).is_err() {
-                println!("Failed to copy file.");
+    // One command or pipeline. Its exit status is whatever `self.status` holds at the end.
+    fn run_command(&mut self, command: &str) -> i32 {
+        self.status = 0;
+        let tokens = match self.parse_line(command) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                fail!(self, "{}", e);
+                return self.status;
             }
+        };
+        let mut stages = match split_pipeline(tokens) {
+            Ok(stages) => stages,
+            Err(e) => {
+                fail!(self, "{}", e);
+                return self.status;
+            }
+        };
+
+        if stages.len() == 1 {
+            let stage = stages.remove(0);
+            self.run_stage(&stage);
         } else {
-            println!("Usage: copy <source> <destination>");
+            self.run_pipeline(&stages);
         }
+        self.status
     }
 
-    fn type_file(&self, file_name: Option<&str>) {
-        if let Some(name) = file_name {
-            let file_path = self.current_dir.join(name);
-            match fs::read_to_string(file_path) {
-                Ok(contents) => println!("{}", contents),
-                Err(_) => println!("Failed to read file."),
+    // Starts a program without waiting for it. Built-ins and pipelines run in the shell
+    // itself, so only a single external command can go in the background.
+    fn run_background(&mut self, command: &str) -> i32 {
+        self.status = 0;
+        let stage = match self.parse_line(command).and_then(split_pipeline) {
+            Ok(mut stages) if stages.len() == 1 => stages.remove(0),
+            Ok(_) => {
+                fail!(self, "Pipelines can't run in the background.");
+                return self.status;
+            }
+            Err(e) => {
+                fail!(self, "{}", e);
+                return self.status;
+            }
+        };
+        let name = stage.words[0].as_str();
+        if self.is_builtin_or_custom(name) {
+            fail!(self, "{}: only external programs can run in the background", name);
+            return self.status;
+        }
+        let Some(program) = self.find_program(name) else {
+            eprintln!("{}: command not found", name);
+            self.status = 127;
+            return self.status;
+        };
+
+        let mut child_command = self.child_command(&program);
+        child_command.args(&stage.words[1..]).stdin(Stdio::null());
+        // Its own process group keeps Ctrl-C at the prompt from reaching it.
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut child_command, 0);
+        if stage.redirect.is_some() {
+            match self.open_redirect(&stage) {
+                Some(file) => child_command.stdout(file),
+                None => return self.status,
+            };
+        }
+        if self.options.xtrace {
+            eprintln!("+ {} &", stage.words.join(" "));
+        }
+
+        match child_command.spawn() {
+            Ok(child) => {
+                let id = self.jobs.last().map_or(1, |job| job.id + 1);
+                eprintln!("[{}] {}", id, child.id());
+                self.jobs.push(Job { id, command: command.to_string(), child });
+            }
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                self.status = spawn_failure_code(&e);
             }
-        } else {
-            println!("Usage: type <file_name>");
         }
+        self.status
     }
 
-    fn exit_shell(&self) {
-        exit(0);
+    // Announces and forgets background jobs that have finished; called before each prompt.
+    fn reap_jobs(&mut self) {
+        self.jobs.retain_mut(|job| match job.child.try_wait() {
+            Ok(Some(status)) => {
+                eprintln!("[{}] {}  {}", job.id, describe_job_status(status), job.command);
+                false
+            }
+            Ok(None) => true,
+            Err(_) => false,
+        });
     }
 
-    fn handle_file_commands(&self, file_name: &str, args: &[&str]) {
-        println!("Unknown command: {}", file_name);
+    fn list_jobs(&mut self) {
+        let mut listing = Vec::new();
+        self.jobs.retain_mut(|job| {
+            let (state, running) = match job.child.try_wait() {
+                Ok(None) => ("Running".to_string(), true),
+                Ok(Some(status)) => (describe_job_status(status), false),
+                Err(e) => (e.to_string(), false),
+            };
+            listing.push(format!("[{}] {:>6}  {}  {}", job.id, job.child.id(), state, job.command));
+            running
+        });
+        for line in listing {
+            outln!(self, "{}", line);
+        }
     }
-}
 
-fn main() {
-    let mut shell = Shell::new();
-    shell.run();
+    // Blocks until the given jobs (all of them by default) finish; the status is that of
+    // the last one waited for.
+    fn wait_for_jobs(&mut self, args: &[&str]) {
+        let ids: Vec<usize> = if args.is_empty() {
+            self.jobs.iter().map(|job| job.id).collect()
+        } else {
+            let mut ids = Vec::new();
+            for arg in args {
+                match arg.trim_start_matches('%').parse() {
+                    Ok(id) => ids.push(id),
+                    Err(_) => {
+                        fail!(self, "wait: {}: not a job number", arg);
+                        return;
+                    }
+                }
+            }
+            ids
+        };
+
+        for id in ids {
+            let Some(index) = self.jobs.iter().position(|job| job.id == id) else {
+                fail!(self, "wait: %{}: no such job", id);
+                continue;
+            };
+            let mut job = self.jobs.remove(index);
+            match job.child.wait() {
+                Ok(status) => {
+                    eprintln!("[{}] {}  {}", job.id, describe_job_status(status), job.command);
+                    self.status = exit_code(status);
+                }
+                Err(e) => fail!(self, "wait: %{}: {}", id, e),
+            }
+        }
+    }
+
+    // Runs one built-in or custom command, applying its redirection if it has one.
+    fn run_stage(&mut self, stage: &Stage) {
+        let mut args: Vec<&str> = stage.words.iter().map(String::as_str).collect();
+        // Stripped here so individual commands never see it; the guards consult the flag.
+        self.guard_override = args.contains(&"--override");
+        args.retain(|arg| *arg != "--override");
+
+        if self.options.xtrace && !args.is_empty() {
+            eprintln!("+ {}", args.join(" "));
+        }
+
+        if stage.redirect.is_none() {
+            self.dispatch(&args);
+            return;
+        }
+        if let Some(file) = self.open_redirect(stage) {
+            let previous = std::mem::replace(&mut self.out, Output::File(file));
+            self.dispatch(&args);
+            self.out = previous;
+        }
+    }
+
+    fn open_redirect(&mut self, stage: &Stage) -> Option<fs::File> {
+        let (target, append) = stage.redirect.as_ref()?;
+        let target_path = self.current_dir.join(target);
+        if !append && self.options.noclobber && target_path.exists() {
+            fail!(self, "{}: already exists, not overwriting (noclobber is on)", target);
+            return None;
+        }
+        if !append && !self.guard("redirection", &[Risk::Overwrite(&target_path)]) {
+            return None;
+        }
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(*append)
+            .truncate(!append)
+            .open(&target_path);
+        match file {
+            Ok(file) => Some(file),
+            Err(e) => {
+                fail!(self, "{}: {}", target, e);
+                None
+            }
+        }
+    }
+
+    // External stages are connected with real OS pipes and run concurrently. Built-in
+    // stages run in-process, so their input is collected up front and their output is
+    // buffered before being handed to the next stage.
+    fn run_pipeline(&mut self, stages: &[Stage]) {
+        let mut input = PipeInput::Inherit;
+        let mut children = Vec::new();
+
+        for (index, stage) in stages.iter().enumerate() {
+            let is_last = index == stages.len() - 1;
+            if is_last {
+                // Earlier stages failing doesn't fail the pipeline.
+                self.status = 0;
+            }
+            let program = &stage.words[0];
+
+            if self.is_builtin_or_custom(program) {
+                self.piped_input = match std::mem::replace(&mut input, PipeInput::Inherit) {
+                    PipeInput::Inherit => None,
+                    PipeInput::Bytes(bytes) => Some(bytes),
+                    PipeInput::Child(mut stdout) => {
+                        let mut bytes = Vec::new();
+                        let _ = stdout.read_to_end(&mut bytes);
+                        Some(bytes)
+                    }
+                };
+                if is_last || stage.redirect.is_some() {
+                    self.run_stage(stage);
+                    input = PipeInput::Bytes(Vec::new());
+                } else {
+                    let previous = std::mem::replace(&mut self.out, Output::Buffer(Vec::new()));
+                    self.run_stage(stage);
+                    if let Output::Buffer(bytes) = std::mem::replace(&mut self.out, previous) {
+                        input = PipeInput::Bytes(bytes);
+                    }
+                }
+                self.piped_input = None;
+                continue;
+            }
+
+            let mut command = self.child_command(program);
+            command.args(&stage.words[1..]);
+            let mut pending_bytes = None;
+            match std::mem::replace(&mut input, PipeInput::Inherit) {
+                PipeInput::Inherit => {}
+                PipeInput::Bytes(bytes) => {
+                    command.stdin(Stdio::piped());
+                    pending_bytes = Some(bytes);
+                }
+                PipeInput::Child(stdout) => {
+                    command.stdin(Stdio::from(stdout));
+                }
+            }
+            let piped_onward = !is_last && stage.redirect.is_none();
+            if stage.redirect.is_some() {
+                match self.open_redirect(stage) {
+                    Some(file) => command.stdout(file),
+                    None => {
+                        input = PipeInput::Bytes(Vec::new());
+                        continue;
+                    }
+                };
+            } else if piped_onward {
+                command.stdout(Stdio::piped());
+            } else {
+                command.stdout(self.out.child_stdio());
+            }
+
+            if self.options.xtrace {
+                eprintln!("+ {}", stage.words.join(" "));
+            }
+            match command.spawn() {
+                Ok(mut child) => {
+                    if let (Some(bytes), Some(mut stdin)) = (pending_bytes, child.stdin.take()) {
+                        // From a separate thread, so a child that fills its stdout pipe before
+                        // reading all of its input can't deadlock us.
+                        thread::spawn(move || {
+                            let _ = stdin.write_all(&bytes);
+                        });
+                    }
+                    input = match child.stdout.take() {
+                        Some(stdout) if piped_onward => PipeInput::Child(stdout),
+                        _ => PipeInput::Bytes(Vec::new()),
+                    };
+                    children.push((index, program.clone(), child));
+                }
+                Err(e) => {
+                    eprintln!("{}: {}", program, e);
+                    self.status = spawn_failure_code(&e);
+                    input = PipeInput::Bytes(Vec::new());
+                }
+            }
+        }
+
+        // Like other shells, the pipeline's status is that of its last command.
+        let last_index = stages.len() - 1;
+        for (index, program, mut child) in children {
+            match child.wait() {
+                Ok(status) if index == last_index => self.status = exit_code(status),
+                Ok(_) => {}
+                Err(e) => fail!(self, "{}: {}", program, e),
+            }
+        }
+    }
+
+    // Asks a yes/no question on stderr and reads the answer from the shell's input. An
+    // empty answer or the end of input means no; anything unrecognised asks again.
+    fn confirm(&self, question: &str) -> bool {
+        loop {
+            eprint!("{} [y/N] ", question);
+            let _ = io::stderr().flush();
+            let mut answer = String::new();
+            match self.input.read_line(&mut answer) {
+                Ok(0) | Err(_) => {
+                    eprintln!();
+                    return false;
+                }
+                Ok(_) => {}
+            }
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "y" | "yes" => return true,
+                "" | "n" | "no" => return false,
+                _ => eprintln!("Please answer y or n."),
+            }
+        }
+    }
+
+    fn is_builtin_or_custom(&self, name: &str) -> bool {
+        find_command(name).is_some() || self.custom_commands.iter().any(|c| c.name == name)
+    }
+
+    // Input for built-ins that read stdin: the previous pipeline stage's output if there
+    // is one, otherwise the shell's own input.
+    fn input_reader(&mut self) -> Box<dyn BufRead> {
+        match self.piped_input.take() {
+            Some(bytes) => Box::new(io::Cursor::new(bytes)),
+            None => self.input.reader(),
+        }
+    }
+
+    // Runs a child to completion with the shell's current stdin and stdout wiring.
+    fn run_child(&mut self, command: &mut Command) -> io::Result<ExitStatus> {
+        let input = self.piped_input.take();
+        if input.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        let capture = matches!(self.out, Output::Buffer(_));
+        command.stdout(if capture { Stdio::piped() } else { self.out.child_stdio() });
+
+        let mut child = command.spawn()?;
+        if let (Some(bytes), Some(mut stdin)) = (input, child.stdin.take()) {
+            thread::spawn(move || {
+                let _ = stdin.write_all(&bytes);
+            });
+        }
+        if let (Output::Buffer(buffer), Some(mut stdout)) = (&mut self.out, child.stdout.take()) {
+            stdout.read_to_end(buffer)?;
+        }
+        child.wait()
+    }
+
+    fn dispatch(&mut self, args: &[&str]) {
+        let Some((&name, rest)) = args.split_first() else { return };
+        // Checked first: a custom command can only share a built-in's name if it was
+        // created with --force, to replace the built-in.
+        if let Some(command) = self.custom_commands.iter().find(|c| c.name == name) {
+            let definition = command.definition.clone();
+            self.run_custom_command(&definition, rest);
+            return;
+        }
+        let Some(spec) = find_command(name) else {
+            self.handle_file_commands(name, rest);
+            return;
+        };
+
+        // `-h` only counts on its own so commands can still use it as a regular flag.
+        let wants_help = match rest.first().copied() {
+            Some("--help") => true,
+            Some("-h") => rest.len() == 1,
+            _ => false,
+        };
+        if wants_help {
+            self.display_command_help(spec);
+        } else if rest.len() < spec.min_args || spec.max_args.is_some_and(|max| rest.len() > max) {
+            eprintln!("Usage: {}", spec.usage);
+            self.status = 2;
+        } else {
+            (spec.handler)(self, rest);
+        }
+    }
+
+    // Every child process is built through here so it sees the shell's variables and
+    // starts in its current directory.
+    fn child_command<S: AsRef<OsStr>>(&self, program: S) -> Command {
+        // Keep anything we've printed ahead of the child's output.
+        let _ = io::stdout().flush();
+        let mut command = Command::new(program);
+        command.envs(&self.env_vars).current_dir(&self.current_dir);
+        if let Some(hook_command) = &self.hook_command {
+            command.env("RUBIN_LAST_CMD", hook_command);
+        }
+        command
+    }
+
+    fn lookup_variable(&self, name: &str) -> Option<String> {
+        if name == "?" {
+            return Some(self.last_status.to_string());
+        }
+        if let Some(command) = self.hook_command.as_ref().filter(|_| name == "RUBIN_LAST_CMD") {
+            return Some(command.clone());
+        }
+        self.env_vars.get(name).cloned().or_else(|| env::var(name).ok())
+    }
+
+    // Splits a line into tokens, expanding variables from the shell, then the process
+    // environment. Unknown variables stay literal unless `set -u` is on.
+    fn parse_line(&self, input: &str) -> Result<Vec<Token>, String> {
+        parser::tokenize(input, |name| match self.lookup_variable(name) {
+            Some(value) => Ok(Some(value)),
+            None if self.options.nounset => Err(format!("{}: unbound variable", name)),
+            None => Ok(None),
+        })
+    }
+
+    // Returns the script's exit code, or `None` if it couldn't be started (or was killed
+    // by a signal).
+    fn run_script(&mut self, path: &str, script_args: &[&str]) -> Option<i32> {
+        let script_full_path = self.current_dir.join(path);
+        if !script_full_path.exists() {
+            fail!(self, "Script not found: {}", path);
+            return None;
+        }
+        // The shell runs its own scripts, so they can use its built-ins, aliases and variables.
+        if script_full_path.extension().is_some_and(|extension| extension == "rubin") {
+            if !script_args.is_empty() {
+                fail!(self, "{}: .rubin scripts don't take arguments", path);
+                return None;
+            }
+            let code = self.run_command_file(&script_full_path);
+            if code != 0 {
+                eprintln!("{}: exited with code {}", path, code);
+            }
+            self.status = code;
+            return Some(code);
+        }
+
+        let mut command = match script_interpreter(&script_full_path) {
+            Some((program, interpreter_args)) => {
+                let mut command = self.child_command(program);
+                command.args(interpreter_args);
+                command
+            }
+            None => {
+                let mut command = self.child_command("sh");
+                command.args(self.sh_option_flags());
+                command
+            }
+        };
+        command.arg(&script_full_path).args(script_args);
+
+        match self.run_child(&mut command) {
+            Ok(status) => {
+                let code = status.code();
+                match code {
+                    Some(0) => {}
+                    Some(code) => eprintln!("{}: exited with code {}", path, code),
+                    None => eprintln!("{}: terminated by signal", path),
+                }
+                self.status = exit_code(status);
+                code
+            }
+            Err(e) => {
+                eprintln!("Failed to run script: {}", e);
+                self.status = spawn_failure_code(&e);
+                None
+            }
+        }
+    }
+
+    // The active `set` options in the form `sh` understands, so scripts inherit them.
+    fn sh_option_flags(&self) -> Vec<&'static str> {
+        let mut flags = Vec::new();
+        if self.options.errexit {
+            flags.push("-e");
+        }
+        if self.options.xtrace {
+            flags.push("-x");
+        }
+        if self.options.nounset {
+            flags.push("-u");
+        }
+        flags
+    }
+
+    fn set_shell_options(&mut self, args: &[&str]) {
+        if let [assignment] = args {
+            if let Some((name, value)) = assignment.split_once('=') {
+                if !script::is_variable_name(name) {
+                    fail!(self, "set: `{}` is not a valid variable name", name);
+                    return;
+                }
+                self.assign_env_var(name, value, false);
+                return;
+            }
+        }
+        if let [name, setting] = args {
+            if !name.starts_with(['-', '+']) {
+                let enable = match *setting {
+                    "on" => true,
+                    "off" => false,
+                    _ => {
+                        fail!(self, "set: {}: expected on or off, not '{}'", name, setting);
+                        return;
+                    }
+                };
+                match self.options.flag_mut(name) {
+                    Some(flag) => *flag = enable,
+                    None => fail!(self, "set: unknown option '{}' (options: {})", name, ShellOptions::NAMES.join(", ")),
+                }
+                return;
+            }
+        }
+        if args.is_empty() {
+            for name in ShellOptions::NAMES {
+                let line = format!("{:<10}{}", name, if self.options.flag(name) == Some(true) { "on" } else { "off" });
+                outln!(self, "{}", line);
+            }
+            return;
+        }
+
+        // Validate everything first so a bad flag doesn't leave options half-applied.
+        let mut changes = Vec::new();
+        for arg in args {
+            let (enable, letters) = match arg.split_at_checked(1) {
+                Some(("-", letters)) if !letters.is_empty() => (true, letters),
+                Some(("+", letters)) if !letters.is_empty() => (false, letters),
+                _ => {
+                    fail!(self, "Usage: set [-e|-x|-u|+e|+x|+u]");
+                    return;
+                }
+            };
+            for letter in letters.chars() {
+                if !matches!(letter, 'e' | 'x' | 'u') {
+                    fail!(self, "set: unknown option -{}", letter);
+                    return;
+                }
+                changes.push((letter, enable));
+            }
+        }
+        for (letter, enable) in changes {
+            match letter {
+                'e' => self.options.errexit = enable,
+                'x' => self.options.xtrace = enable,
+                _ => self.options.nounset = enable,
+            }
+        }
+    }
+
+    fn unset_options(&mut self, names: &[&str]) {
+        // All names are checked before any option changes.
+        if let Some(unknown) = names.iter().find(|name| !ShellOptions::NAMES.contains(name)) {
+            fail!(self, "unset: unknown option '{}' (options: {})", unknown, ShellOptions::NAMES.join(", "));
+            return;
+        }
+        for name in names {
+            if let Some(flag) = self.options.flag_mut(name) {
+                *flag = false;
+            }
+        }
+    }
+
+    fn source_env_file(&mut self, path: &str) {
+        let contents = match fs::read_to_string(self.current_dir.join(path)) {
+            Ok(contents) => contents,
+            Err(e) => {
+                fail!(self, "source: {}: {}", path, e);
+                return;
+            }
+        };
+        let mut loaded = 0;
+        let mut malformed = false;
+        for (index, line) in contents.lines().enumerate() {
+            let line = parser::strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            match self.parse_env_line(line) {
+                Ok((key, value, export)) => {
+                    if self.assign_env_var(&key, &value, export) {
+                        loaded += 1;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("source: {}: line {}: {}", path, index + 1, e);
+                    malformed = true;
+                }
+            }
+        }
+        outln!(self, "Sourced {} variable{} from {}.", loaded, if loaded == 1 { "" } else { "s" }, path);
+        if malformed {
+            self.status = 1;
+        }
+    }
+
+    // `[export] KEY=value`, where the value is unquoted the way the shell would (so
+    // `$NAME` sees variables set by earlier lines) and may be empty.
+    fn parse_env_line(&self, line: &str) -> Result<(String, String, bool), String> {
+        let (export, assignment) = match line.strip_prefix("export") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => (true, rest.trim_start()),
+            _ => (false, line),
+        };
+        let (key, value) = assignment.split_once('=').ok_or("expected KEY=value")?;
+        let key = key.trim();
+        validate_env_name(key).map_err(|reason| format!("invalid name '{}': {}", key, reason))?;
+        let mut words = Vec::new();
+        for token in self.parse_line(value.trim())? {
+            match token {
+                Token::Word(word) => words.push(word),
+                _ => return Err("unquoted `|` or `>` in the value".to_string()),
+            }
+        }
+        Ok((key.to_string(), words.join(" "), export))
+    }
+
+    fn handle_setenv(&mut self, args: &[&str]) {
+        match args {
+            [key, value] => self.set_env_var(key, value, false),
+            ["--export", key, value] | [key, value, "--export"] => self.set_env_var(key, value, true),
+            _ => fail!(self, "Usage: setenv [--export] <key> <value>"),
+        }
+    }
+
+    // Every program the shell starts gets its variables (see `child_command`); exporting
+    // also puts a variable in the shell's own process environment.
+    fn set_env_var(&mut self, k: &str, v: &str, export: bool) {
+        if self.assign_env_var(k, v, export) {
+            outln!(self, "Environment variable set: {}={}", k, v);
+        }
+    }
+
+    // `set_env_var` without the confirmation, for `set name=value`.
+    fn assign_env_var(&mut self, k: &str, v: &str, export: bool) -> bool {
+        if export || self.exported.contains(k) {
+            if let Err(reason) = validate_env_name(k) {
+                fail!(self, "Cannot export '{}': {}", k, reason);
+                return false;
+            }
+            if v.contains('\0') {
+                fail!(self, "Cannot export '{}': the value contains a NUL byte", k);
+                return false;
+            }
+            env::set_var(k, v);
+            self.exported.insert(k.to_string());
+        }
+        self.env_vars.insert(k.to_string(), v.to_string());
+        self.state_dirty = true;
+        true
+    }
+
+    // `export name=value` sets and exports, `export name` exports a variable that's
+    // already set, and `export` alone lists what's exported.
+    fn export_vars(&mut self, args: &[&str]) {
+        if args.is_empty() {
+            let listing: Vec<String> = self
+                .exported
+                .iter()
+                .filter_map(|name| self.env_vars.get(name).map(|value| format!("export {}='{}'", name, value.replace('\'', "'\\''"))))
+                .collect();
+            for line in listing {
+                outln!(self, "{}", line);
+            }
+            return;
+        }
+        for arg in args {
+            match arg.split_once('=') {
+                Some((key, value)) => self.set_env_var(key, value, true),
+                None => match self.lookup_variable(arg) {
+                    Some(value) => self.set_env_var(arg, &value, true),
+                    None => fail!(self, "export: {}: not set", arg),
+                },
+            }
+        }
+    }
+
+    fn list_env_vars(&mut self, args: &[&str], mark_exported: bool) {
+        let mut inherited = false;
+        for arg in args {
+            match *arg {
+                "-a" => inherited = true,
+                _ => {
+                    fail!(self, "Unknown option: {}", arg);
+                    return;
+                }
+            }
+        }
+        let mut vars: BTreeMap<String, String> = BTreeMap::new();
+        if inherited {
+            vars.extend(env::vars_os().map(|(k, v)| (k.to_string_lossy().into_owned(), v.to_string_lossy().into_owned())));
+        }
+        vars.extend(self.env_vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        let listing: Vec<String> = vars
+            .iter()
+            .map(|(key, value)| {
+                let mark = if mark_exported && self.exported.contains(key) { "  (exported)" } else { "" };
+                format!("{}={}{}", key, value, mark)
+            })
+            .collect();
+        for line in listing {
+            outln!(self, "{}", line);
+        }
+    }
+
+    fn print_env_var(&mut self, args: &[&str]) {
+        match args.first() {
+            None | Some(&"-a") => self.list_env_vars(args, true),
+            Some(key) => match self.env_vars.get(*key).cloned().or_else(|| env::var(key).ok()) {
+                Some(value) => outln!(self, "{}", value),
+                None => self.status = 1,
+            },
+        }
+    }
+
+    fn unset_env_var(&mut self, k: &str) {
+        if self.env_vars.remove(k).is_some() {
+            if self.exported.remove(k) {
+                env::remove_var(k);
+            }
+            self.state_dirty = true;
+            outln!(self, "Environment variable removed: {}", k);
+        } else {
+            fail!(self, "Environment variable not set: {}", k);
+        }
+    }
+
+    fn manage_hooks(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: hook add pre|post <command> | hook list | hook remove pre|post <number>";
+        match args {
+            ["list"] => {
+                if self.hooks.is_empty() {
+                    outln!(self, "No hooks.");
+                }
+                let listing: Vec<String> = [("pre", &self.hooks.pre), ("post", &self.hooks.post)]
+                    .into_iter()
+                    .flat_map(|(stage, hooks)| hooks.iter().enumerate().map(move |(index, hook)| format!("{:<4} {}  {}", stage, index + 1, hook)))
+                    .collect();
+                for line in listing {
+                    outln!(self, "{}", line);
+                }
+            }
+            ["add", stage, words @ ..] if !words.is_empty() => {
+                // A single argument is taken as the command line itself, so quoting it
+                // keeps `$?` and friends for when the hook runs.
+                let command = match words {
+                    [line] => line.to_string(),
+                    _ => words.iter().map(|word| quote_argument(word)).collect::<Vec<_>>().join(" "),
+                };
+                match self.hooks.stage_mut(stage) {
+                    Some(hooks) => {
+                        hooks.push(command);
+                        self.state_dirty = true;
+                    }
+                    None => fail!(self, "hook: expected pre or post, not '{}'", stage),
+                }
+            }
+            ["remove", stage, number] => {
+                let index = number.parse::<usize>().ok().and_then(|n| n.checked_sub(1));
+                let Some(hooks) = self.hooks.stage_mut(stage) else {
+                    fail!(self, "hook: expected pre or post, not '{}'", stage);
+                    return;
+                };
+                match index.filter(|&index| index < hooks.len()) {
+                    Some(index) => {
+                        hooks.remove(index);
+                        self.state_dirty = true;
+                    }
+                    None => fail!(self, "hook: no {} hook {} (see `hook list`)", stage, number),
+                }
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                self.status = 2;
+            }
+        }
+    }
+
+    fn define_alias(&mut self, args: &[&str]) {
+        if args.is_empty() {
+            let listing: Vec<String> = self.aliases.iter().map(|(name, value)| format_alias(name, value)).collect();
+            for line in listing {
+                outln!(self, "{}", line);
+            }
+            return;
+        }
+
+        for arg in args {
+            let Some((name, value)) = arg.split_once('=') else {
+                match self.aliases.get(*arg) {
+                    Some(value) => outln!(self, "{}", format_alias(arg, value)),
+                    None => fail!(self, "alias: {}: not found", arg),
+                }
+                continue;
+            };
+            if let Err(reason) = validate_alias_name(name) {
+                fail!(self, "alias: invalid name '{}': {}", name, reason);
+                continue;
+            }
+            self.aliases.insert(name.to_string(), value.to_string());
+            self.state_dirty = true;
+        }
+    }
+
+    fn remove_alias(&mut self, names: &[&str]) {
+        for name in names {
+            if self.aliases.remove(*name).is_some() {
+                self.state_dirty = true;
+            } else {
+                fail!(self, "unalias: {}: not found", name);
+            }
+        }
+    }
+
+    fn which(&mut self, names: &[&str]) {
+        for name in names {
+            if let Some(value) = self.aliases.get(*name) {
+                let line = format!("{}: aliased to `{}`", name, value);
+                outln!(self, "{}", line);
+            } else if let Some(command) = self.custom_commands.iter().find(|c| c.name == *name) {
+                let line = format!("{}: custom command `{}`", name, command.definition);
+                outln!(self, "{}", line);
+            } else if find_command(name).is_some() {
+                outln!(self, "{}: shell built-in", name);
+            } else if let Some(path) = self.find_program(name) {
+                outln!(self, "{}", path.display());
+            } else {
+                fail!(self, "which: {}: not found", name);
+            }
+        }
+    }
+
+    fn list_commands(&mut self, args: &[&str]) {
+        let external = match args {
+            [] => false,
+            ["--external"] => true,
+            _ => {
+                fail!(self, "Usage: commands [--external]");
+                return;
+            }
+        };
+        let mut builtins: Vec<&str> = COMMANDS.iter().map(|spec| spec.name).collect();
+        builtins.sort();
+        builtins.dedup();
+        let aliases: Vec<String> = self.aliases.iter().map(|(name, expansion)| format!("{} = {}", name, expansion)).collect();
+        let mut custom: Vec<String> = self
+            .custom_commands
+            .iter()
+            .map(|command| if command.description.is_empty() { command.name.clone() } else { format!("{} - {}", command.name, command.description) })
+            .collect();
+        custom.sort();
+        custom.dedup();
+        let programs = if external { self.path_programs() } else { Vec::new() };
+
+        let groups = [
+            ("Built-ins", builtins.iter().map(|name| name.to_string()).collect()),
+            ("Aliases", aliases),
+            ("Custom commands", custom),
+            ("Programs on PATH", programs),
+        ];
+        let mut first = true;
+        for (title, entries) in groups {
+            if entries.is_empty() {
+                continue;
+            }
+            if !first {
+                outln!(self);
+            }
+            first = false;
+            outln!(self, "{}:", title);
+            for entry in entries {
+                outln!(self, "  {}", entry);
+            }
+        }
+    }
+
+    // Names of the executables in the PATH directories, sorted, each once.
+    fn path_programs(&self) -> Vec<String> {
+        let path_var = self.env_vars.get("PATH").map(OsString::from).or_else(|| env::var_os("PATH"));
+        let mut names = BTreeSet::new();
+        for dir in path_var.as_deref().map(env::split_paths).into_iter().flatten() {
+            let Ok(entries) = fs::read_dir(&dir) else { continue };
+            for entry in entries.filter_map(Result::ok) {
+                if is_executable(&entry.path()) {
+                    names.insert(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        names.into_iter().collect()
+    }
+
+    fn edit_file(&mut self, args: &[&str]) {
+        let [name] = args else { return };
+        // `$EDITOR` may carry arguments of its own, like `code --wait`, quoted as on a
+        // command line.
+        let configured: Vec<String> = self
+            .lookup_variable("EDITOR")
+            .and_then(|editor| parser::tokenize(&editor, |_| Ok(None)).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|token| match token {
+                Token::Word(word) => Some(word),
+                _ => None,
+            })
+            .collect();
+        let (editor, editor_args) = match configured.split_first() {
+            Some((program, words)) => match self.find_program(program) {
+                Some(path) => (path, words.to_vec()),
+                None => {
+                    fail!(self, "edit: editor '{}' (from $EDITOR) not found", program);
+                    return;
+                }
+            },
+            None => {
+                let fallbacks: &[&str] = if cfg!(windows) { &["notepad"] } else { &["vi", "nano"] };
+                match fallbacks.iter().find_map(|program| self.find_program(program)) {
+                    Some(path) => (path, Vec::new()),
+                    None => {
+                        fail!(self, "edit: no editor found; set one with `setenv EDITOR <program>`");
+                        return;
+                    }
+                }
+            }
+        };
+
+        let path = self.current_dir.join(name);
+        if !path.exists() {
+            if !self.guard("edit", &[Risk::Modify(&path)]) {
+                return;
+            }
+            if let Err(e) = fs::File::create(&path) {
+                fail!(self, "edit: {}: {}", name, e);
+                return;
+            }
+            self.record_operation(FileOperation::CreatedFile(path.clone()));
+        }
+        // The editor needs the terminal, so it isn't wired into a pipeline.
+        let mut command = self.child_command(&editor);
+        command.args(editor_args).arg(&path).stdin(Stdio::inherit()).stdout(Stdio::inherit());
+        match command.status() {
+            Ok(status) => self.status = exit_code(status),
+            Err(e) => {
+                fail!(self, "edit: {}: {}", editor.display(), e);
+                self.status = spawn_failure_code(&e);
+            }
+        }
+    }
+
+    // Looks a program up on the PATH children will see, which `setenv PATH` can change.
+    fn find_program(&self, name: &str) -> Option<PathBuf> {
+        let path_var = self.env_vars.get("PATH").map(OsString::from).or_else(|| env::var_os("PATH"));
+        find_in_path(name, path_var.as_deref(), &self.current_dir)
+    }
+
+    fn handle_custom_command(&mut self, args: &[&str]) {
+        match args[0] {
+            "create" => self.create_custom_command(&args[1..]),
+            "list" => self.list_custom_commands(),
+            "delete" => self.delete_custom_command(args.get(1).copied()),
+            "refactor" => self.refactor_custom_command(
+                args.get(1).copied(),
+                args.get(2).copied(),
+                args.get(3).copied()
+            ),
+            "complete" => self.set_custom_completion(args.get(1).copied(), args.get(2).copied()),
+            "export" => self.export_custom_commands(args.get(1).copied()),
+            "import" => self.import_custom_commands(&args[1..]),
+            _ => fail!(self, "Unknown custom command action: {}", args[0]),
+        }
+    }
+
+    fn create_custom_command(&mut self, args: &[&str]) {
+        let force = args.contains(&"--force");
+        let args: Vec<&str> = args.iter().copied().filter(|arg| *arg != "--force").collect();
+        if let [name, definition, description] = args[..] {
+            if let Err(reason) = validate_custom_command_name(name) {
+                fail!(self, "Invalid command name '{}': {}", name, reason);
+                return;
+            }
+            if self.custom_commands.iter().any(|c| c.name == name) {
+                fail!(self, "A custom command named '{}' already exists; use `cc refactor` to change it.", name);
+                return;
+            }
+            if find_command(name).is_some() {
+                if !force {
+                    fail!(self, "'{}' is a built-in command; add --force to replace it with a custom command.", name);
+                    return;
+                }
+                eprintln!("Warning: custom command '{}' replaces the built-in of the same name.", name);
+            }
+            let command = CustomCommand {
+                name: name.to_string(),
+                definition: definition.to_string(),
+                description: description.to_string(),
+                completion: None,
+            };
+            self.custom_commands.push(command);
+            self.state_dirty = true;
+            outln!(self, "Custom command '{}' created.", name);
+        } else {
+            fail!(self, "Usage: cc create <command_name> <command_definition> <command_description> [--force]");
+        }
+    }
+
+    fn run_custom_command(&mut self, definition: &str, args: &[&str]) {
+        let expanded = substitute_positional_args(definition, args);
+        // Operators in a definition are passed through as plain arguments.
+        let words: Vec<String> = match self.parse_line(&expanded) {
+            Ok(tokens) => tokens
+                .into_iter()
+                .map(|token| match token {
+                    Token::Word(word) => word,
+                    Token::Redirect { append } => (if append { ">>" } else { ">" }).to_string(),
+                    Token::Pipe => "|".to_string(),
+                })
+                .collect(),
+            Err(e) => {
+                fail!(self, "{}", e);
+                return;
+            }
+        };
+        let Some((program, program_args)) = words.split_first() else {
+            fail!(self, "Custom command has an empty definition.");
+            return;
+        };
+
+        let mut command = self.child_command(program);
+        command.args(program_args);
+        match self.run_child(&mut command) {
+            Ok(status) if !status.success() => {
+                match status.code() {
+                    Some(code) => eprintln!("Command exited with status {}", code),
+                    None => eprintln!("Command was terminated by a signal"),
+                }
+                self.status = exit_code(status);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Failed to run '{}': {}", program, e);
+                self.status = spawn_failure_code(&e);
+            }
+        }
+    }
+
+    fn set_custom_completion(&mut self, cmd_name: Option<&str>, spec: Option<&str>) {
+        let Some(name) = cmd_name else {
+            fail!(self, "Usage: cc complete <command_name> <words:a,b,c|dir:<path>|none>");
+            return;
+        };
+        let Some(command) = self.custom_commands.iter_mut().find(|c| c.name == name) else {
+            fail!(self, "No custom command named '{}'.", name);
+            return;
+        };
+
+        match spec {
+            None => match &command.completion {
+                Some(spec) => outln!(self, "{}: {}", name, spec.describe()),
+                None => outln!(self, "{}: no completions registered", name),
+            },
+            Some("none") => {
+                command.completion = None;
+                self.state_dirty = true;
+                outln!(self, "Completions for '{}' removed.", name);
+            }
+            Some(spec) => match CompletionSpec::parse(spec) {
+                Some(parsed) => {
+                    command.completion = Some(parsed);
+                    self.state_dirty = true;
+                    outln!(self, "Completions for '{}' set.", name);
+                }
+                None => fail!(self, "Invalid completion spec; expected words:a,b,c or dir:<path>"),
+            },
+        }
+    }
+
+    fn print_completions(&mut self, name: &str, partial: Option<&str>) {
+        let candidates = custom_arg_candidates(&self.custom_commands, &self.current_dir, name, partial.unwrap_or(""));
+        for candidate in candidates {
+            outln!(self, "{}", candidate);
+        }
+    }
+
+    fn export_custom_commands(&mut self, file_name: Option<&str>) {
+        let Some(name) = file_name else {
+            fail!(self, "Usage: cc export <file>");
+            return;
+        };
+        let json = match serde_json::to_string_pretty(&self.custom_commands) {
+            Ok(json) => json,
+            Err(e) => {
+                fail!(self, "Failed to serialize custom commands: {}", e);
+                return;
+            }
+        };
+        let file_path = self.current_dir.join(name);
+        if !self.guard("cc export", &[Risk::Overwrite(&file_path)]) {
+            return;
+        }
+        if fs::write(&file_path, json).is_err() {
+            fail!(self, "Failed to write file.");
+        } else {
+            outln!(self, "Exported {} custom command(s) to {}.", self.custom_commands.len(), name);
+        }
+    }
+
+    fn import_custom_commands(&mut self, args: &[&str]) {
+        let overwrite = args.contains(&"--overwrite");
+        let Some(name) = args.iter().copied().find(|arg| *arg != "--overwrite") else {
+            fail!(self, "Usage: cc import <file> [--overwrite]");
+            return;
+        };
+        let contents = match fs::read_to_string(self.current_dir.join(name)) {
+            Ok(contents) => contents,
+            Err(_) => {
+                fail!(self, "Failed to read file.");
+                return;
+            }
+        };
+        let commands: Vec<CustomCommand> = match serde_json::from_str(&contents) {
+            Ok(commands) => commands,
+            Err(e) => {
+                fail!(self, "Invalid custom command file: {}", e);
+                return;
+            }
+        };
+
+        let (mut imported, mut skipped) = (0, 0);
+        for command in commands {
+            if let Err(reason) = validate_custom_command_name(&command.name) {
+                eprintln!("Skipping '{}': {}", command.name, reason);
+                skipped += 1;
+                continue;
+            }
+            if find_command(&command.name).is_some() {
+                eprintln!("Skipping '{}': that is a built-in command.", command.name);
+                skipped += 1;
+                continue;
+            }
+            match self.custom_commands.iter().position(|existing| existing.name == command.name) {
+                Some(index) if overwrite => self.custom_commands[index] = command,
+                Some(_) => {
+                    eprintln!("Skipping '{}': a command with that name already exists.", command.name);
+                    skipped += 1;
+                    continue;
+                }
+                None => self.custom_commands.push(command),
+            }
+            imported += 1;
+        }
+        if imported > 0 {
+            self.state_dirty = true;
+        }
+        outln!(self, "Imported {} custom command(s), skipped {}.", imported, skipped);
+    }
+
+    fn list_custom_commands(&mut self) {
+        if self.custom_commands.is_empty() {
+            outln!(self, "No custom commands defined.");
+        } else {
+            for (index, command) in self.custom_commands.iter().enumerate() {
+                outln!(self, "{}: {} - {} (Definition: {})", index + 1, command.name, command.description, command.definition);
+            }
+        }
+    }
+
+    // Finds a custom command by its number in `cc list` or by name. A number is always
+    // taken as a position, as it was before names were accepted.
+    fn find_custom_command(&mut self, key: &str) -> Option<usize> {
+        if let Ok(number) = key.parse::<usize>() {
+            if number > 0 && number <= self.custom_commands.len() {
+                return Some(number - 1);
+            }
+            fail!(self, "Command number out of range.");
+            return None;
+        }
+        let matches: Vec<usize> = self
+            .custom_commands
+            .iter()
+            .enumerate()
+            .filter(|(_, command)| command.name == key)
+            .map(|(index, _)| index)
+            .collect();
+        match matches[..] {
+            [index] => Some(index),
+            [] => {
+                fail!(self, "No custom command named '{}'.", key);
+                None
+            }
+            _ => {
+                let numbers: Vec<String> = matches.iter().map(|index| (index + 1).to_string()).collect();
+                fail!(self, "'{}' matches several custom commands ({}); use the command number instead.", key, numbers.join(", "));
+                None
+            }
+        }
+    }
+
+    fn delete_custom_command(&mut self, cmd_key: Option<&str>) {
+        let Some(key) = cmd_key else {
+            fail!(self, "Usage: cc delete <command_number|command_name>");
+            return;
+        };
+        if let Some(index) = self.find_custom_command(key) {
+            let removed = self.custom_commands.remove(index);
+            self.state_dirty = true;
+            outln!(self, "Custom command '{}' deleted.", removed.name);
+        }
+    }
+
+    fn refactor_custom_command(&mut self, cmd_key: Option<&str>, new_definition: Option<&str>, new_description: Option<&str>) {
+        let Some(key) = cmd_key else {
+            fail!(self, "Usage: cc refactor <command_number|command_name> <new_definition> <new_description>");
+            return;
+        };
+        if let Some(index) = self.find_custom_command(key) {
+            let command = &mut self.custom_commands[index];
+            if let Some(definition) = new_definition {
+                command.definition = definition.to_string();
+            }
+            if let Some(description) = new_description {
+                command.description = description.to_string();
+            }
+            outln!(self, "Custom command '{}' updated.", command.name);
+            self.state_dirty = true;
+        }
+    }
+
+    fn list_dir(&mut self, args: &[&str]) {
+        let mut git = false;
+        let mut long = false;
+        let mut human = false;
+        let mut all = false;
+        for arg in args {
+            match arg.strip_prefix('-') {
+                Some("-git") => git = true,
+                Some(flags) if !flags.is_empty() && flags.chars().all(|c| matches!(c, 'l' | 'h' | 'a')) => {
+                    long |= flags.contains('l');
+                    human |= flags.contains('h');
+                    all |= flags.contains('a');
+                }
+                _ => {
+                    fail!(self, "dir: unknown option '{}'", arg);
+                    return;
+                }
+            }
+        }
+        let git_statuses = if git { self.git_statuses() } else { None };
+        let use_color = self.color_output();
+
+        let entries = match fs::read_dir(&self.current_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                fail!(self, "dir: {}", e);
+                return;
+            }
+        };
+        let entries: Vec<fs::DirEntry> = entries.filter_map(Result::ok).collect();
+        if long {
+            self.list_dir_long(entries, human, all, git_statuses.as_deref());
+            return;
+        }
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let annotation = git_statuses.as_deref().and_then(|statuses| git_annotation(statuses, &name));
+            let shown = match entry.metadata().ok().and_then(|metadata| entry_color(&metadata)) {
+                Some(color) if use_color => paint(&name, color),
+                _ => name.clone(),
+            };
+            outln!(self, "{}{}", shown, format_annotation(annotation, use_color));
+        }
+    }
+
+    fn make_link(&mut self, args: &[&str]) {
+        let (hard, target, link_name) = match args {
+            ["-h", target, link_name] => (true, *target, *link_name),
+            [target, link_name] => (false, *target, *link_name),
+            _ => {
+                fail!(self, "Usage: ln [-h] <target> <link_name>");
+                return;
+            }
+        };
+        let mut link_path = self.current_dir.join(link_name);
+        // Like `move` and `copy`, an existing directory means "into this directory".
+        if link_path.is_dir() {
+            if let Some(file_name) = Path::new(target).file_name() {
+                link_path.push(file_name);
+            }
+        }
+
+        let result = if hard {
+            fs::hard_link(self.current_dir.join(target), &link_path)
+        } else {
+            let resolved = link_path.parent().map_or_else(|| PathBuf::from(target), |dir| dir.join(target));
+            create_symlink(Path::new(target), &link_path, resolved.is_dir())
+        };
+        match result {
+            Ok(()) => self.record_operation(FileOperation::CreatedFile(link_path)),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied && cfg!(windows) && !hard => fail!(
+                self,
+                "ln: {}: {} (creating symlinks on Windows needs Developer Mode or an elevated prompt)",
+                link_name,
+                e
+            ),
+            Err(e) => fail!(self, "ln: {}: {}", link_name, e),
+        }
+    }
+
+    fn identify_files(&mut self, args: &[&str]) {
+        let names = match self.expand_globs(args) {
+            Ok(names) => names,
+            Err(e) => {
+                fail!(self, "file: {}", e);
+                return;
+            }
+        };
+        for name in &names {
+            let path = self.current_dir.join(name);
+            let description = match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_symlink() => match fs::read_link(&path) {
+                    Ok(target) => format!("symbolic link to {}", target.display()),
+                    Err(e) => format!("symbolic link ({})", e),
+                },
+                Ok(metadata) if metadata.is_dir() => "directory".to_string(),
+                Ok(_) => {
+                    let mut sample = Vec::new();
+                    let read = fs::File::open(&path).and_then(|file| file.take(FILE_SAMPLE_SIZE).read_to_end(&mut sample));
+                    match read {
+                        Ok(_) => describe_contents(&sample).to_string(),
+                        Err(e) => {
+                            fail!(self, "file: {}: {}", name, e);
+                            continue;
+                        }
+                    }
+                }
+                Err(e) => {
+                    fail!(self, "file: {}: {}", name, e);
+                    continue;
+                }
+            };
+            outln!(self, "{}: {}", name, description);
+        }
+    }
+
+    fn change_mode(&mut self, args: &[&str]) {
+        let Some((mode, names)) = args.split_first() else { return };
+        let Some(change) = ModeChange::parse(mode) else {
+            fail!(self, "chmod: invalid mode '{}' (expected e.g. 644, +x or go-w)", mode);
+            return;
+        };
+        let names = match self.expand_globs(names) {
+            Ok(names) => names,
+            Err(e) => {
+                fail!(self, "chmod: {}", e);
+                return;
+            }
+        };
+        for name in &names {
+            let path = self.current_dir.join(name);
+            if !self.guard("chmod", &[Risk::Modify(&path)]) {
+                continue;
+            }
+            if let Err(e) = apply_mode_change(&path, change) {
+                fail!(self, "chmod: {}: {}", name, e);
+            }
+        }
+    }
+
+    fn stat_paths(&mut self, names: &[&str]) {
+        for (index, name) in names.iter().enumerate() {
+            let path = self.current_dir.join(name);
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    fail!(self, "stat: {}: {}", name, e);
+                    continue;
+                }
+            };
+            if index > 0 {
+                outln!(self);
+            }
+
+            let file_type = metadata.file_type();
+            if file_type.is_symlink() {
+                let target = fs::read_link(&path).map_or_else(|e| format!("? ({})", e), |target| target.display().to_string());
+                outln!(self, "    File: {} -> {}", name, target);
+            } else {
+                outln!(self, "    File: {}", name);
+            }
+            let kind = if file_type.is_symlink() {
+                "symbolic link"
+            } else if file_type.is_dir() {
+                "directory"
+            } else if file_type.is_file() {
+                "regular file"
+            } else {
+                "other"
+            };
+            outln!(self, "    Type: {}", kind);
+            match metadata.len() {
+                len if len < 1024 => outln!(self, "    Size: {} bytes", len),
+                len => outln!(self, "    Size: {} bytes ({})", len, human_size(len)),
+            }
+            outln!(self, "  Access: {}", describe_permissions(&metadata));
+            // Not every platform or filesystem records all three.
+            for (label, time) in [("Modified", metadata.modified()), ("Accessed", metadata.accessed()), (" Created", metadata.created())] {
+                match time {
+                    Ok(time) => outln!(self, "{}: {}", label, format_timestamp(time)),
+                    Err(_) => outln!(self, "{}: unavailable", label),
+                }
+            }
+        }
+    }
+
+    // Colors only make sense on the terminal, not in a file or piped to another command.
+    fn color_output(&self) -> bool {
+        matches!(self.out, Output::Stdout) && self.options.color && color_enabled()
+    }
+
+    // One row per entry: kind, size, modification time and name, directories first.
+    // Entries whose metadata can't be read still get a row, with `?` placeholders.
+    fn list_dir_long(&mut self, entries: Vec<fs::DirEntry>, human: bool, all: bool, git_statuses: Option<&[(String, String)]>) {
+        struct Row {
+            is_dir: bool,
+            kind: &'static str,
+            size: String,
+            modified: String,
+            name: String,
+            color: Option<&'static str>,
+        }
+
+        let use_color = self.color_output();
+        let mut rows: Vec<Row> = entries
+            .iter()
+            .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.metadata().ok()))
+            .filter(|(name, _)| all || !name.starts_with('.'))
+            .map(|(name, metadata)| {
+                let is_dir = metadata.as_ref().is_some_and(|m| m.is_dir());
+                let kind = match &metadata {
+                    Some(m) if m.is_dir() => "dir",
+                    Some(m) if m.is_file() => "file",
+                    Some(m) if m.is_symlink() => "link",
+                    Some(_) => "other",
+                    None => "?",
+                };
+                let size = match &metadata {
+                    Some(m) if human => human_size(m.len()),
+                    Some(m) => m.len().to_string(),
+                    None => "?".to_string(),
+                };
+                let color = metadata.as_ref().and_then(entry_color);
+                let modified = metadata
+                    .and_then(|m| m.modified().ok())
+                    .map(format_timestamp)
+                    .unwrap_or_else(|| "?".to_string());
+                Row { is_dir, kind, size, modified, name, color }
+            })
+            .collect();
+        rows.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        let size_width = rows.iter().map(|row| row.size.len()).max().unwrap_or(0);
+        for row in rows {
+            let annotation = git_statuses.and_then(|statuses| git_annotation(statuses, &row.name));
+            let shown = match row.color {
+                Some(color) if use_color => paint(&row.name, color),
+                _ => row.name.clone(),
+            };
+            outln!(
+                self,
+                "{:<5} {:>size_width$}  {:<23}  {}{}",
+                row.kind,
+                row.size,
+                row.modified,
+                shown,
+                format_annotation(annotation, use_color)
+            );
+        }
+    }
+
+    // Returns the porcelain status code and path of every change below `current_dir`,
+    // with paths made relative to it. `None` means git is unavailable or this isn't a repository.
+    fn git_statuses(&self) -> Option<Vec<(String, String)>> {
+        let prefix_output = self.child_command("git")
+            .args(["rev-parse", "--show-prefix"])
+            .output()
+            .ok()?;
+        if !prefix_output.status.success() {
+            return None;
+        }
+        let prefix = String::from_utf8_lossy(&prefix_output.stdout).trim().to_string();
+
+        let status_output = self.child_command("git")
+            .args(["status", "--porcelain", "."])
+            .output()
+            .ok()?;
+        if !status_output.status.success() {
+            return None;
+        }
+
+        let statuses = String::from_utf8_lossy(&status_output.stdout)
+            .lines()
+            .filter(|line| line.len() > 3)
+            .filter_map(|line| {
+                let (code, path) = line.split_at(3);
+                // Renames are reported as "old -> new"; the new path is what's on disk.
+                let path = path.rsplit(" -> ").next().unwrap_or(path).trim_matches('"');
+                path.strip_prefix(&prefix)
+                    .map(|relative| (code[..2].to_string(), relative.to_string()))
+            })
+            .collect();
+        Some(statuses)
+    }
+
+    fn print_working_dir(&mut self) {
+        // Fall back to the path as we know it if it can't be resolved (e.g. it was deleted).
+        let path = fs::canonicalize(&self.current_dir).unwrap_or_else(|_| self.current_dir.clone());
+        outln!(self, "{}", path.display());
+    }
+
+    fn make_dir(&mut self, args: &[&str]) {
+        let parents = args[0] == "-p";
+        let [name] = args[usize::from(parents)..] else {
+            fail!(self, "Usage: mkdir [-p] <directory_name>");
+            return;
+        };
+        self.create_directory(name, parents);
+    }
+
+    // Returns whether the directory exists afterwards.
+    fn create_directory(&mut self, name: &str, parents: bool) -> bool {
+        let path = self.current_dir.join(name);
+        let created: Vec<PathBuf> = path.ancestors().take_while(|p| !p.exists()).map(PathBuf::from).collect();
+        let result = if parents { fs::create_dir_all(&path) } else { fs::create_dir(&path) };
+        match result {
+            Ok(()) => {
+                if !created.is_empty() {
+                    self.record_operation(FileOperation::CreatedDirs(created));
+                }
+                true
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                fail!(self, "mkdir: {}: already exists", name);
+                false
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !parents => {
+                fail!(self, "mkdir: {}: parent directory does not exist (use -p to create it)", name);
+                false
+            }
+            Err(e) => {
+                fail!(self, "mkdir: {}: {}", name, e);
+                false
+            }
+        }
+    }
+
+    fn remove_dir(&mut self, args: &[&str]) {
+        let mut recursive = false;
+        let mut force = false;
+        let mut quiet = false;
+        let mut trash = self.options.trash;
+        let mut name = None;
+        for arg in args {
+            match *arg {
+                "-r" => recursive = true,
+                "-f" => force = true,
+                "--quiet" => quiet = true,
+                "--trash" => trash = true,
+                "-rf" | "-fr" => {
+                    recursive = true;
+                    force = true;
+                }
+                _ if name.is_none() => name = Some(*arg),
+                _ => {
+                    fail!(self, "Usage: rmdir [-r|-rf] [--trash] [--quiet] <directory_name>");
+                    return;
+                }
+            }
+        }
+        let Some(name) = name else {
+            fail!(self, "Usage: rmdir [-r|-rf] [--trash] [--quiet] <directory_name>");
+            return;
+        };
+
+        let path = self.current_dir.join(name);
+        let mut risks = vec![Risk::Modify(&path)];
+        if recursive {
+            risks.push(Risk::RecursiveDelete(&path));
+        }
+        if !self.guard("rmdir", &risks) {
+            return;
+        }
+
+        if !path.is_dir() {
+            match fs::symlink_metadata(&path) {
+                Ok(_) => fail!(self, "rmdir: {}: Not a directory", name),
+                Err(_) => fail!(self, "rmdir: {}: No such directory", name),
+            }
+            return;
+        }
+        if recursive && !force && !self.confirm(&format!("Remove {} and everything in it?", name)) {
+            fail!(self, "rmdir: {}: not removed", name);
+            return;
+        }
+
+        // An empty directory has nothing worth keeping, so only -r goes to the trash.
+        let trash = trash && recursive;
+        let result = if trash {
+            move_to_trash(&path)
+        } else if recursive {
+            remove_tree(&path, &mut Progress::new("rmdir: removed", "entries", quiet))
+        } else {
+            fs::remove_dir(&path)
+        };
+        match result {
+            Ok(()) if trash => {
+                outln!(self, "Moved {} to the trash.", name);
+                self.record_operation(FileOperation::Irreversible(format!("rmdir {} (`restore` brings it back)", name)));
+            }
+            Ok(()) => self.record_operation(FileOperation::Irreversible(format!("rmdir {}", name))),
+            Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                fail!(self, "rmdir: {}: Directory not empty (use -r to remove its contents)", name)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fail!(self, "rmdir: {}: No such directory", name),
+            Err(e) => fail!(self, "rmdir: {}: {}", name, e),
+        }
+    }
+
+    fn remove_files(&mut self, args: &[&str]) {
+        let mut interactive = false;
+        let mut force = false;
+        let mut recursive = false;
+        let mut quiet = false;
+        let mut trash = self.options.trash;
+        let mut names = Vec::new();
+        for arg in args {
+            match arg.strip_prefix('-') {
+                Some("-quiet") => quiet = true,
+                Some("-trash") => trash = true,
+                Some(flags) if !flags.is_empty() && flags.chars().all(|c| matches!(c, 'i' | 'f' | 'r')) => {
+                    interactive |= flags.contains('i');
+                    force |= flags.contains('f');
+                    recursive |= flags.contains('r');
+                }
+                _ => names.push(*arg),
+            }
+        }
+        if names.is_empty() {
+            fail!(self, "Usage: rm [-i] [-f] [-r] [--trash] [--quiet] <file>...");
+            return;
+        }
+        let names = match self.expand_globs(&names) {
+            Ok(names) => names,
+            Err(_) if force => return,
+            Err(e) => {
+                fail!(self, "rm: {}", e);
+                return;
+            }
+        };
+
+        for name in &names {
+            let path = self.current_dir.join(name);
+            // Not following links, so a link to a directory is removed like a file.
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) if force => continue,
+                Err(e) => {
+                    fail!(self, "rm: {}: {}", name, e);
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                if recursive {
+                    // rmdir asks before removing a whole tree; that's only wanted with -i here.
+                    let mut rmdir_args = vec![if interactive { "-r" } else { "-rf" }, name];
+                    if quiet {
+                        rmdir_args.push("--quiet");
+                    }
+                    if trash {
+                        rmdir_args.push("--trash");
+                    }
+                    self.remove_dir(&rmdir_args);
+                } else {
+                    fail!(self, "rm: {}: is a directory (use -r to remove it)", name);
+                }
+                continue;
+            }
+            if interactive && !self.confirm(&format!("Remove {}?", name)) {
+                continue;
+            }
+            if !self.guard("rm", &[Risk::Modify(&path)]) {
+                continue;
+            }
+            if trash {
+                match move_to_trash(&path) {
+                    Ok(()) => {
+                        outln!(self, "Moved {} to the trash.", name);
+                        self.record_operation(FileOperation::Irreversible(format!("rm {} (`restore` brings it back)", name)));
+                    }
+                    Err(e) => fail!(self, "rm: {}: {}", name, e),
+                }
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    outln!(self, "Removed {}.", name);
+                    self.record_operation(FileOperation::Irreversible(format!("rm {}", name)));
+                }
+                Err(e) => fail!(self, "rm: {}: {}", name, e),
+            }
+        }
+    }
+
+    fn restore_from_trash(&mut self, args: &[&str]) {
+        let Some(trash) = trash_dir_path() else {
+            fail!(self, "restore: no home directory to keep the trash in");
+            return;
+        };
+        let mut index = match read_trash_index(&trash) {
+            Ok(index) => index,
+            Err(e) => {
+                fail!(self, "restore: {}: {}", trash.display(), e);
+                return;
+            }
+        };
+        if args.is_empty() {
+            if index.entries.is_empty() {
+                outln!(self, "The trash is empty.");
+            }
+            for entry in &index.entries {
+                let deleted = format_timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(entry.deleted));
+                let line = format!("{}  {}", deleted, entry.original.display());
+                outln!(self, "{}", line);
+            }
+            return;
+        }
+
+        for name in args {
+            let target = normalize_path(&self.current_dir.join(name));
+            let Some(position) = index.entries.iter().rposition(|entry| entry.original == target) else {
+                fail!(self, "restore: {}: not in the trash", name);
+                continue;
+            };
+            if fs::symlink_metadata(&target).is_ok() {
+                fail!(self, "restore: {}: already exists, not overwriting", name);
+                continue;
+            }
+            if !self.guard("restore", &[Risk::Modify(&target)]) {
+                continue;
+            }
+            let stored = trash.join(&index.entries[position].stored);
+            let restored = target.parent().map_or(Ok(()), fs::create_dir_all).and_then(|()| move_path(&stored, &target));
+            match restored {
+                Ok(()) => {
+                    index.entries.remove(position);
+                    outln!(self, "Restored {}.", name);
+                    self.record_operation(FileOperation::Restored(target));
+                }
+                Err(e) => fail!(self, "restore: {}: {}", name, e),
+            }
+        }
+        if let Err(e) = write_trash_index(&trash, &index) {
+            fail!(self, "restore: {}: {}", trash.display(), e);
+        }
+    }
+
+    fn empty_trash(&mut self, _args: &[&str]) {
+        let Some(trash) = trash_dir_path().filter(|trash| trash.exists()) else {
+            outln!(self, "The trash is empty.");
+            return;
+        };
+        let count = read_trash_index(&trash).map_or(0, |index| index.entries.len());
+        if !self.guard("emptytrash", &[Risk::RecursiveDelete(&trash)]) {
+            return;
+        }
+        match remove_tree(&trash, &mut Progress::new("emptytrash: removed", "entries", false)) {
+            Ok(()) if count == 0 => outln!(self, "The trash is empty."),
+            Ok(()) => {
+                outln!(self, "Emptied the trash ({} item{}).", count, if count == 1 { "" } else { "s" });
+                self.record_operation(FileOperation::Irreversible("emptytrash".to_string()));
+            }
+            Err(e) => fail!(self, "emptytrash: {}: {}", trash.display(), e),
+        }
+    }
+
+    fn change_dir(&mut self, target: Option<&str>) {
+        if target == Some("-") {
+            self.change_to_previous_dir();
+            return;
+        }
+        let target_path = match target {
+            Some(path) => self.current_dir.join(path),
+            None => match home_dir() {
+                Some(home) => home,
+                None => {
+                    fail!(self, "Usage: cd <path>");
+                    return;
+                }
+            },
+        };
+        let display = target.map(String::from).unwrap_or_else(|| target_path.display().to_string());
+
+        match fs::metadata(&target_path) {
+            Ok(metadata) if metadata.is_dir() => match target_path.canonicalize() {
+                Ok(resolved) => self.enter_dir(resolved),
+                Err(e) => fail!(self, "cd: {}: {}", display, e),
+            },
+            Ok(_) => fail!(self, "cd: {}: Not a directory", display),
+            Err(_) => fail!(self, "cd: {}: No such directory", display),
+        }
+    }
+
+    // `cd -`: back to wherever we were before the last change, printing where that is.
+    // Doing it again toggles between the two.
+    fn change_to_previous_dir(&mut self) {
+        let Some(previous) = self.previous_dir.clone() else {
+            fail!(self, "cd: no previous directory yet");
+            return;
+        };
+        if !previous.is_dir() {
+            fail!(self, "cd: {}: No such directory", previous.display());
+            return;
+        }
+        self.enter_dir(previous);
+        outln!(self, "{}", self.current_dir.display());
+    }
+
+    // Moves to `path` and records it in the navigation history. Like a browser, any
+    // forward history is dropped when navigating somewhere new after going back.
+    fn enter_dir(&mut self, path: PathBuf) {
+        if self.history.get(self.history_index) != Some(&path) {
+            self.history.truncate(self.history_index + 1);
+            self.history.push(path.clone());
+            let excess = self.history.len().saturating_sub(self.history_limit());
+            self.history.drain(..excess);
+            self.history_index = self.history.len() - 1;
+        }
+        self.switch_dir(path);
+    }
+
+    // Every change of directory goes through here, so `cd -` always knows where we were.
+    // The process directory follows along, so anything resolving relative paths itself
+    // (rather than through `current_dir`) agrees with the shell.
+    fn switch_dir(&mut self, path: PathBuf) {
+        if let Err(e) = env::set_current_dir(&path) {
+            eprintln!("Warning: could not change the process directory to {}: {}", path.display(), e);
+        }
+        if path != self.current_dir {
+            self.previous_dir = Some(std::mem::replace(&mut self.current_dir, path));
+        }
+    }
+
+    // The stack is separate from the `<-`/`->` history, though every change it makes is
+    // recorded there like any other `cd`.
+    fn push_dir(&mut self, target: Option<&str>) {
+        let from = self.current_dir.clone();
+        match target {
+            Some(path) => self.change_dir(Some(path)),
+            None => {
+                let Some(top) = self.dir_stack.pop() else {
+                    fail!(self, "pushd: the directory stack is empty");
+                    return;
+                };
+                if !top.is_dir() {
+                    fail!(self, "pushd: {}: No such directory", top.display());
+                    self.dir_stack.push(top);
+                    return;
+                }
+                self.enter_dir(top);
+            }
+        }
+        if self.status == 0 {
+            self.dir_stack.push(from);
+            self.list_dir_stack();
+        }
+    }
+
+    fn pop_dir(&mut self) {
+        let Some(top) = self.dir_stack.pop() else {
+            fail!(self, "popd: the directory stack is empty");
+            return;
+        };
+        if !top.is_dir() {
+            fail!(self, "popd: {}: No such directory (removed from the stack)", top.display());
+            return;
+        }
+        self.enter_dir(top);
+        self.list_dir_stack();
+    }
+
+    fn list_dir_stack(&mut self) {
+        let lines: Vec<String> = std::iter::once(&self.current_dir)
+            .chain(self.dir_stack.iter().rev())
+            .enumerate()
+            .map(|(index, dir)| format!("{}  {}", index, dir.display()))
+            .collect();
+        for line in lines {
+            outln!(self, "{}", line);
+        }
+    }
+
+    // If the current directory has been deleted (by another program, or `rmdir` of a
+    // parent), moves to its nearest surviving ancestor, or home, so commands keep working.
+    fn recover_current_dir(&mut self) {
+        if self.current_dir.is_dir() {
+            return;
+        }
+        let fallback = self
+            .current_dir
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.is_dir())
+            .map(Path::to_path_buf)
+            .or_else(|| home_dir().filter(|home| home.is_dir()))
+            .unwrap_or_else(|| PathBuf::from(std::path::MAIN_SEPARATOR_STR));
+        eprintln!("Warning: {} no longer exists; moved to {}", self.current_dir.display(), fallback.display());
+        self.enter_dir(fallback);
+    }
+
+    // Both directions are no-ops on an empty history rather than indexing out of range.
+    fn go_backward(&mut self) {
+        if self.history_index > 0 {
+            if let Some(dir) = self.history.get(self.history_index - 1).cloned() {
+                self.history_index -= 1;
+                self.switch_dir(dir);
+            }
+        }
+    }
+
+    fn go_forward(&mut self) {
+        let Some(last) = self.history.len().checked_sub(1) else { return };
+        if self.history_index < last {
+            self.history_index += 1;
+            self.switch_dir(self.history[self.history_index].clone());
+        }
+    }
+
+    fn repeat(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: repeat [--stop-on-error] <count> <command> [args...]";
+        let (stop_on_error, args) = match args {
+            ["--stop-on-error", rest @ ..] => (true, rest),
+            _ => (false, args),
+        };
+        let [count, words @ ..] = args else {
+            fail!(self, "{}", USAGE);
+            return;
+        };
+        let count = match count.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                fail!(self, "repeat: invalid count '{}' (expected a positive whole number)", count);
+                return;
+            }
+        };
+        if words.is_empty() {
+            fail!(self, "{}", USAGE);
+            return;
+        }
+        // As with `watch`, quoting keeps the words from being expanded a second time.
+        let command = words.iter().map(|word| quote_argument(word)).collect::<Vec<_>>().join(" ");
+        let mut status = 0;
+        for run in 1..=count {
+            status = self.run_line(&command);
+            if INTERRUPTED.load(Ordering::SeqCst) {
+                break;
+            }
+            if status != 0 && stop_on_error {
+                eprintln!("repeat: run {} of {} failed with status {}; stopping", run, count, status);
+                break;
+            }
+        }
+        self.status = status;
+    }
+
+    fn watch(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: watch [-n <seconds>] <command> [args...]";
+        let (interval, words) = match args {
+            ["-n", value, words @ ..] => match value.parse::<f64>() {
+                Ok(seconds) if seconds.is_finite() && seconds > 0.0 => (Duration::from_secs_f64(seconds), words),
+                _ => {
+                    fail!(self, "watch: invalid interval '{}' (expected a positive number of seconds)", value);
+                    return;
+                }
+            },
+            ["-n"] => {
+                fail!(self, "watch: -n expects a number of seconds");
+                return;
+            }
+            words => (Duration::from_secs(2), words),
+        };
+        if words.is_empty() {
+            fail!(self, "{}", USAGE);
+            return;
+        }
+        // The words have already been expanded once; quoting stops it happening again.
+        let command = words.iter().map(|word| quote_argument(word)).collect::<Vec<_>>().join(" ");
+        let clear = matches!(self.out, Output::Stdout) && io::stdout().is_terminal();
+
+        let mut status = 0;
+        while !INTERRUPTED.load(Ordering::SeqCst) && !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            if clear {
+                self.clear_screen();
+            }
+            let header = format!("Every {:.1}s: {}    {}", interval.as_secs_f64(), command, format_timestamp(SystemTime::now()));
+            outln!(self, "{}", header);
+            outln!(self);
+            status = self.run_line(&command);
+            let _ = self.out.flush();
+            // Sleep in short steps so Ctrl-C doesn't have to wait out a long interval.
+            let deadline = Instant::now() + interval;
+            while Instant::now() < deadline && !INTERRUPTED.load(Ordering::SeqCst) {
+                thread::sleep((deadline - Instant::now()).min(Duration::from_millis(100)));
+            }
+        }
+        outln!(self);
+        self.status = status;
+    }
+
+    fn clear_screen(&mut self) {
+        if terminal_supports_ansi() {
+            out!(self, "\x1b[2J\x1b[H");
+            let _ = self.out.flush();
+            return;
+        }
+
+        let status = if cfg!(windows) {
+            Command::new("cmd").args(["/C", "cls"]).status()
+        } else {
+            Command::new("clear").status()
+        };
+        if let Err(e) = status {
+            fail!(self, "Failed to clear the screen: {}", e);
+        }
+    }
+
+    fn rename_dir(&mut self, args: &[&str]) {
+        if args[0] == "--sub" {
+            self.rename_matching(&args[1..]);
+            return;
+        }
+        let (clobber, args) = parse_clobber(args);
+        let [old, new] = args[..] else {
+            fail!(self, "Usage: rename [-f | -i] <old_name> <new_name>");
+            return;
+        };
+        self.move_file("rename", old, new, clobber);
+    }
+
+    // `rename --sub`: works out every new name first, so the listing and the renames agree.
+    fn rename_matching(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: rename --sub <from> <to> [-g] [--apply] <pattern>...";
+        let mut global = false;
+        let mut apply = false;
+        let mut words = Vec::new();
+        for arg in args {
+            match *arg {
+                "-g" => global = true,
+                "--apply" => apply = true,
+                _ => words.push(*arg),
+            }
+        }
+        let [from, to, patterns @ ..] = &words[..] else {
+            fail!(self, "{}", USAGE);
+            return;
+        };
+        if from.is_empty() || patterns.is_empty() {
+            fail!(self, "{}", USAGE);
+            return;
+        }
+        let names = match self.expand_globs(patterns) {
+            Ok(names) => names,
+            Err(e) => {
+                fail!(self, "rename: {}", e);
+                return;
+            }
+        };
+
+        let mut planned: Vec<(String, String)> = Vec::new();
+        for name in names {
+            let path = Path::new(&name);
+            let Some(file_name) = path.file_name().and_then(OsStr::to_str) else { continue };
+            if !file_name.contains(from) {
+                continue;
+            }
+            let new_file_name = if global { file_name.replace(from, to) } else { file_name.replacen(from, to, 1) };
+            let new_name = path.with_file_name(&new_file_name).to_string_lossy().into_owned();
+            if new_file_name.is_empty() || new_file_name.contains(['/', '\\']) {
+                eprintln!("rename: {}: skipped, '{}' isn't a valid name", name, new_file_name);
+                continue;
+            }
+            let taken = fs::symlink_metadata(self.current_dir.join(&new_name)).is_ok()
+                || planned.iter().any(|(_, planned_name)| *planned_name == new_name);
+            if taken {
+                eprintln!("rename: {}: skipped, {} already exists", name, new_name);
+                continue;
+            }
+            planned.push((name, new_name));
+        }
+
+        if planned.is_empty() {
+            outln!(self, "Nothing to rename.");
+            return;
+        }
+        for (old, new) in &planned {
+            if apply {
+                self.move_file("rename", old, new, Clobber::Refuse);
+            } else {
+                let line = format!("{} -> {}", old, new);
+                outln!(self, "{}", line);
+            }
+        }
+        if !apply {
+            let count = planned.len();
+            outln!(self, "{} rename{} planned; re-run with --apply to carry out.", count, if count == 1 { "" } else { "s" });
+        }
+    }
+
+    // `move`/`copy` with wildcard sources. Several sources need an existing directory to
+    // go into; a single one may also be copied or moved into a directory by name.
+    fn transfer_files(&mut self, command: &str, args: &[&str]) {
+        let (clobber, mut args) = if command == "move" { parse_clobber(args) } else { (Clobber::Force, args.to_vec()) };
+        let mut options = CopyOptions::default();
+        if command == "copy" {
+            // `-f` overrides noclobber for one copy.
+            options.no_clobber = args.contains(&"-n") || (self.options.noclobber && !args.contains(&"-f"));
+            options.verbose = args.contains(&"-v");
+            options.verify = args.contains(&"--verify");
+            options.quiet = args.contains(&"--quiet");
+            args.retain(|arg| !matches!(*arg, "-n" | "-f" | "-v" | "--verify" | "--quiet"));
+        }
+        let Some((destination, sources)) = args.split_last() else { return };
+        if sources.is_empty() {
+            match command {
+                "move" => fail!(self, "Usage: move [-f | -i] <source>... <destination>"),
+                _ => fail!(self, "Usage: copy [-n | -f] [-v] [--verify] [--quiet] <source>... <destination>"),
+            }
+            return;
+        }
+        let sources = match self.expand_globs(sources) {
+            Ok(sources) => sources,
+            Err(e) => {
+                fail!(self, "{}: {}", command, e);
+                return;
+            }
+        };
+        let into_dir = self.current_dir.join(destination).is_dir();
+        if sources.len() > 1 && !into_dir {
+            fail!(self, "{}: {}: not a directory (needed for multiple sources)", command, destination);
+            return;
+        }
+
+        let mut total = CopyReport::default();
+        for source in &sources {
+            let target = match Path::new(source).file_name() {
+                Some(name) if into_dir => Path::new(destination).join(name).to_string_lossy().into_owned(),
+                _ => destination.to_string(),
+            };
+            if command == "move" {
+                self.move_file("move", source, &target, clobber);
+            } else if let Some(report) = self.copy_file(source, &target, options) {
+                total.files += report.files;
+                total.bytes += report.bytes;
+                total.verified += report.verified;
+            }
+        }
+        if options.verbose {
+            eprintln!("copy: {} file{}, {} bytes copied", total.files, if total.files == 1 { "" } else { "s" }, total.bytes);
+        }
+        if options.verify && total.verified > 0 {
+            let line = format!("Verified {} file{} ({}).", total.verified, if total.verified == 1 { "" } else { "s" }, VerifyDigest::NAME);
+            outln!(self, "{}", line);
+        }
+    }
+
+    // Replaces each argument containing wildcards with the sorted entries it matches. The
+    // directory part of a pattern is taken literally; only the last component is matched.
+    fn expand_globs(&self, args: &[&str]) -> Result<Vec<String>, String> {
+        let mut expanded = Vec::new();
+        for arg in args {
+            if !glob::has_wildcards(arg) {
+                expanded.push(arg.to_string());
+                continue;
+            }
+            let (dir, pattern) = match arg.rfind(['/', '\\']) {
+                Some(index) => (&arg[..=index], &arg[index + 1..]),
+                None => ("", *arg),
+            };
+            let entries = fs::read_dir(self.current_dir.join(dir)).map_err(|e| format!("{}: {}", arg, e))?;
+            let mut matches: Vec<String> = entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                // As in other shells, hidden entries only match a pattern that asks for them.
+                .filter(|name| (!name.starts_with('.') || pattern.starts_with('.')) && glob::matches(pattern, name))
+                .map(|name| format!("{}{}", dir, name))
+                .collect();
+            if matches.is_empty() {
+                return Err(format!("{}: pattern matched nothing", arg));
+            }
+            matches.sort();
+            expanded.extend(matches);
+        }
+        Ok(expanded)
+    }
+
+    // Shared by `move` and `rename`; `command` only names the caller in messages.
+    fn move_file(&mut self, command: &str, src: &str, dest: &str, clobber: Clobber) {
+        let src_path = self.current_dir.join(src);
+        let dest_path = self.current_dir.join(dest);
+        if fs::symlink_metadata(&src_path).is_err() {
+            fail!(self, "{}: {}: no such file or directory", command, src);
+            return;
+        }
+        let overwrites = fs::symlink_metadata(&dest_path).is_ok();
+        if overwrites {
+            match clobber {
+                Clobber::Refuse => {
+                    fail!(self, "{}: {}: destination exists (use -f to overwrite)", command, dest);
+                    return;
+                }
+                Clobber::Ask if !self.confirm(&format!("Overwrite {}?", dest)) => return,
+                _ => {}
+            }
+        }
+        if !self.guard(command, &[Risk::Modify(&src_path), Risk::Overwrite(&dest_path)]) {
+            return;
+        }
+        match move_path(&src_path, &dest_path) {
+            Ok(()) => self.record_move(src_path, dest_path, overwrites),
+            Err(e) => fail!(self, "{}: {}: {}", command, src, e),
+        }
+    }
+
+    // Returns what was copied, or `None` if nothing was.
+    fn copy_file(&mut self, src: &str, dest: &str, options: CopyOptions) -> Option<CopyReport> {
+        let src_path = self.current_dir.join(src);
+        let dest_path = self.current_dir.join(dest);
+        let overwrites = dest_path.exists();
+        if overwrites && options.no_clobber && !src_path.is_dir() {
+            eprintln!("copy: {}: already exists, not overwriting", dest);
+            return None;
+        }
+        let risks: &[Risk] = if options.no_clobber { &[] } else { &[Risk::Overwrite(&dest_path)] };
+        if !self.guard("copy", risks) {
+            return None;
+        }
+        if src_path.is_dir() {
+            return self.copy_dir(src, &src_path, dest, &dest_path, options);
+        }
+        match copy_contents(&src_path, &dest_path, options) {
+            Ok(bytes) => {
+                if overwrites {
+                    self.record_operation(FileOperation::Irreversible(format!("copy overwrote {}", dest)));
+                } else {
+                    self.record_operation(FileOperation::CreatedFile(dest_path));
+                }
+                Some(CopyReport { files: 1, bytes, verified: options.verify as usize, ..CopyReport::default() })
+            }
+            Err(e) => {
+                fail!(self, "copy: {}: {}", src, e);
+                None
+            }
+        }
+    }
+
+    fn copy_dir(&mut self, src: &str, src_path: &Path, dest: &str, dest_path: &Path, options: CopyOptions) -> Option<CopyReport> {
+        let merges = dest_path.exists();
+        let report = match copy_tree(src_path, dest_path, true, options) {
+            Ok(report) => report,
+            Err(e) => {
+                fail!(self, "copy: {}: {}", src, e);
+                return None;
+            }
+        };
+        for link in &report.loops {
+            eprintln!("copy: {}: symlink loop, skipped", link.display());
+        }
+        let skipped = match report.skipped {
+            0 => String::new(),
+            n => format!(", {} already existed", n),
+        };
+        outln!(self, "Copied {} file{} to {}{}.", report.files, if report.files == 1 { "" } else { "s" }, dest, skipped);
+        // Removing a whole tree is too destructive to do from `undo`, and a merge changed
+        // files that were already there.
+        let description = if merges { format!("copy merged into {}", dest) } else { format!("copy created {}", dest) };
+        self.record_operation(FileOperation::Irreversible(description));
+        Some(report)
+    }
+
+    fn safe_mode_enabled(&self) -> bool {
+        // A runtime `setenv RUBIN_SAFE_MODE ...` takes precedence over the startup setting.
+        match self.env_vars.get("RUBIN_SAFE_MODE") {
+            Some(value) => is_truthy(value),
+            None => self.safe_mode,
+        }
+    }
+
+    // Every destructive command runs its targets through here before touching the disk,
+    // so safe mode applies uniformly. Returns whether the operation may proceed.
+    fn guard(&mut self, action: &str, risks: &[Risk]) -> bool {
+        if !self.safe_mode_enabled() || self.guard_override {
+            return true;
+        }
+
+        let root = normalize_path(&self.current_dir);
+        for risk in risks {
+            let (path, reason) = match risk {
+                Risk::Overwrite(path) if path.exists() => (path, "overwrite"),
+                Risk::Overwrite(path) | Risk::Modify(path) if !normalize_path(path).starts_with(&root) => {
+                    (path, "touch a path outside the current directory:")
+                }
+                Risk::RecursiveDelete(path) => (path, "recursively delete"),
+                _ => continue,
+            };
+            fail!(self, "Safe mode blocked {}: it would {} {}.", action, reason, path.display());
+            eprintln!("Re-run with --override to proceed, or disable safe mode with `setenv RUBIN_SAFE_MODE off`.");
+            return false;
+        }
+        true
+    }
+
+    fn record_operation(&mut self, operation: FileOperation) {
+        if self.undo_stack.len() == UNDO_LIMIT {
+            self.undo_stack.remove(0);
+        }
+        self.undo_stack.push(operation);
+    }
+
+    // A move that replaced an existing file can't be fully reverted, since the old target is gone.
+    fn record_move(&mut self, from: PathBuf, to: PathBuf, overwrote: bool) {
+        if overwrote {
+            let description = format!("move overwrote {}", to.display());
+            self.record_operation(FileOperation::Irreversible(description));
+        } else {
+            self.record_operation(FileOperation::Moved { from, to });
+        }
+    }
+
+    fn undo_last_operation(&mut self) {
+        let Some(operation) = self.undo_stack.pop() else {
+            fail!(self, "Nothing to undo.");
+            return;
+        };
+
+        match operation {
+            FileOperation::Moved { from, to } => {
+                if from.exists() {
+                    fail!(self, "Cannot undo: {} already exists.", from.display());
+                    self.undo_stack.push(FileOperation::Moved { from, to });
+                } else if move_path(&to, &from).is_err() {
+                    fail!(self, "Failed to move {} back to {}.", to.display(), from.display());
+                    self.undo_stack.push(FileOperation::Moved { from, to });
+                } else {
+                    outln!(self, "Moved {} back to {}.", to.display(), from.display());
+                }
+            }
+            FileOperation::CreatedFile(path) => {
+                if fs::remove_file(&path).is_err() {
+                    fail!(self, "Failed to remove {}.", path.display());
+                } else {
+                    outln!(self, "Removed {}.", path.display());
+                }
+            }
+            FileOperation::CreatedDirs(paths) => {
+                // remove_dir refuses non-empty directories, so anything added since is kept.
+                for path in &paths {
+                    if fs::remove_dir(path).is_err() {
+                        fail!(self, "Failed to remove {} (is it empty?).", path.display());
+                        return;
+                    }
+                }
+                if let Some(top) = paths.last() {
+                    outln!(self, "Removed {}.", top.display());
+                }
+            }
+            FileOperation::Restored(path) => match move_to_trash(&path) {
+                Ok(()) => outln!(self, "Moved {} back to the trash.", path.display()),
+                Err(e) => {
+                    fail!(self, "Failed to move {} back to the trash: {}", path.display(), e);
+                    self.undo_stack.push(FileOperation::Restored(path));
+                }
+            },
+            FileOperation::Irreversible(description) => {
+                fail!(self, "Cannot undo '{}': the operation is not reversible.", description);
+            }
+        }
+    }
+
+    // Concatenates the files like `cat`, streaming them so size doesn't matter. A file
+    // that can't be read is reported and skipped.
+    fn type_files(&mut self, args: &[&str]) {
+        let number_lines = args.contains(&"-n");
+        let force = args.contains(&"--force");
+        let hex = args.contains(&"--hex");
+        let names: Vec<&str> = args.iter().copied().filter(|arg| !matches!(*arg, "-n" | "--force" | "--hex")).collect();
+        if names.is_empty() {
+            eprintln!("Usage: type [-n] [--force | --hex] <file_name>...");
+            self.status = 2;
+            return;
+        }
+        let files = match self.expand_globs(&names) {
+            Ok(files) => files,
+            Err(e) => {
+                fail!(self, "type: {}", e);
+                return;
+            }
+        };
+
+        let mut line_number = 0;
+        for file in &files {
+            let mut reader = match fs::File::open(self.current_dir.join(file)) {
+                Ok(opened) => BufReader::new(opened),
+                Err(e) => {
+                    fail!(self, "type: {}: {}", file, e);
+                    continue;
+                }
+            };
+            let result = if hex {
+                self.write_hex_dump(&mut reader, 0)
+            } else {
+                match reader.fill_buf().map(looks_binary) {
+                    Ok(true) if !force => {
+                        fail!(self, "type: {}: binary file (use --hex to inspect it, or --force to print it anyway)", file);
+                        continue;
+                    }
+                    Ok(_) if number_lines => self.write_numbered(reader, &mut line_number),
+                    Ok(_) => io::copy(&mut reader, &mut self.out).map(|_| ()),
+                    Err(e) => Err(e),
+                }
+            };
+            if let Err(e) = result {
+                fail!(self, "type: {}: {}", file, e);
+            }
+        }
+        // `type` has always ended a single file with an extra newline; kept so existing
+        // scripts see the same output.
+        if files.len() == 1 && !number_lines && !hex && self.status == 0 {
+            outln!(self);
+        }
+    }
+
+    fn hexdump(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: hexdump [-n <bytes>] [-s <offset>] [file]";
+        let (mut limit, mut skip, mut name) = (None, 0, None);
+        let mut rest = args.iter();
+        while let Some(arg) = rest.next() {
+            match *arg {
+                "-n" | "-s" => {
+                    let Some(size) = rest.next().and_then(|value| parse_size(value)) else {
+                        fail!(self, "hexdump: {} needs a size, like 256 or 4k", arg);
+                        return;
+                    };
+                    if *arg == "-n" {
+                        limit = Some(size);
+                    } else {
+                        skip = size;
+                    }
+                }
+                _ if name.is_none() && !arg.starts_with('-') => name = Some(*arg),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    self.status = 2;
+                    return;
+                }
+            }
+        }
+
+        let mut reader: Box<dyn Read> = match name {
+            Some(name) => {
+                let opened = fs::File::open(self.current_dir.join(name)).and_then(|mut file| {
+                    file.seek(SeekFrom::Start(skip))?;
+                    Ok(file)
+                });
+                match opened {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(e) => {
+                        fail!(self, "hexdump: {}: {}", name, e);
+                        return;
+                    }
+                }
+            }
+            // Input can't seek, so the skipped part is read and dropped.
+            None => {
+                let mut input = self.input_reader();
+                if let Err(e) = io::copy(&mut (&mut input).take(skip), &mut io::sink()) {
+                    fail!(self, "hexdump: {}", e);
+                    return;
+                }
+                input
+            }
+        };
+        if let Some(limit) = limit {
+            reader = Box::new(reader.take(limit));
+        }
+        if let Err(e) = self.write_hex_dump(&mut reader, skip) {
+            fail!(self, "hexdump: {}", e);
+        }
+    }
+
+    // Sixteen bytes a row: the offset (counting from `offset`), the bytes in hex, then
+    // as ASCII with `.` for anything unprintable.
+    fn write_hex_dump(&mut self, reader: &mut dyn Read, offset: u64) -> io::Result<()> {
+        let mut row = [0; 16];
+        let mut offset = offset;
+        loop {
+            let mut filled = 0;
+            while filled < row.len() {
+                match reader.read(&mut row[filled..])? {
+                    0 => break,
+                    read => filled += read,
+                }
+            }
+            if filled == 0 {
+                return Ok(());
+            }
+            writeln!(self.out, "{}", hex_dump_row(offset, &row[..filled]))?;
+            offset += filled as u64;
+        }
+    }
+
+    // Numbering carries on across files, as with `cat -n`.
+    fn write_numbered(&mut self, mut reader: impl BufRead, line_number: &mut usize) -> io::Result<()> {
+        let mut line = Vec::new();
+        while reader.read_until(b'\n', &mut line)? > 0 {
+            *line_number += 1;
+            write!(self.out, "{:>6}\t", line_number)?;
+            self.out.write_all(&line)?;
+            if !line.ends_with(b"\n") {
+                writeln!(self.out)?;
+            }
+            line.clear();
+        }
+        Ok(())
+    }
+
+    fn display_help(&mut self, command: Option<&str>) {
+        if let Some(name) = command {
+            if let Some(spec) = find_command(name) {
+                self.display_command_help(spec);
+            } else if let Some(custom) = self.custom_commands.iter().find(|c| c.name == name) {
+                let (description, definition) = (custom.description.clone(), custom.definition.clone());
+                outln!(self, "{} (custom command)", name);
+                outln!(self, "{}", description);
+                outln!(self, "Runs: {}", definition);
+            } else {
+                fail!(self, "help: no such command: {}", name);
+            }
+            return;
+        }
+
+        let width = COMMANDS
+            .iter()
+            .map(|spec| spec.name.len())
+            .chain(self.custom_commands.iter().map(|c| c.name.len()))
+            .max()
+            .unwrap_or(0);
+        outln!(self, "Available commands:");
+        for spec in COMMANDS {
+            outln!(self, "  {:<width$}  {}", spec.name, spec.summary);
+        }
+        if !self.custom_commands.is_empty() {
+            outln!(self);
+            outln!(self, "Custom commands:");
+            for command in &self.custom_commands {
+                outln!(self, "  {:<width$}  {}", command.name, command.description);
+            }
+        }
+        outln!(self);
+        outln!(self, "Run `help <command>` for usage and examples.");
+    }
+
+    fn display_command_help(&mut self, help: &CommandSpec) {
+        outln!(self, "Usage: {}", help.usage);
+        outln!(self, "{}", help.description);
+        if !help.examples.is_empty() {
+            outln!(self);
+            outln!(self, "Examples:");
+            for example in help.examples {
+                outln!(self, "  {}", example);
+            }
+        }
+    }
+
+    // Previews by default, like `rename --sub`; only --apply (or the older -i) writes.
+    fn replace_in_file(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: replace [--regex] [--apply | -i] [--no-backup] <pattern> <replacement> <file>...";
+        let (mut use_regex, mut apply, mut backup) = (false, false, true);
+        let mut positional = Vec::new();
+        for arg in args {
+            match *arg {
+                "--regex" => use_regex = true,
+                "--apply" | "-i" => apply = true,
+                "--no-backup" => backup = false,
+                _ if arg.starts_with("--") => {
+                    fail!(self, "replace: unknown option '{}'", arg);
+                    return;
+                }
+                _ => positional.push(*arg),
+            }
+        }
+        let [pattern, replacement, ref names @ ..] = positional[..] else {
+            fail!(self, "{}", USAGE);
+            return;
+        };
+        if names.is_empty() {
+            fail!(self, "{}", USAGE);
+            return;
+        }
+        let source = if use_regex { pattern.to_string() } else { regex::escape(pattern) };
+        let regex = match regex::bytes::Regex::new(&source) {
+            Ok(regex) => regex,
+            Err(e) => {
+                fail!(self, "replace: invalid pattern: {}", e);
+                return;
+            }
+        };
+        let replacer = Replacement { text: replacement.as_bytes(), expand: use_regex };
+        let names = match self.expand_globs(names) {
+            Ok(names) => names,
+            Err(e) => {
+                fail!(self, "replace: {}", e);
+                return;
+            }
+        };
+
+        let mut total = 0;
+        for name in &names {
+            let path = self.current_dir.join(name);
+            let count = match fs::File::open(&path).and_then(|file| replace_lines(&mut BufReader::new(file), None, &regex, replacer)) {
+                Ok(count) => count,
+                Err(e) => {
+                    fail!(self, "replace: {}: {}", name, e);
+                    continue;
+                }
+            };
+            total += count;
+            let line = format!("{}: {} replacement{}", name, count, if count == 1 { "" } else { "s" });
+            outln!(self, "{}", line);
+            if !apply || count == 0 || !self.guard("replace", &[Risk::Modify(&path)]) {
+                continue;
+            }
+            match rewrite_with_replacements(&path, &regex, replacer, backup) {
+                Ok(()) => self.record_operation(FileOperation::Irreversible(format!("replace in {}", name))),
+                Err(e) => fail!(self, "replace: {}: {} (file left untouched)", name, e),
+            }
+        }
+        if !apply && total > 0 {
+            outln!(self, "Nothing changed yet; re-run with --apply to write the files.");
+        }
+    }
+
+    fn number_lines(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: nl [-w <width>] [-s <sep>] [-b a|t] [file]";
+        let mut width = 6;
+        let mut separator = "\t".to_string();
+        let mut number_blank = false;
+        let mut file_name = None;
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match *arg {
+                "-w" => match iter.next().and_then(|w| w.parse::<usize>().ok()) {
+                    Some(w) if w > 0 => width = w,
+                    _ => {
+                        fail!(self, "nl: -w expects a positive number");
+                        return;
+                    }
+                },
+                "-s" => match iter.next() {
+                    Some(sep) => separator = sep.to_string(),
+                    None => {
+                        fail!(self, "{}", USAGE);
+                        return;
+                    }
+                },
+                "-b" => match iter.next().copied() {
+                    Some("a") => number_blank = true,
+                    Some("t") => number_blank = false,
+                    _ => {
+                        fail!(self, "nl: -b expects 'a' or 't'");
+                        return;
+                    }
+                },
+                name if file_name.is_none() => file_name = Some(name),
+                _ => {
+                    fail!(self, "{}", USAGE);
+                    return;
+                }
+            }
+        }
+
+        let reader: Box<dyn BufRead> = match file_name {
+            Some(name) => match fs::File::open(self.current_dir.join(name)) {
+                Ok(file) => Box::new(BufReader::new(file)),
+                Err(_) => {
+                    fail!(self, "Failed to read file.");
+                    return;
+                }
+            },
+            None => self.input_reader(),
+        };
+
+        let mut number = 1;
+        for line in reader.lines() {
+            let Ok(line) = line else {
+                fail!(self, "Failed to read file.");
+                return;
+            };
+            if line.is_empty() && !number_blank {
+                // GNU nl pads unnumbered lines so the text stays aligned.
+                outln!(self, "{:pad$}", "", pad = width + separator.len());
+            } else {
+                outln!(self, "{:>width$}{}{}", number, separator, line, width = width);
+                number += 1;
+            }
+        }
+    }
+
+    // Lines get sorted in memory, as they must be; only input is buffered, not output.
+    fn sort_lines(&mut self, args: &[&str]) {
+        let (mut reverse, mut numeric, mut file_name) = (false, false, None);
+        for arg in args {
+            match arg.strip_prefix('-') {
+                Some(flags) if !flags.is_empty() && flags.chars().all(|c| matches!(c, 'r' | 'n')) => {
+                    reverse |= flags.contains('r');
+                    numeric |= flags.contains('n');
+                }
+                None if file_name.is_none() => file_name = Some(*arg),
+                _ => {
+                    fail!(self, "Usage: sort [-r] [-n] [file]");
+                    return;
+                }
+            }
+        }
+        let Some(reader) = self.open_input("sort", file_name) else { return };
+        let mut lines = match reader.lines().collect::<io::Result<Vec<String>>>() {
+            Ok(lines) => lines,
+            Err(e) => {
+                fail!(self, "sort: {}", e);
+                return;
+            }
+        };
+
+        if numeric {
+            lines.sort_by(|a, b| {
+                let (x, y) = (leading_number(a), leading_number(b));
+                x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.cmp(b))
+            });
+        } else {
+            lines.sort();
+        }
+        if reverse {
+            lines.reverse();
+        }
+        for line in lines {
+            outln!(self, "{}", line);
+        }
+    }
+
+    // Streams, keeping only the line before, so it only drops adjacent duplicates.
+    fn unique_lines(&mut self, args: &[&str]) {
+        let (mut count, mut file_name) = (false, None);
+        for arg in args {
+            match *arg {
+                "-c" => count = true,
+                name if file_name.is_none() && !name.starts_with('-') => file_name = Some(name),
+                _ => {
+                    fail!(self, "Usage: uniq [-c] [file]");
+                    return;
+                }
+            }
+        }
+        let Some(reader) = self.open_input("uniq", file_name) else { return };
+
+        let mut run: Option<(String, usize)> = None;
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    fail!(self, "uniq: {}", e);
+                    return;
+                }
+            };
+            match &mut run {
+                Some((previous, repeats)) if *previous == line => *repeats += 1,
+                _ => {
+                    if let Some((previous, repeats)) = run.replace((line, 1)) {
+                        self.write_unique(&previous, repeats, count);
+                    }
+                }
+            }
+        }
+        if let Some((previous, repeats)) = run {
+            self.write_unique(&previous, repeats, count);
+        }
+    }
+
+    fn write_unique(&mut self, line: &str, repeats: usize, count: bool) {
+        if count {
+            outln!(self, "{:>7} {}", repeats, line);
+        } else {
+            outln!(self, "{}", line);
+        }
+    }
+
+    // The named file, or the shell's input without one, for filters like `sort`.
+    fn open_input(&mut self, command: &str, file_name: Option<&str>) -> Option<Box<dyn BufRead>> {
+        match file_name {
+            Some(name) => match fs::File::open(self.current_dir.join(name)) {
+                Ok(file) => Some(Box::new(BufReader::new(file))),
+                Err(e) => {
+                    fail!(self, "{}: {}: {}", command, name, e);
+                    None
+                }
+            },
+            None => Some(self.input_reader()),
+        }
+    }
+
+    fn head_file(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: head [-n <lines>] [file]";
+        let mut count = 10;
+        let mut file_name = None;
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match *arg {
+                "-n" => match iter.next().and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) => count = n,
+                    None => {
+                        fail!(self, "head: -n expects a number");
+                        return;
+                    }
+                },
+                name if file_name.is_none() => file_name = Some(name),
+                _ => {
+                    fail!(self, "{}", USAGE);
+                    return;
+                }
+            }
+        }
+
+        let mut reader: Box<dyn BufRead> = match file_name {
+            Some(name) => match fs::File::open(self.current_dir.join(name)) {
+                Ok(file) => Box::new(BufReader::new(file)),
+                Err(e) => {
+                    fail!(self, "head: {}: {}", name, e);
+                    return;
+                }
+            },
+            None => self.input_reader(),
+        };
+        // Stops reading as soon as it has enough, however large the file is.
+        let mut line = Vec::new();
+        let mut ends_with_newline = true;
+        for _ in 0..count {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    let _ = self.out.write_all(&line);
+                    ends_with_newline = line.ends_with(b"\n");
+                }
+                Err(e) => {
+                    fail!(self, "head: {}", e);
+                    return;
+                }
+            }
+        }
+        if !ends_with_newline {
+            outln!(self);
+        }
+    }
+
+    fn echo(&mut self, args: &[&str]) {
+        let mut newline = true;
+        let mut escapes = false;
+        // Leading flags only; anything else, `-x` included, starts the text.
+        let mut words = args;
+        while let Some((flag, rest)) = words.split_first() {
+            match flag.strip_prefix('-') {
+                Some(letters) if !letters.is_empty() && letters.chars().all(|c| matches!(c, 'n' | 'e')) => {
+                    newline &= !letters.contains('n');
+                    escapes |= letters.contains('e');
+                    words = rest;
+                }
+                _ => break,
+            }
+        }
+        let mut text = words.join(" ");
+        if escapes {
+            let (expanded, stopped) = interpret_escapes(&text);
+            text = expanded;
+            newline &= !stopped;
+        }
+        if newline {
+            outln!(self, "{}", text);
+        } else {
+            out!(self, "{}", text);
+        }
+    }
+
+    fn calculate(&mut self, args: &[&str]) {
+        match calc::evaluate(&args.join(" ")) {
+            Ok(value) => outln!(self, "{}", value),
+            Err(e) => fail!(self, "calc: {}", e),
+        }
+    }
+
+    fn word_count(&mut self, args: &[&str]) {
+        let (mut lines, mut words, mut bytes) = (false, false, false);
+        let mut names = Vec::new();
+        for arg in args {
+            match arg.strip_prefix('-') {
+                Some(flags) if !flags.is_empty() && flags.chars().all(|c| matches!(c, 'l' | 'w' | 'c')) => {
+                    lines |= flags.contains('l');
+                    words |= flags.contains('w');
+                    bytes |= flags.contains('c');
+                }
+                Some(flags) if !flags.is_empty() => {
+                    fail!(self, "wc: unknown option '{}'", arg);
+                    return;
+                }
+                _ => names.push(*arg),
+            }
+        }
+        if !(lines || words || bytes) {
+            (lines, words, bytes) = (true, true, true);
+        }
+        let names = match self.expand_globs(&names) {
+            Ok(names) => names,
+            Err(e) => {
+                fail!(self, "wc: {}", e);
+                return;
+            }
+        };
+
+        let mut rows = Vec::new();
+        if names.is_empty() {
+            let mut reader = self.input_reader();
+            match count_contents(&mut reader) {
+                Ok(counts) => rows.push((counts, String::new())),
+                Err(e) => fail!(self, "wc: {}", e),
+            }
+        }
+        for name in &names {
+            let counts = fs::File::open(self.current_dir.join(name)).and_then(|file| count_contents(&mut BufReader::new(file)));
+            match counts {
+                Ok(counts) => rows.push((counts, name.clone())),
+                Err(e) => fail!(self, "wc: {}: {}", name, e),
+            }
+        }
+        if names.len() > 1 {
+            let total = rows.iter().fold([0; 3], |total, (counts, _)| [0, 1, 2].map(|i| total[i] + counts[i]));
+            rows.push((total, "total".to_string()));
+        }
+
+        // Every column is as wide as the largest count shown, so they line up.
+        let shown = [lines, words, bytes];
+        let width = rows
+            .iter()
+            .flat_map(|(counts, _)| (0..3).filter(|&i| shown[i]).map(|i| counts[i]))
+            .max()
+            .map_or(1, |max| max.to_string().len());
+        for (counts, name) in rows {
+            let columns: Vec<String> =
+                (0..3).filter(|&i| shown[i]).map(|i| format!("{:>width$}", counts[i], width = width)).collect();
+            let line = columns.join(" ");
+            if name.is_empty() {
+                outln!(self, "{}", line);
+            } else {
+                outln!(self, "{} {}", line, name);
+            }
+        }
+    }
+
+    fn split_file(&mut self, args: &[&str]) {
+        let [name, size] = args else { return };
+        let Some(chunk_size) = parse_size(size).filter(|&size| size > 0) else {
+            fail!(self, "split: invalid chunk size '{}' (expected e.g. 512k, 10M or 1G)", size);
+            return;
+        };
+        let path = self.current_dir.join(name);
+        let length = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => {
+                fail!(self, "split: {}: not a file", name);
+                return;
+            }
+            Err(e) => {
+                fail!(self, "split: {}: {}", name, e);
+                return;
+            }
+        };
+        // An empty file still gets one (empty) part, so `join` can bring it back.
+        let count = length.div_ceil(chunk_size).max(1);
+        let parts: Vec<PathBuf> = (1..=count).map(|number| part_path(&path, number)).collect();
+        let risks: Vec<Risk> = parts.iter().map(|part| Risk::Overwrite(part)).collect();
+        if !self.guard("split", &risks) {
+            return;
+        }
+
+        if let Err(e) = split_into_parts(&path, &parts, chunk_size) {
+            fail!(self, "split: {}: {}", name, e);
+            return;
+        }
+        let line = format!("Split {} into {} part{} of up to {}.", name, count, if count == 1 { "" } else { "s" }, human_size(chunk_size));
+        outln!(self, "{}", line);
+        // `join` takes every consecutive part, so one left over from an earlier split
+        // would end up on the end.
+        let stale = part_path(&path, count + 1);
+        if stale.exists() {
+            eprintln!("split: warning: {} is left from an earlier split; remove it before joining", stale.display());
+        }
+    }
+
+    fn join_parts(&mut self, args: &[&str]) {
+        let [name] = args else { return };
+        let path = self.current_dir.join(name);
+        let parts: Vec<PathBuf> = (1..).map(|number| part_path(&path, number)).take_while(|part| part.is_file()).collect();
+        if parts.is_empty() {
+            fail!(self, "join: {}: no parts found", part_path(Path::new(name), 1).display());
+            return;
+        }
+        let overwrites = path.exists();
+        if !self.guard("join", &[Risk::Overwrite(&path)]) {
+            return;
+        }
+
+        let joined = fs::File::create(&path).and_then(|mut output| {
+            let mut total = 0;
+            for part in &parts {
+                total += io::copy(&mut fs::File::open(part)?, &mut output)?;
+            }
+            Ok(total)
+        });
+        match joined {
+            Ok(bytes) => {
+                let line = format!("Joined {} part{} into {} ({}).", parts.len(), if parts.len() == 1 { "" } else { "s" }, name, human_size(bytes));
+                outln!(self, "{}", line);
+                if overwrites {
+                    self.record_operation(FileOperation::Irreversible(format!("join overwrote {}", name)));
+                } else {
+                    self.record_operation(FileOperation::CreatedFile(path));
+                }
+            }
+            Err(e) => fail!(self, "join: {}: {}", name, e),
+        }
+    }
+
+    fn tail_file(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: tail [-n <lines>] [-f] [file]";
+        let mut count = 10;
+        let mut follow = false;
+        let mut file_name = None;
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match *arg {
+                "-n" => match iter.next().and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) => count = n,
+                    None => {
+                        fail!(self, "tail: -n expects a number");
+                        return;
+                    }
+                },
+                "-f" => follow = true,
+                name if file_name.is_none() => file_name = Some(name),
+                _ => {
+                    fail!(self, "{}", USAGE);
+                    return;
+                }
+            }
+        }
+        let Some(name) = file_name else {
+            if follow {
+                fail!(self, "tail: -f needs a file");
+                return;
+            }
+            let mut reader = self.input_reader();
+            match last_lines(&mut reader, count) {
+                Ok((lines, _)) => self.print_lines(&lines),
+                Err(e) => fail!(self, "tail: {}", e),
+            }
+            return;
+        };
+
+        let file_path = self.current_dir.join(name);
+        let mut file = match fs::File::open(&file_path) {
+            Ok(file) => file,
+            Err(_) => {
+                fail!(self, "Failed to read file.");
+                return;
+            }
+        };
+        let (lines, position) = match last_lines(&mut BufReader::new(&mut file), count) {
+            Ok(result) => result,
+            Err(_) => {
+                fail!(self, "Failed to read file.");
+                return;
+            }
+        };
+        self.print_lines(&lines);
+
+        if follow {
+            self.follow_file(&file_path, file, position);
+        }
+    }
+
+    // Lines as read, newlines included, adding one after a last line that lacks it.
+    fn print_lines(&mut self, last_lines: &VecDeque<String>) {
+        for line in last_lines {
+            out!(self, "{}", line);
+        }
+        if last_lines.back().is_some_and(|line| !line.ends_with('\n')) {
+            outln!(self);
+        }
+    }
+
+    // Polls for appended data until the shell is asked to shut down. A file that shrinks
+    // is assumed to have been truncated or rotated, so reading restarts from the top.
+    fn follow_file(&mut self, path: &Path, mut file: fs::File, mut position: u64) {
+        let mut buffer = Vec::new();
+        while !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) && !INTERRUPTED.load(Ordering::SeqCst) {
+            let _ = self.out.flush();
+            thread::sleep(Duration::from_millis(500));
+
+            let len = match fs::metadata(path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            if len < position {
+                eprintln!("tail: {}: file truncated", path.display());
+                // Reopen in case the old file was rotated away.
+                if let Ok(reopened) = fs::File::open(path) {
+                    file = reopened;
+                }
+                position = 0;
+            }
+            if len == position {
+                continue;
+            }
+
+            buffer.clear();
+            if file.seek(SeekFrom::Start(position)).is_err() || file.read_to_end(&mut buffer).is_err() {
+                fail!(self, "Failed to read file.");
+                return;
+            }
+            position += buffer.len() as u64;
+            out!(self, "{}", String::from_utf8_lossy(&buffer));
+        }
+    }
+
+    fn copy_to_clipboard(&mut self, file_name: Option<&str>) {
+        let contents = match file_name {
+            Some(name) => fs::read(self.current_dir.join(name)),
+            None => {
+                let mut buffer = Vec::new();
+                self.input_reader().read_to_end(&mut buffer).map(|_| buffer)
+            }
+        };
+        let Ok(contents) = contents else {
+            fail!(self, "Failed to read input.");
+            return;
+        };
+
+        let Ok(text) = String::from_utf8(contents) else {
+            fail!(self, "copy-clip: the input isn't text.");
+            return;
+        };
+        match self.clipboard().and_then(|clipboard| clipboard.set_text(text.as_str())) {
+            Ok(()) => outln!(self, "Copied {} bytes to the clipboard.", text.len()),
+            Err(e) => fail!(self, "No clipboard available ({}).", e),
+        }
+    }
+
+    fn paste_from_clipboard(&mut self, name: &str) {
+        let file_path = self.current_dir.join(name);
+        if !self.guard("paste-clip", &[Risk::Overwrite(&file_path)]) {
+            return;
+        }
+
+        let text = match self.clipboard().and_then(|clipboard| clipboard.get_text()) {
+            Ok(text) => text,
+            Err(arboard::Error::ContentNotAvailable) => {
+                fail!(self, "paste-clip: the clipboard holds no text.");
+                return;
+            }
+            Err(e) => {
+                fail!(self, "No clipboard available ({}).", e);
+                return;
+            }
+        };
+        if fs::write(&file_path, &text).is_err() {
+            fail!(self, "Failed to write file.");
+        } else {
+            outln!(self, "Pasted {} bytes into {}.", text.len(), name);
+        }
+    }
+
+    // Opened on first use and then kept: on X11 and Wayland, copied text is only
+    // available for as long as the clipboard that set it is.
+    fn clipboard(&mut self) -> Result<&mut arboard::Clipboard, arboard::Error> {
+        if self.clipboard.is_none() {
+            self.clipboard = Some(arboard::Clipboard::new()?);
+        }
+        Ok(self.clipboard.as_mut().expect("the clipboard was just opened"))
+    }
+
+    fn show_tree(&mut self, args: &[&str]) {
+        let mut all = false;
+        let mut max_depth = None;
+        let mut root = None;
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match *arg {
+                "-a" => all = true,
+                "--max-depth" => match iter.next().map(|value| value.parse::<usize>()) {
+                    Some(Ok(depth)) => max_depth = Some(depth),
+                    _ => {
+                        fail!(self, "tree: --max-depth expects a number");
+                        return;
+                    }
+                },
+                _ if arg.starts_with('-') => {
+                    fail!(self, "tree: unknown option '{}'", arg);
+                    return;
+                }
+                _ if root.is_none() => root = Some(*arg),
+                _ => {
+                    fail!(self, "Usage: tree [-a] [--max-depth <n>] [path]");
+                    return;
+                }
+            }
+        }
+        let root_path = self.current_dir.join(root.unwrap_or("."));
+        if !root_path.is_dir() {
+            fail!(self, "tree: {}: not a directory", root.unwrap_or("."));
+            return;
+        }
+        let use_color = self.color_output();
+        outln!(self, "{}", root.unwrap_or("."));
+
+        let (mut dirs, mut files) = (0, 0);
+        // An explicit stack, as in `find`. Each entry carries the prefix drawn for the levels
+        // above it and whether it is the last in its directory.
+        let mut pending: Vec<(PathBuf, String, bool, usize)> = Vec::new();
+        let children = |path: &Path| -> io::Result<Vec<PathBuf>> {
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(Result::ok)
+                .filter(|entry| all || !entry.file_name().to_string_lossy().starts_with('.'))
+                .map(|entry| entry.path())
+                .collect();
+            entries.sort();
+            Ok(entries)
+        };
+        let push_children = |pending: &mut Vec<_>, entries: Vec<PathBuf>, prefix: &str, depth: usize| {
+            let count = entries.len();
+            for (index, entry) in entries.into_iter().enumerate().rev() {
+                pending.push((entry, prefix.to_string(), index + 1 == count, depth));
+            }
+        };
+        match children(&root_path) {
+            Ok(entries) if max_depth != Some(0) => push_children(&mut pending, entries, "", 1),
+            Ok(_) => {}
+            Err(e) => {
+                fail!(self, "tree: {}: {}", root.unwrap_or("."), e);
+                return;
+            }
+        }
+
+        while let Some((path, prefix, last, depth)) = pending.pop() {
+            let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+            let metadata = fs::symlink_metadata(&path).ok();
+            let shown = match metadata.as_ref().and_then(entry_color) {
+                Some(color) if use_color => paint(&name, color),
+                _ => name,
+            };
+            let connector = if last { "└── " } else { "├── " };
+            // Symlinks aren't followed, so a link back up the tree can't loop.
+            if !metadata.is_some_and(|metadata| metadata.is_dir()) {
+                files += 1;
+                outln!(self, "{}{}{}", prefix, connector, shown);
+                continue;
+            }
+            dirs += 1;
+            let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+            if max_depth.is_some_and(|max| depth >= max) {
+                outln!(self, "{}{}{}", prefix, connector, shown);
+                continue;
+            }
+            match children(&path) {
+                Ok(entries) => {
+                    outln!(self, "{}{}{}", prefix, connector, shown);
+                    push_children(&mut pending, entries, &child_prefix, depth + 1);
+                }
+                Err(e) => outln!(self, "{}{}{} [unreadable: {}]", prefix, connector, shown, e),
+            }
+        }
+        outln!(self);
+        let dir_noun = if dirs == 1 { "directory" } else { "directories" };
+        outln!(self, "{} {}, {} {}", dirs, dir_noun, files, if files == 1 { "file" } else { "files" });
+    }
+
+    fn find_files(&mut self, args: &[&str]) {
+        let mut query = FindQuery::default();
+        let mut quiet = false;
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            if *flag == "--quiet" {
+                quiet = true;
+                continue;
+            }
+            if !flag.starts_with("--") {
+                if query.name.is_some() {
+                    fail!(self, "find: only one name pattern may be given");
+                    return;
+                }
+                // Without wildcards a bare pattern matches anywhere in the name.
+                query.name = Some(if glob::has_wildcards(flag) { flag.to_string() } else { format!("*{}*", flag) });
+                continue;
+            }
+            let Some(value) = iter.next().copied() else {
+                fail!(self, "find: {} expects a value", flag);
+                return;
+            };
+            match *flag {
+                "--name" => query.name = Some(value.to_string()),
+                "--type" => match value {
+                    "f" => query.kind = Some(EntryKind::File),
+                    "d" => query.kind = Some(EntryKind::Dir),
+                    _ => {
+                        fail!(self, "find: --type expects f or d, not '{}'", value);
+                        return;
+                    }
+                },
+                "--max-depth" => match value.parse() {
+                    Ok(depth) => query.max_depth = Some(depth),
+                    Err(_) => {
+                        fail!(self, "find: invalid depth '{}'", value);
+                        return;
+                    }
+                },
+                "--newer-than" => match parse_age(value) {
+                    Some(age) => query.newer_than = Some(age),
+                    None => {
+                        fail!(self, "find: invalid age '{}' (expected e.g. 30m, 3h, 2d)", value);
+                        return;
+                    }
+                },
+                "--size" => match SizeFilter::parse(value) {
+                    Some(size) => query.size = Some(size),
+                    None => {
+                        fail!(self, "find: invalid size '{}' (expected e.g. +10k, -2M)", value);
+                        return;
+                    }
+                },
+                "--contains" => query.contains = Some(value.to_string()),
+                _ => {
+                    fail!(self, "find: unknown option '{}'", flag);
+                    return;
+                }
+            }
+        }
+
+        // An explicit stack instead of recursion, so deep trees can't overflow. Entries
+        // directly in the current directory are at depth 1.
+        let mut progress = Progress::new("find: scanned", "entries", quiet);
+        let mut pending = vec![(self.current_dir.clone(), 1)];
+        while let Some((dir, depth)) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    fail!(self, "find: {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            let mut entries: Vec<fs::DirEntry> = entries.filter_map(Result::ok).collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            if query.max_depth.is_none_or(|max| depth < max) {
+                for entry in entries.iter().rev() {
+                    if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                        pending.push((entry.path(), depth + 1));
+                    }
+                }
+            }
+            for entry in &entries {
+                progress.tick();
+                if find_matches(&query, entry) {
+                    progress.clear();
+                    let path = entry.path();
+                    let relative = path.strip_prefix(&self.current_dir).unwrap_or(&path);
+                    outln!(self, "{}", relative.display());
+                }
+            }
+        }
+    }
+
+    fn grep(&mut self, args: &[&str]) {
+        const USAGE: &str = "Usage: grep [-i] [-n] [-r] [-o] <pattern> [path]...";
+        let mut options = GrepOptions::default();
+        let mut ignore_case = false;
+        let mut operands = Vec::new();
+        for arg in args {
+            match arg.strip_prefix('-') {
+                Some(flags) if !flags.is_empty() && operands.is_empty() => {
+                    for flag in flags.chars() {
+                        match flag {
+                            'i' => ignore_case = true,
+                            'n' => options.line_numbers = true,
+                            'r' => options.recursive = true,
+                            'o' => options.only_matching = true,
+                            _ => {
+                                fail!(self, "grep: unknown option '-{}'", flag);
+                                return;
+                            }
+                        }
+                    }
+                }
+                _ => operands.push(*arg),
+            }
+        }
+        let Some((pattern, paths)) = operands.split_first() else {
+            fail!(self, "{}", USAGE);
+            return;
+        };
+        let regex = match regex::RegexBuilder::new(pattern).case_insensitive(ignore_case).build() {
+            Ok(regex) => regex,
+            Err(e) => {
+                fail!(self, "grep: invalid pattern: {}", e);
+                return;
+            }
+        };
+        options.highlight = self.color_output();
+
+        if paths.is_empty() {
+            let reader = self.input_reader();
+            self.grep_reader(&regex, reader, None, &mut options);
+        } else {
+            options.show_names = paths.len() > 1 || options.recursive;
+            for path in paths {
+                self.grep_path(&regex, path, &mut options);
+            }
+        }
+        // As with other greps, finding nothing counts as failure.
+        if options.matched_lines == 0 {
+            self.status = 1;
+        }
+    }
+
+    fn grep_path(&mut self, regex: &Regex, path: &str, options: &mut GrepOptions) {
+        let full_path = self.current_dir.join(path);
+        if !full_path.is_dir() {
+            self.grep_file(regex, &full_path, path, options);
+            return;
+        }
+        if !options.recursive {
+            fail!(self, "grep: {}: Is a directory (use -r to search it)", path);
+            return;
+        }
+
+        // Same traversal as `find`: an explicit stack, unreadable directories skipped.
+        let mut pending = vec![full_path];
+        while let Some(dir) = pending.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    fail!(self, "grep: {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            let mut entries: Vec<fs::DirEntry> = entries.filter_map(Result::ok).collect();
+            entries.sort_by_key(|entry| entry.file_name());
+            for entry in entries.iter().rev() {
+                if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    pending.push(entry.path());
+                }
+            }
+            for entry in entries.iter().filter(|entry| entry.file_type().is_ok_and(|t| t.is_file())) {
+                let entry_path = entry.path();
+                let label = entry_path.strip_prefix(&self.current_dir).unwrap_or(&entry_path).display().to_string();
+                self.grep_file(regex, &entry_path, &label, options);
+            }
+        }
+    }
+
+    fn grep_file(&mut self, regex: &Regex, path: &Path, label: &str, options: &mut GrepOptions) {
+        let mut reader = match fs::File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => {
+                fail!(self, "grep: {}: {}", label, e);
+                return;
+            }
+        };
+        // Judged by the first buffer's worth, like other greps, so large files still stream.
+        match reader.fill_buf() {
+            Ok(start) if start.contains(&0) => {
+                eprintln!("grep: {}: binary file, skipped", label);
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                fail!(self, "grep: {}: {}", label, e);
+                return;
+            }
+        }
+        self.grep_reader(regex, Box::new(reader), Some(label), options);
+    }
+
+    fn grep_reader(&mut self, regex: &Regex, mut reader: Box<dyn BufRead>, label: Option<&str>, options: &mut GrepOptions) {
+        let mut line = Vec::new();
+        let mut number = 0;
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    fail!(self, "grep: {}: {}", label.unwrap_or("(standard input)"), e);
+                    break;
+                }
+            }
+            number += 1;
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\n', '\r']);
+            if !regex.is_match(text) {
+                continue;
+            }
+            options.matched_lines += 1;
+            let ranges = match_ranges(regex, text);
+            let prefix = format!(
+                "{}{}",
+                label.filter(|_| options.show_names).map_or(String::new(), |label| format!("{}:", label)),
+                if options.line_numbers { format!("{}:", number) } else { String::new() }
+            );
+            if options.only_matching {
+                for range in ranges {
+                    let matched = &text[range];
+                    let shown = if options.highlight { paint(matched, MATCH_COLOR) } else { matched.to_string() };
+                    outln!(self, "{}{}", prefix, shown);
+                }
+            } else if options.highlight {
+                outln!(self, "{}{}", prefix, highlight_ranges(text, &ranges));
+            } else {
+                outln!(self, "{}{}", prefix, text);
+            }
+        }
+    }
+
+    fn print_sysinfo(&mut self) {
+        outln!(self, "OS: {} ({})", env::consts::OS, env::consts::FAMILY);
+        outln!(self, "Architecture: {}", env::consts::ARCH);
+        match available_memory_kb() {
+            Some(kb) => outln!(self, "Available memory: {} MB", kb / 1024),
+            None => outln!(self, "Available memory: unknown"),
+        }
+    }
+
+    fn exit_shell(&mut self, args: &[&str]) {
+        let force = match args.first() {
+            None => false,
+            Some(&"-f") => true,
+            Some(other) => {
+                fail!(self, "exit: unknown option '{}'", other);
+                return;
+            }
+        };
+        self.reap_jobs();
+        if !self.jobs.is_empty() && !force && !self.exit_warned {
+            let count = self.jobs.len();
+            eprintln!("There {} {} running job{}:", if count == 1 { "is" } else { "are" }, count, if count == 1 { "" } else { "s" });
+            for job in &self.jobs {
+                eprintln!("[{}] {}", job.id, job.command);
+            }
+            eprintln!("Run `exit` again or `exit -f` to quit and kill them.");
+            self.exit_warned = true;
+            self.status = 1;
+            return;
+        }
+        for job in &mut self.jobs {
+            let _ = job.child.kill();
+            let _ = job.child.wait();
+        }
+        self.shutdown(0);
+    }
+
+    // Anything that isn't a built-in or custom command runs as a program from PATH.
+    fn handle_file_commands(&mut self, file_name: &str, args: &[&str]) {
+        let Some(program) = self.find_program(file_name) else {
+            // Only once nothing else claims the name, so autocd never hides a program.
+            if self.options.autocd && args.is_empty() && self.current_dir.join(file_name).is_dir() {
+                self.change_dir(Some(file_name));
+                return;
+            }
+            eprintln!("{}: command not found", file_name);
+            self.status = 127;
+            return;
+        };
+        let mut command = self.child_command(&program);
+        command.args(args);
+        match self.run_child(&mut command) {
+            Ok(status) => self.status = exit_code(status),
+            Err(e) => {
+                eprintln!("{}: {}", file_name, e);
+                self.status = spawn_failure_code(&e);
+            }
+        }
+    }
+}
+
+// The executable `name` resolves to: each PATH entry in turn, or, for a name with a
+// directory part, that path relative to `dir`. On Windows every PATHEXT extension is tried.
+fn find_in_path(name: &str, path_var: Option<&OsStr>, dir: &Path) -> Option<PathBuf> {
+    let mut extensions = Vec::new();
+    if !cfg!(windows) || Path::new(name).extension().is_some() {
+        extensions.push(String::new());
+    }
+    if cfg!(windows) {
+        let pathext = env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+        extensions.extend(pathext.split(';').filter(|ext| !ext.is_empty()).map(str::to_string));
+    }
+    let with_extensions = |base: PathBuf| {
+        extensions.iter().map(move |ext| {
+            let mut candidate = base.clone().into_os_string();
+            candidate.push(ext);
+            PathBuf::from(candidate)
+        })
+    };
+
+    if name.contains(['/', '\\']) {
+        return with_extensions(dir.join(name)).find(|candidate| is_executable(candidate));
+    }
+    env::split_paths(path_var?)
+        .flat_map(|entry| with_extensions(dir.join(entry).join(name)))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+// Windows has no execute bit; PATHEXT decides what runs.
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// The last `count` lines of `reader` and the number of bytes read. Only those lines are
+// ever held in memory.
+fn last_lines(reader: &mut dyn BufRead, count: usize) -> io::Result<(VecDeque<String>, u64)> {
+    let mut last_lines = VecDeque::with_capacity(count);
+    let mut position = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line)? {
+            0 => break,
+            n => position += n as u64,
+        }
+        if count > 0 {
+            if last_lines.len() == count {
+                last_lines.pop_front();
+            }
+            last_lines.push_back(String::from_utf8_lossy(&line).into_owned());
+        }
+    }
+    Ok((last_lines, position))
+}
+
+// Takes `-f`/`--force` and `-i` out of a `move` or `rename` argument list; whichever
+// comes last wins.
+fn parse_clobber<'a>(args: &[&'a str]) -> (Clobber, Vec<&'a str>) {
+    let mut clobber = Clobber::Refuse;
+    let mut rest = Vec::new();
+    for arg in args {
+        match *arg {
+            "-f" | "--force" => clobber = Clobber::Force,
+            "-i" => clobber = Clobber::Ask,
+            _ => rest.push(*arg),
+        }
+    }
+    (clobber, rest)
+}
+
+// Backslash escapes for `echo -e`. Also returns whether a `\c` cut the text short, which
+// drops the trailing newline too. Unknown escapes are kept as they are.
+fn interpret_escapes(text: &str) -> (String, bool) {
+    let mut expanded = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => expanded.push('\n'),
+            Some('t') => expanded.push('\t'),
+            Some('r') => expanded.push('\r'),
+            Some('a') => expanded.push('\x07'),
+            Some('b') => expanded.push('\x08'),
+            Some('e') => expanded.push('\x1b'),
+            Some('\\') => expanded.push('\\'),
+            Some('c') => return (expanded, true),
+            Some(other) => {
+                expanded.push('\\');
+                expanded.push(other);
+            }
+            None => expanded.push('\\'),
+        }
+    }
+    (expanded, false)
+}
+
+// How much of a file `file` looks at.
+const FILE_SAMPLE_SIZE: u64 = 8192;
+
+// Leading bytes that identify a format, checked in order. Add new formats here.
+const MAGIC_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "PNG image"),
+    (b"\xff\xd8\xff", "JPEG image"),
+    (b"GIF87a", "GIF image"),
+    (b"GIF89a", "GIF image"),
+    (b"%PDF-", "PDF document"),
+    (b"PK\x03\x04", "Zip archive"),
+    (b"PK\x05\x06", "Zip archive (empty)"),
+    (b"\x7fELF", "ELF executable"),
+    (b"MZ", "PE executable (Windows)"),
+    (b"\x1f\x8b", "gzip compressed data"),
+];
+
+// What a file holds, from its first bytes: a known signature, otherwise text or data.
+fn describe_contents(sample: &[u8]) -> &'static str {
+    if sample.is_empty() {
+        return "empty";
+    }
+    if let Some((_, description)) = MAGIC_SIGNATURES.iter().find(|(magic, _)| sample.starts_with(magic)) {
+        return description;
+    }
+    if looks_binary(sample) {
+        "data"
+    } else if sample.is_ascii() {
+        "ASCII text"
+    } else {
+        "UTF-8 text"
+    }
+}
+
+// Judged from the start of a file: a NUL byte, or bytes that aren't UTF-8. A multi-byte
+// character cut off at the end of the sample doesn't count.
+fn looks_binary(sample: &[u8]) -> bool {
+    sample.contains(&0) || std::str::from_utf8(sample).is_err_and(|e| e.error_len().is_some())
+}
+
+fn hex_dump_row(offset: u64, bytes: &[u8]) -> String {
+    let mut hex = String::new();
+    for (index, byte) in bytes.iter().enumerate() {
+        // An extra space down the middle, as in `hexdump -C`.
+        if index == 8 {
+            hex.push(' ');
+        }
+        hex.push_str(&format!("{:02x} ", byte));
+    }
+    let ascii: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+    format!("{:08x}  {:<49} |{}|", offset, hex, ascii)
+}
+
+// Quotes a word so the tokenizer reads it back unchanged, for re-running already-parsed
+// arguments as a command line.
+fn quote_argument(word: &str) -> String {
+    let plain = !word.is_empty() && !word.chars().any(|c| ESCAPABLE.contains(&c) || c.is_whitespace() || c == '#');
+    if plain {
+        return word.to_string();
+    }
+    // Single quotes have no escapes, so a `'` closes the quote, is escaped, and reopens it.
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+// `1.234s`, or `2m3.456s` past a minute.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let millis = duration.subsec_millis();
+    match seconds / 60 {
+        0 => format!("{}.{:03}s", seconds, millis),
+        minutes => format!("{}m{}.{:03}s", minutes, seconds % 60, millis),
+    }
+}
+
+// User and system CPU time used so far by the shell's finished children. `/proc` counts
+// in clock ticks, which are 100 a second on every Linux configuration in common use.
+#[cfg(target_os = "linux")]
+fn child_cpu_times() -> Option<(Duration, Duration)> {
+    const TICKS_PER_SECOND: u64 = 100;
+    let stat = fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name in parentheses may contain spaces, so count fields after it.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    let (user, sys) = (ticks(13)?, ticks(14)?);
+    let to_duration = |ticks: u64| Duration::from_millis(ticks * 1000 / TICKS_PER_SECOND);
+    Some((to_duration(user), to_duration(sys)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn child_cpu_times() -> Option<(Duration, Duration)> {
+    None
+}
+
+// Lines, words and bytes, read a line at a time so large files aren't held in memory.
+// Words are runs of non-whitespace, so multi-byte characters count once per word.
+fn count_contents(reader: &mut dyn BufRead) -> io::Result<[u64; 3]> {
+    let mut counts = [0; 3];
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        counts[0] += u64::from(line.ends_with(b"\n"));
+        counts[1] += line.split(u8::is_ascii_whitespace).filter(|word| !word.is_empty()).count() as u64;
+        counts[2] += line.len() as u64;
+        line.clear();
+    }
+    Ok(counts)
+}
+
+// `fs::remove_dir_all`, counting each entry as it goes. Links are removed, not followed.
+fn remove_tree(root: &Path, progress: &mut Progress) -> io::Result<()> {
+    // A directory is visited twice: once to empty it, then, with everything it held
+    // removed, to remove it.
+    let mut pending = vec![(root.to_path_buf(), false)];
+    while let Some((dir, emptied)) = pending.pop() {
+        if emptied {
+            fs::remove_dir(&dir)?;
+            progress.tick();
+            continue;
+        }
+        pending.push((dir.clone(), true));
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                pending.push((entry.path(), false));
+            } else {
+                fs::remove_file(entry.path())?;
+                progress.tick();
+            }
+        }
+    }
+    Ok(())
+}
+
+// `fs::rename`, falling back to copy-then-delete when the two paths are on different
+// filesystems, which rename can't cross.
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if is_cross_device(&e) => move_by_copying(from, to),
+        result => result,
+    }
+}
+
+// The fallback half of `move_path`.
+fn move_by_copying(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(from)?.is_dir() {
+        copy_tree(from, to, false, CopyOptions { quiet: true, ..CopyOptions::default() })?;
+        fs::remove_dir_all(from)
+    } else {
+        fs::copy(from, to)?;
+        fs::remove_file(from)
+    }
+}
+
+fn is_cross_device(error: &io::Error) -> bool {
+    // EXDEV on Unix, ERROR_NOT_SAME_DEVICE on Windows, for platforms that don't map them.
+    const RAW_CROSS_DEVICE: i32 = if cfg!(windows) { 17 } else { 18 };
+    error.kind() == io::ErrorKind::CrossesDevices || error.raw_os_error() == Some(RAW_CROSS_DEVICE)
+}
+
+#[derive(Clone, Copy, Default)]
+struct CopyOptions {
+    // Leave existing files alone (`copy -n`).
+    no_clobber: bool,
+    // List each file on stderr as it's copied (`copy -v`).
+    verbose: bool,
+    // Compare checksums of each file and its copy afterwards (`copy --verify`).
+    verify: bool,
+    // No running count while copying a directory (`copy --quiet`).
+    quiet: bool,
+}
+
+// The checksum `copy --verify` compares.
+type VerifyDigest = checksum::Sha256;
+
+#[derive(Default)]
+struct CopyReport {
+    files: usize,
+    bytes: u64,
+    // Files left alone because they already existed (`no_clobber`).
+    skipped: usize,
+    // Files whose copy was checked against the original (`verify`).
+    verified: usize,
+    // Symlinks that lead back to a directory already being copied.
+    loops: Vec<PathBuf>,
+}
+
+// Copies a directory tree, merging into whatever is already at `to`. Iterative, like
+// `find`, so deep trees can't overflow the stack. With `follow_links` symlinks are
+// copied as what they point to, except those that loop; otherwise they're recreated.
+fn copy_tree(from: &Path, to: &Path, follow_links: bool, options: CopyOptions) -> io::Result<CopyReport> {
+    let source_root = from.canonicalize()?;
+    let target_root = to
+        .canonicalize()
+        .ok()
+        .or_else(|| Some(to.parent()?.canonicalize().ok()?.join(to.file_name()?)));
+    if target_root.is_some_and(|target| target.starts_with(&source_root)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot copy a directory into itself"));
+    }
+
+    let mut report = CopyReport::default();
+    // `-v` already lists every file.
+    let mut progress = Progress::new("copy: copied", "files", options.quiet || options.verbose);
+    // Each pending directory carries the canonical paths of itself and its parents.
+    let mut pending = vec![(from.to_path_buf(), to.to_path_buf(), vec![source_root])];
+    while let Some((source, target, ancestors)) = pending.pop() {
+        fs::create_dir_all(&target)?;
+        for entry in fs::read_dir(&source)? {
+            let entry = entry?;
+            let entry_path = entry.path();
+            let entry_target = target.join(entry.file_name());
+            let mut file_type = entry.file_type()?;
+            if file_type.is_symlink() && follow_links {
+                // A dangling link has nothing to follow, so it's recreated instead.
+                if let Ok(metadata) = fs::metadata(&entry_path) {
+                    file_type = metadata.file_type();
+                }
+            }
+
+            let existing = fs::symlink_metadata(&entry_target).ok();
+            if file_type.is_dir() {
+                let canonical = entry_path.canonicalize()?;
+                if ancestors.contains(&canonical) {
+                    report.loops.push(entry_path);
+                    continue;
+                }
+                let mut chain = ancestors.clone();
+                chain.push(canonical);
+                pending.push((entry_path, entry_target, chain));
+            } else if options.no_clobber && existing.is_some() {
+                report.skipped += 1;
+            } else if file_type.is_symlink() {
+                if existing.is_some_and(|metadata| !metadata.is_dir()) {
+                    fs::remove_file(&entry_target)?;
+                }
+                copy_symlink(&entry_path, &entry_target)?;
+                if options.verbose {
+                    eprintln!("{} -> {}", entry_path.display(), entry_target.display());
+                }
+                report.files += 1;
+                progress.tick();
+            } else {
+                report.bytes += copy_contents(&entry_path, &entry_target, options)?;
+                report.files += 1;
+                report.verified += options.verify as usize;
+                progress.tick();
+            }
+        }
+    }
+    Ok(report)
+}
+
+// `fs::copy`, then with `verify` a check that the copy reads back the same.
+fn copy_contents(from: &Path, to: &Path, options: CopyOptions) -> io::Result<u64> {
+    let bytes = copy_contents_reporting(from, to, options.verbose)?;
+    if options.verify {
+        verify_copy(from, to)?;
+    }
+    Ok(bytes)
+}
+
+// An `InvalidData` error naming both checksums unless `to` hashes the same as `from`.
+fn verify_copy(from: &Path, to: &Path) -> io::Result<()> {
+    let (original, copy) = (checksum::file_digest::<VerifyDigest>(from)?, checksum::file_digest::<VerifyDigest>(to)?);
+    if original == copy {
+        return Ok(());
+    }
+    let message = format!(
+        "{}: {} mismatch after copy ({} expected, {} found)",
+        to.display(),
+        VerifyDigest::NAME,
+        checksum::to_hex(&original),
+        checksum::to_hex(&copy)
+    );
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+// `fs::copy`, which with `verbose` also names the file on stderr. Large files are then
+// copied in chunks instead, showing how far along they are.
+fn copy_contents_reporting(from: &Path, to: &Path, verbose: bool) -> io::Result<u64> {
+    const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+    if !verbose {
+        return fs::copy(from, to);
+    }
+    let size = fs::metadata(from)?.len();
+    if size < PROGRESS_THRESHOLD {
+        let bytes = fs::copy(from, to)?;
+        eprintln!("{} -> {}", from.display(), to.display());
+        return Ok(bytes);
+    }
+
+    let mut reader = fs::File::open(from)?;
+    let mut writer = fs::File::create(to)?;
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut copied = 0;
+    let mut shown = None;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+        let percent = copied * 100 / size.max(1);
+        if shown != Some(percent) {
+            eprint!("\r{} -> {} {:3}%", from.display(), to.display(), percent);
+            shown = Some(percent);
+        }
+    }
+    eprintln!();
+    writer.set_permissions(fs::metadata(from)?.permissions())?;
+    Ok(copied)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path, _target_is_dir: bool) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// Windows has separate kinds of symlink for files and directories.
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path, target_is_dir: bool) -> io::Result<()> {
+    if target_is_dir {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_symlink(_target: &Path, _link: &Path, _target_is_dir: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks are not supported on this platform"))
+}
+
+#[cfg(unix)]
+fn copy_symlink(link: &Path, target: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(link)?, target)
+}
+
+// Creating symlinks on Windows needs extra privileges, so copy what the link points to.
+#[cfg(not(unix))]
+fn copy_symlink(link: &Path, target: &Path) -> io::Result<()> {
+    fs::copy(link, target).map(|_| ())
+}
+
+fn find_matches(query: &FindQuery, entry: &fs::DirEntry) -> bool {
+    if let Some(pattern) = &query.name {
+        if !glob::matches(pattern, &entry.file_name().to_string_lossy()) {
+            return false;
+        }
+    }
+    if let Some(kind) = query.kind {
+        // Symlinks aren't followed, so a link to a directory is neither.
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+        let is_file = entry.file_type().is_ok_and(|t| t.is_file());
+        if (kind == EntryKind::Dir && !is_dir) || (kind == EntryKind::File && !is_file) {
+            return false;
+        }
+    }
+    if query.newer_than.is_none() && query.size.is_none() && query.contains.is_none() {
+        return true;
+    }
+
+    let Ok(metadata) = entry.metadata() else { return false };
+    if let Some(age) = query.newer_than {
+        let modified_age = metadata.modified().ok().and_then(|modified| SystemTime::now().duration_since(modified).ok());
+        // A timestamp in the future counts as newer than anything.
+        if modified_age.is_some_and(|modified_age| modified_age > age) {
+            return false;
+        }
+    }
+    if query.size.is_some() || query.contains.is_some() {
+        if !metadata.is_file() {
+            return false;
+        }
+        if query.size.as_ref().is_some_and(|size| !size.accepts(metadata.len())) {
+            return false;
+        }
+    }
+    match &query.contains {
+        Some(needle) => file_contains(&entry.path(), needle),
+        None => true,
+    }
+}
+
+fn file_contains(path: &Path, needle: &str) -> bool {
+    let Ok(file) = fs::File::open(path) else { return false };
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) if String::from_utf8_lossy(&line).contains(needle) => return true,
+            Ok(_) => {}
+        }
+    }
+}
+
+// What `replace` puts in place of each match: taken literally, or with `$1`-style
+// references expanded for `--regex`.
+#[derive(Clone, Copy)]
+struct Replacement<'a> {
+    text: &'a [u8],
+    expand: bool,
+}
+
+impl regex::bytes::Replacer for Replacement<'_> {
+    fn replace_append(&mut self, captures: &regex::bytes::Captures<'_>, dst: &mut Vec<u8>) {
+        if self.expand {
+            captures.expand(self.text, dst);
+        } else {
+            dst.extend_from_slice(self.text);
+        }
+    }
+}
+
+// Streams `reader` a line at a time, writing each with its replacements made to
+// `writer` if there is one, and returns how many replacements there were. Matches
+// never span lines; the line ending is left out of what the pattern sees.
+fn replace_lines(reader: &mut dyn BufRead, mut writer: Option<&mut dyn Write>, regex: &regex::bytes::Regex, replacement: Replacement) -> io::Result<usize> {
+    let mut line = Vec::new();
+    let mut count = 0;
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let body_length = line.len() - line.iter().rev().take_while(|&&byte| matches!(byte, b'\n' | b'\r')).count();
+        let (body, ending) = line.split_at(body_length);
+        count += regex.find_iter(body).count();
+        if let Some(writer) = writer.as_mut() {
+            writer.write_all(&regex.replace_all(body, replacement))?;
+            writer.write_all(ending)?;
+        }
+        line.clear();
+    }
+    Ok(count)
+}
+
+// Writes the replaced text to a temporary file beside `path`, then swaps it in, so a
+// failure part way leaves the original as it was.
+fn rewrite_with_replacements(path: &Path, regex: &regex::bytes::Regex, replacement: Replacement, backup: bool) -> io::Result<()> {
+    let with_suffix = |suffix: &str| {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let temporary = with_suffix(".rubin-tmp");
+    let written = (|| {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let mut writer = io::BufWriter::new(fs::File::create(&temporary)?);
+        replace_lines(&mut reader, Some(&mut writer), regex, replacement)?;
+        writer.flush()?;
+        fs::set_permissions(&temporary, fs::metadata(path)?.permissions())
+    })();
+    let swapped = written
+        .and_then(|()| if backup { fs::copy(path, with_suffix(".bak")).map(|_| ()) } else { Ok(()) })
+        .and_then(|()| fs::rename(&temporary, path));
+    if swapped.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    swapped
+}
+
+// The number a line starts with, after any leading blanks, for `sort -n`.
+fn leading_number(line: &str) -> Option<f64> {
+    let text = line.trim_start();
+    let mut end = 0;
+    for (index, c) in text.char_indices() {
+        let sign = index == 0 && matches!(c, '-' | '+');
+        if !(c.is_ascii_digit() || c == '.' || sign) {
+            break;
+        }
+        end = index + c.len_utf8();
+    }
+    // The longest prefix that parses, so `1.2.3` is 1.2.
+    (1..=end).rev().find_map(|length| text[..length].parse().ok())
+}
+
+// `<file>.part001` and so on, as written by `split`.
+fn part_path(path: &Path, number: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".part{:03}", number));
+    PathBuf::from(name)
+}
+
+// Streams `path` into `parts`, `chunk_size` bytes to each but the last.
+fn split_into_parts(path: &Path, parts: &[PathBuf], chunk_size: u64) -> io::Result<()> {
+    let mut input = fs::File::open(path)?;
+    for part in parts {
+        let mut output = fs::File::create(part)?;
+        io::copy(&mut (&mut input).take(chunk_size), &mut output)?;
+    }
+    Ok(())
+}
+
+// Parses sizes such as `512`, `10k`, `2M` or `1G` (powers of 1024) into bytes.
+fn parse_size(text: &str) -> Option<u64> {
+    let (digits, multiplier) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1024),
+        (i, 'm' | 'M') => (&text[..i], 1024 * 1024),
+        (i, 'g' | 'G') => (&text[..i], 1024 * 1024 * 1024),
+        _ => (text, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// Parses relative ages such as `45s`, `30m`, `3h`, `2d` or `1w`.
+fn parse_age(text: &str) -> Option<Duration> {
+    let (i, unit) = text.char_indices().last()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let amount: u64 = text[..i].parse().ok()?;
+    amount.checked_mul(seconds).map(Duration::from_secs)
+}
+
+// Replaces `$1`..`$9` with the matching argument (or nothing) and `$@` with all of them.
+fn substitute_positional_args(definition: &str, args: &[&str]) -> String {
+    let mut result = String::with_capacity(definition.len());
+    let mut chars = definition.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            result.push(c);
+            continue;
+        }
+        match chars.peek().copied() {
+            Some('@') => {
+                chars.next();
+                result.push_str(&args.join(" "));
+            }
+            Some(digit @ '1'..='9') => {
+                chars.next();
+                let index = digit as usize - '1' as usize;
+                result.push_str(args.get(index).copied().unwrap_or(""));
+            }
+            _ => result.push('$'),
+        }
+    }
+    result
+}
+
+// Candidates for the argument being typed after a custom command, based on its registered spec.
+fn custom_arg_candidates(commands: &[CustomCommand], current_dir: &Path, cmd_name: &str, partial: &str) -> Vec<String> {
+    let Some(spec) = commands.iter().find(|c| c.name == cmd_name).and_then(|c| c.completion.as_ref()) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<String> = match spec {
+        CompletionSpec::Words(words) => words.iter().filter(|w| w.starts_with(partial)).cloned().collect(),
+        CompletionSpec::Dir(dir) => fs::read_dir(current_dir.join(dir))
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .filter(|name| name.starts_with(partial))
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    candidates.sort();
+    candidates
+}
+
+// The `$?` value for a finished child. Killed by a signal is 128 + its number, as in
+// other shells.
+fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+// "Done", "Exit 3" or "Killed by signal 9", for job notices.
+fn describe_job_status(status: ExitStatus) -> String {
+    match status.code() {
+        Some(0) => "Done".to_string(),
+        Some(code) => format!("Exit {}", code),
+        None => format!("Killed by signal {}", exit_code(status) - 128),
+    }
+}
+
+// 127 when the program doesn't exist, 126 when it exists but couldn't be run.
+fn spawn_failure_code(error: &io::Error) -> i32 {
+    if error.kind() == io::ErrorKind::NotFound { 127 } else { 126 }
+}
+
+// Groups tokens into `|`-separated stages, pulling out each stage's redirection.
+fn split_pipeline(tokens: Vec<Token>) -> Result<Vec<Stage>, String> {
+    let mut stages = Vec::new();
+    let mut stage = Stage { words: Vec::new(), redirect: None };
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::Word(word) => stage.words.push(word),
+            Token::Redirect { append } => match tokens.next() {
+                Some(Token::Word(target)) => stage.redirect = Some((target, append)),
+                _ => return Err(format!("parse error: expected a file name after {}", if append { ">>" } else { ">" })),
+            },
+            Token::Pipe => {
+                if stage.words.is_empty() || tokens.peek().is_none() {
+                    return Err("parse error: empty command in pipeline".to_string());
+                }
+                stages.push(std::mem::replace(&mut stage, Stage { words: Vec::new(), redirect: None }));
+            }
+        }
+    }
+    stages.push(stage);
+    Ok(stages)
+}
+
+// How to launch a script: `cmd /C` or PowerShell by extension on Windows, the `#!` line
+// elsewhere. `None` means no interpreter was named and the caller should fall back to `sh`.
+fn script_interpreter(path: &Path) -> Option<(String, Vec<String>)> {
+    if cfg!(windows) {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        return match extension.as_str() {
+            "bat" | "cmd" => Some(("cmd".to_string(), vec!["/C".to_string()])),
+            "ps1" => Some((
+                "powershell".to_string(),
+                vec!["-NoProfile".to_string(), "-ExecutionPolicy".to_string(), "Bypass".to_string(), "-File".to_string()],
+            )),
+            _ => None,
+        };
+    }
+
+    let file = fs::File::open(path).ok()?;
+    let mut first_line = String::new();
+    BufReader::new(file).read_line(&mut first_line).ok()?;
+    let mut parts = first_line.strip_prefix("#!")?.split_whitespace();
+    let program = parts.next()?.to_string();
+    // Like the kernel, treat everything after the interpreter as a single argument.
+    let rest: Vec<&str> = parts.collect();
+    let args = if rest.is_empty() { Vec::new() } else { vec![rest.join(" ")] };
+    Some((program, args))
+}
+
+// Aliases are matched against the raw first word of a line, before any quote handling,
+// so they also can't contain `=` (which `alias` splits on) or path separators.
+fn validate_alias_name(name: &str) -> Result<(), String> {
+    validate_custom_command_name(name)?;
+    match name.chars().find(|c| matches!(c, '=' | '/' | '\\')) {
+        Some(c) => Err(format!("the name contains '{}'", c)),
+        None => Ok(()),
+    }
+}
+
+// In the form `alias` accepts, so the listing can be pasted back in.
+fn format_alias(name: &str, value: &str) -> String {
+    format!("alias {}='{}'", name, value.replace('\'', "'\\''"))
+}
+
+// The names `$NAME` can refer to, which also keeps `env::set_var` from panicking.
+fn validate_env_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    match chars.next() {
+        None => Err("the name is empty".to_string()),
+        Some(c) if !(c.is_ascii_alphabetic() || c == '_') => Err("the name must start with a letter or '_'".to_string()),
+        _ if !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+            Err("the name may only contain letters, digits and '_'".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+// A name has to come through the parser as a single plain word to be callable, so
+// anything it treats specially is out, as is a leading `!` (history expansion).
+fn validate_custom_command_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("the name is empty".to_string());
+    }
+    if name.chars().any(char::is_whitespace) {
+        return Err("the name contains whitespace".to_string());
+    }
+    if let Some(c) = name.chars().find(|c| parser::ESCAPABLE.contains(c)) {
+        return Err(format!("the name contains '{}'", c));
+    }
+    if name.starts_with('!') {
+        return Err("the name starts with '!'".to_string());
+    }
+    Ok(())
+}
+
+// `RUBIN_CONFIG` overrides the default `~/.rubin/state.json`.
+fn state_file_path() -> Option<PathBuf> {
+    match env::var_os("RUBIN_CONFIG") {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => home_dir().map(|home| home.join(".rubin").join("state.json")),
+    }
+}
+
+// Lists completions bash-style rather than cycling through them in place.
+fn create_editor() -> Option<Editor<ShellHelper, DefaultHistory>> {
+    let config = Config::builder().completion_type(CompletionType::List).build();
+    let mut editor = Editor::with_config(config).ok()?;
+    editor.set_helper(Some(ShellHelper {
+        builtins: COMMANDS.iter().map(|spec| spec.name).collect(),
+        ..ShellHelper::default()
+    }));
+    Some(editor)
+}
+
+// One line of the command log.
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    // Seconds since the Unix epoch.
+    time: u64,
+    cwd: &'a str,
+    command: &'a str,
+    status: i32,
+}
+
+// Writes `entry` as a single line of JSON.
+fn write_log_entry(writer: &mut dyn Write, entry: &LogEntry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+// Where `set logging on` writes when `RUBIN_LOG` doesn't say.
+fn command_log_path() -> Option<PathBuf> {
+    state_file_path().map(|path| path.with_file_name("commands.log"))
+}
+
+// Kept next to the state file.
+fn trash_dir_path() -> Option<PathBuf> {
+    state_file_path().map(|path| path.with_file_name("trash"))
+}
+
+fn read_trash_index(trash: &Path) -> io::Result<TrashIndex> {
+    match fs::read_to_string(trash.join("index.json")) {
+        Ok(json) => serde_json::from_str(&json).map_err(io::Error::from),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(TrashIndex::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_trash_index(trash: &Path, index: &TrashIndex) -> io::Result<()> {
+    fs::create_dir_all(trash)?;
+    fs::write(trash.join("index.json"), serde_json::to_string_pretty(index).map_err(io::Error::from)?)
+}
+
+// Moves a file or directory into the trash and records where it came from.
+fn move_to_trash(path: &Path) -> io::Result<()> {
+    let trash = trash_dir_path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory to keep the trash in"))?;
+    let original = normalize_path(path);
+    if original.starts_with(&trash) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "already in the trash (use emptytrash)"));
+    }
+    let mut index = read_trash_index(&trash)?;
+    fs::create_dir_all(&trash)?;
+
+    let deleted = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let name = original.file_name().map_or_else(|| "item".to_string(), |name| name.to_string_lossy().into_owned());
+    // A clash gets the deletion time added, then a counter if even that is taken.
+    let mut stored = name.clone();
+    let mut attempt = 0;
+    while stored == "index.json" || fs::symlink_metadata(trash.join(&stored)).is_ok() {
+        attempt += 1;
+        stored = if attempt == 1 { format!("{}.{}", name, deleted) } else { format!("{}.{}-{}", name, deleted, attempt) };
+    }
+
+    move_path(path, &trash.join(&stored))?;
+    index.entries.push(TrashEntry { original, stored, deleted });
+    write_trash_index(&trash, &index)
+}
+
+// One command per line, kept next to the state file.
+fn history_file_path() -> Option<PathBuf> {
+    state_file_path().map(|path| path.with_file_name("history"))
+}
+
+fn load_command_history() -> Vec<String> {
+    history_file_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .map(|contents| contents.lines().filter(|line| !line.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// A missing file is a fresh start; a corrupt one is reported and ignored rather than fatal.
+fn load_state() -> PersistedState {
+    read_state().unwrap_or_else(|e| {
+        eprintln!("Warning: {}; starting without it", e);
+        PersistedState::default()
+    })
+}
+
+// A missing state file is just an empty state; one that can't be read or parsed is an error.
+fn read_state() -> Result<PersistedState, String> {
+    let Some(path) = state_file_path() else {
+        return Ok(PersistedState::default());
+    };
+    match fs::read_to_string(&path) {
+        Ok(contents) => {
+            serde_json::from_str(&contents).map_err(|e| format!("corrupt state file {}: {}", path.display(), e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(PersistedState::default()),
+        Err(e) => Err(format!("failed to read state file {}: {}", path.display(), e)),
+    }
+}
+
+// Past this many characters the prompt only shows the last two directories.
+const PROMPT_PATH_WIDTH: usize = 40;
+
+// Fills in a `PROMPT` template: `\w` is the directory as in the default prompt, `\W` just
+// its last component, `\u` the user, `\h` the host, `\$?` the last exit status and `\n` a
+// newline. `field` supplies the values, keyed by the letter (`?` for the status).
+fn expand_prompt(template: &str, mut field: impl FnMut(char) -> String) -> String {
+    let mut prompt = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(letter @ ('w' | 'W' | 'u' | 'h')) => prompt.push_str(&field(letter)),
+            Some('$') if chars.next_if_eq(&'?').is_some() => prompt.push_str(&field('?')),
+            Some('n') => prompt.push('\n'),
+            Some('\\') => prompt.push('\\'),
+            Some(other) => {
+                prompt.push('\\');
+                prompt.push(other);
+            }
+            None => prompt.push('\\'),
+        }
+    }
+    prompt
+}
+
+// Prompt form of `path`: the home directory becomes `~`, and a path still wider than
+// `max_width` is cut down to `.../parent/dir`. Works on the string so `\` and `/`
+// separators are handled alike whatever platform we're on.
+fn abbreviate_path(path: &str, home: Option<&str>, max_width: usize) -> String {
+    let is_separator = |c: char| c == '/' || c == '\\';
+    let mut shown = path.to_string();
+    if let Some(home) = home.map(|home| home.trim_end_matches(is_separator)).filter(|home| !home.is_empty()) {
+        if let Some(rest) = path.strip_prefix(home) {
+            if rest.is_empty() || rest.starts_with(is_separator) {
+                shown = format!("~{}", rest);
+            }
+        }
+    }
+
+    if shown.chars().count() <= max_width {
+        return shown;
+    }
+    let separator = if shown.contains('\\') && !shown.contains('/') { '\\' } else { '/' };
+    let components: Vec<&str> = shown.split(is_separator).filter(|c| !c.is_empty()).collect();
+    if components.len() <= 2 {
+        return shown;
+    }
+    let tail = &components[components.len() - 2..];
+    format!("...{}{}", separator, tail.join(&separator.to_string()))
+}
+
+// Where the shell starts. If the directory it was launched from is gone (deleted out from
+// under the terminal, say), it starts in the home directory, or failing that the root.
+fn starting_dir() -> PathBuf {
+    let error = match env::current_dir() {
+        Ok(dir) => return dir,
+        Err(e) => e,
+    };
+    let fallback = home_dir().filter(|home| home.is_dir()).unwrap_or_else(|| PathBuf::from(std::path::MAIN_SEPARATOR_STR));
+    eprintln!("Warning: current directory is unavailable ({}); starting in {}", error, fallback.display());
+    let _ = env::set_current_dir(&fallback);
+    fallback
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+fn is_truthy(value: &str) -> bool {
+    matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "on" | "yes")
+}
+
+// Resolves `.` and `..` lexically, so paths that don't exist yet can still be compared.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+// Legacy Windows consoles don't interpret escape sequences, but Windows Terminal and
+// anything setting TERM (e.g. mintty) does.
+fn terminal_supports_ansi() -> bool {
+    if !io::stdout().is_terminal() {
+        return false;
+    }
+    match env::var("TERM") {
+        Ok(term) => term != "dumb",
+        Err(_) => !cfg!(windows) || env::var_os("WT_SESSION").is_some(),
+    }
+}
+
+fn color_enabled() -> bool {
+    env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal()
+}
+
+// A `chmod` mode: an octal one replaces the permissions, a symbolic one adjusts them.
+#[derive(Clone, Copy)]
+enum ModeChange {
+    Octal(u32),
+    // `who` masks the user/group/other bits to touch; `bits` is `rwx` for all three.
+    Symbolic { who: u32, operator: char, bits: u32 },
+}
+
+impl ModeChange {
+    fn parse(text: &str) -> Option<ModeChange> {
+        if !text.is_empty() && text.chars().all(|c| c.is_digit(8)) {
+            return u32::from_str_radix(text, 8).ok().filter(|&mode| mode <= 0o7777).map(ModeChange::Octal);
+        }
+        let split = text.find(['+', '-', '='])?;
+        let (who_part, rest) = text.split_at(split);
+        let mut who = 0;
+        for c in who_part.chars() {
+            who |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => return None,
+            };
+        }
+        let mut chars = rest.chars();
+        let operator = chars.next()?;
+        let mut bits = 0;
+        for c in chars {
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                _ => return None,
+            };
+        }
+        Some(ModeChange::Symbolic { who: if who == 0 { 0o777 } else { who }, operator, bits })
+    }
+
+    // The permission bits after applying this to `mode`.
+    fn apply(self, mode: u32) -> u32 {
+        match self {
+            ModeChange::Octal(mode) => mode,
+            ModeChange::Symbolic { who, operator: '+', bits } => mode | (bits & who),
+            ModeChange::Symbolic { who, operator: '-', bits } => mode & !(bits & who),
+            ModeChange::Symbolic { who, bits, .. } => (mode & !who) | (bits & who),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn apply_mode_change(path: &Path, change: ModeChange) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = change.apply(permissions.mode() & 0o7777);
+    permissions.set_mode(mode);
+    fs::set_permissions(path, permissions)
+}
+
+// Only the read-only attribute can change here, so only `w` changes make sense.
+#[cfg(not(unix))]
+fn apply_mode_change(path: &Path, change: ModeChange) -> io::Result<()> {
+    let readonly = match change {
+        ModeChange::Symbolic { operator: '+', bits: 0o222, .. } => false,
+        ModeChange::Symbolic { operator: '-', bits: 0o222, .. } => true,
+        ModeChange::Octal(mode) => mode & 0o200 == 0,
+        _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "only +w and -w are supported on this platform")),
+    };
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(readonly);
+    fs::set_permissions(path, permissions)
+}
+
+// `rwxr-xr-x (755)` on Unix; elsewhere only the read-only flag exists.
+#[cfg(unix)]
+fn describe_permissions(metadata: &fs::Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = metadata.permissions().mode();
+    let letters: String = (0..9)
+        .map(|bit| if mode & (0o400 >> bit) != 0 { ['r', 'w', 'x'][bit % 3] } else { '-' })
+        .collect();
+    format!("{} ({:o})", letters, mode & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn describe_permissions(metadata: &fs::Metadata) -> String {
+    if metadata.permissions().readonly() { "read-only".to_string() } else { "read-write".to_string() }
+}
+
+// The `ls` colors: bold blue directories, cyan symlinks, green executables. `metadata`
+// is the entry's own, not its link target's.
+fn entry_color(metadata: &fs::Metadata) -> Option<&'static str> {
+    if metadata.is_symlink() {
+        Some("36")
+    } else if metadata.is_dir() {
+        Some("1;34")
+    } else if is_executable_metadata(metadata) {
+        Some("32")
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn is_executable_metadata(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+}
+
+// Without an execute bit to go on, nothing is colored as executable.
+#[cfg(not(unix))]
+fn is_executable_metadata(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+fn paint(text: &str, color: &str) -> String {
+    format!("\x1b[{}m{}\x1b[0m", color, text)
+}
+
+// Bold red, as other greps use.
+const MATCH_COLOR: &str = "1;31";
+
+// Byte ranges of each non-empty match in `text`, for `grep` to highlight or print alone.
+fn match_ranges(regex: &Regex, text: &str) -> Vec<std::ops::Range<usize>> {
+    regex.find_iter(text).filter(|found| !found.is_empty()).map(|found| found.range()).collect()
+}
+
+// `text` with each of `ranges` (in order, not overlapping) painted.
+fn highlight_ranges(text: &str, ranges: &[std::ops::Range<usize>]) -> String {
+    let mut highlighted = String::new();
+    let mut end = 0;
+    for range in ranges {
+        highlighted.push_str(&text[end..range.start]);
+        highlighted.push_str(&paint(&text[range.clone()], MATCH_COLOR));
+        end = range.end;
+    }
+    highlighted.push_str(&text[end..]);
+    highlighted
+}
+
+// ` [modified]` after a name in `dir --git`, colored when `use_color` is set.
+fn format_annotation(annotation: Option<(&str, &str)>, use_color: bool) -> String {
+    match annotation {
+        Some((label, color)) if use_color => format!(" {}", paint(&format!("[{}]", label), color)),
+        Some((label, _)) => format!(" [{}]", label),
+        None => String::new(),
+    }
+}
+
+// Sizes in powers of 1024 with one decimal, the way `ls -h` shows them.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+// `YYYY-MM-DD HH:MM +HH:MM` in local time, the offset from UTC labelling the zone.
+fn format_timestamp(time: SystemTime) -> String {
+    let Ok(since_epoch) = time.duration_since(SystemTime::UNIX_EPOCH) else {
+        return "?".to_string();
+    };
+    format_timestamp_at_offset(since_epoch.as_secs() as i64, local_utc_offset(since_epoch.as_secs() as i64))
+}
+
+// `seconds` past the epoch as a civil time `offset` seconds east of UTC.
+fn format_timestamp_at_offset(seconds: i64, offset: i64) -> String {
+    let local = seconds + offset;
+    let (days, seconds_of_day) = (local.div_euclid(86_400), local.rem_euclid(86_400));
+
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset_minutes = offset.abs() / 60;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} {}{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        sign,
+        offset_minutes / 60,
+        offset_minutes % 60
+    )
+}
+
+// How far east of UTC local time was at `seconds` past the epoch, by the C library's
+// reading of `TZ` or /etc/localtime, so daylight saving is accounted for.
+#[cfg(unix)]
+fn local_utc_offset(seconds: i64) -> i64 {
+    // time_t and tm_gmtoff are 32 bits on some targets, 64 on others.
+    let time = seconds as libc::time_t;
+    // SAFETY: `tm` is plain data for which all zeroes is valid, and localtime_r only
+    // writes through the two pointers for the length of the call.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+// Elsewhere times are shown in UTC, which the `+00:00` after them says.
+#[cfg(not(unix))]
+fn local_utc_offset(_seconds: i64) -> i64 {
+    0
+}
+
+// Maps a directory entry to its git status label and ANSI color, if it has any changes.
+// Directories are annotated when any path beneath them has changes.
+fn git_annotation(statuses: &[(String, String)], name: &str) -> Option<(&'static str, &'static str)> {
+    let dir_prefix = format!("{}/", name);
+    let codes: Vec<&str> = statuses
+        .iter()
+        .filter(|(_, path)| path.trim_end_matches('/') == name || path.starts_with(&dir_prefix))
+        .map(|(code, _)| code.as_str())
+        .collect();
+
+    if codes.is_empty() {
+        return None;
+    }
+    if codes.iter().all(|code| *code == "??") {
+        return Some(("untracked", "31"));
+    }
+    let staged = codes.iter().any(|code| !code.starts_with([' ', '?']));
+    let modified = codes.iter().any(|code| !code.ends_with(' '));
+    match (staged, modified) {
+        (true, true) => Some(("staged, modified", "33")),
+        (true, false) => Some(("staged", "32")),
+        _ => Some(("modified", "33")),
+    }
+}
+
+fn current_username() -> String {
+    if let Ok(name) = env::var("USER").or_else(|_| env::var("USERNAME")).or_else(|_| env::var("LOGNAME")) {
+        return name;
+    }
+    match Command::new("whoami").output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn current_hostname() -> String {
+    if let Ok(name) = env::var("COMPUTERNAME").or_else(|_| env::var("HOSTNAME")) {
+        return name;
+    }
+    for path in ["/proc/sys/kernel/hostname", "/etc/hostname"] {
+        if let Ok(contents) = fs::read_to_string(path) {
+            let name = contents.trim();
+            if !name.is_empty() {
+                return name.to_string();
+            }
+        }
+    }
+    match Command::new("hostname").output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+// Only Linux exposes this cheaply through /proc; other platforms report "unknown".
+fn available_memory_kb() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+}
+
+// Set once SIGTERM arrives; the main loop finishes the current command, then cleans up and exits.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+// Set by Ctrl-C while a command runs, and cleared before the next prompt. A foreground child
+// gets the SIGINT from the terminal itself; the shell only abandons what's left of the line.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+// True while the main loop is blocked waiting for input with all state already flushed.
+static AT_PROMPT: AtomicBool = AtomicBool::new(false);
+
+const SIGTERM_EXIT_CODE: i32 = 143;
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+// The handler itself only flips a flag (async-signal-safe); the real work happens on a
+// watcher thread, or on the main loop if a command is running when the signal lands.
+#[cfg(unix)]
+fn install_signal_handlers() {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGTERM, SIGINT]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Warning: failed to install signal handlers: {}", e);
+            return;
+        }
+    };
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGINT {
+                INTERRUPTED.store(true, Ordering::SeqCst);
+                continue;
+            }
+            SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+            if AT_PROMPT.load(Ordering::SeqCst) {
+                exit(SIGTERM_EXIT_CODE);
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers() {}
+
+const CLI_USAGE: &str = "Usage: rubin [--safe] [--norc] [--version] [-c <command> | <script>]";
+
+// For `version` and `--version`, and so for bug reports.
+fn version_info() -> String {
+    let commit = match env!("RUBIN_COMMIT") {
+        "" => String::new(),
+        commit => format!(", commit {}", commit),
+    };
+    format!("rubin {} ({}, {} build{})", env!("CARGO_PKG_VERSION"), env!("RUBIN_TARGET"), env!("RUBIN_PROFILE"), commit)
+}
+
+// With no command or script, the shell is interactive.
+fn main() {
+    let mut shell = Shell::new();
+    let mut command = None;
+    let mut script = None;
+    let mut load_rc = true;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--safe" => shell.safe_mode = true,
+            "--norc" => load_rc = false,
+            "-c" => match args.next() {
+                Some(line) => command = Some(line),
+                None => {
+                    eprintln!("rubin: -c expects a command\n{}", CLI_USAGE);
+                    exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", CLI_USAGE);
+                return;
+            }
+            "--version" => {
+                println!("{}", version_info());
+                return;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("rubin: unknown option '{}'\n{}", arg, CLI_USAGE);
+                exit(2);
+            }
+            _ if script.is_none() => script = Some(PathBuf::from(arg)),
+            _ => {
+                eprintln!("rubin: unexpected argument '{}'\n{}", arg, CLI_USAGE);
+                exit(2);
+            }
+        }
+    }
+
+    if let Some(line) = command {
+        install_signal_handlers();
+        let status = shell.run_line(&line);
+        shell.shutdown(status);
+    }
+    if let Some(path) = script {
+        install_signal_handlers();
+        let status = shell.run_command_file(&path);
+        shell.shutdown(status);
+    }
+    // Only interactive shells read the rc file, as with other shells' rc files.
+    if load_rc {
+        shell.load_rc_file();
+    }
+    shell.run();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Once;
+
+    // A fresh directory under the system temp dir, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!("rubin-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+            let path = env::temp_dir().join(name);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path.canonicalize().unwrap())
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // A shell in `dir` that reads `input` in place of stdin and writes into a buffer. The
+    // state file, history and trash all go to a directory of the test run's own, never
+    // the real home directory.
+    fn test_shell(dir: &Path, input: &str) -> Shell {
+        static CONFIG: Once = Once::new();
+        CONFIG.call_once(|| {
+            let config = env::temp_dir().join(format!("rubin-test-{}-config", std::process::id()));
+            env::set_var("RUBIN_CONFIG", config.join("state.json"));
+        });
+        let reader: Box<dyn BufRead> = Box::new(io::Cursor::new(input.as_bytes().to_vec()));
+        let mut shell = Shell::with_io(Output::Buffer(Vec::new()), Input::Reader(Rc::new(RefCell::new(reader))));
+        shell.current_dir = dir.to_path_buf();
+        shell.history = vec![dir.to_path_buf()];
+        shell
+    }
+
+    // A shell in `dir` with nothing to read.
+    fn shell_in(dir: &Path) -> Shell {
+        test_shell(dir, "")
+    }
+
+    // Everything written since the last call.
+    fn take_output(shell: &mut Shell) -> String {
+        match &mut shell.out {
+            Output::Buffer(buffer) => String::from_utf8(std::mem::take(buffer)).unwrap(),
+            _ => panic!("the shell isn't writing into a buffer"),
+        }
+    }
+
+    // Runs `command` and returns what it wrote to the shell's output.
+    fn capture(shell: &mut Shell, command: &str) -> String {
+        let previous = std::mem::replace(&mut shell.out, Output::Buffer(Vec::new()));
+        shell.execute_command(command);
+        match std::mem::replace(&mut shell.out, previous) {
+            Output::Buffer(bytes) => String::from_utf8(bytes).unwrap(),
+            _ => unreachable!("the command replaced the output without restoring it"),
+        }
+    }
+
+    #[test]
+    fn whoami_matches_the_user_variable() {
+        let name = current_username();
+        assert!(!name.is_empty());
+        if let Ok(expected) = env::var("USER").or_else(|_| env::var("USERNAME")) {
+            assert_eq!(name, expected);
+        }
+    }
+
+    #[test]
+    fn hostname_is_not_empty() {
+        assert!(!current_hostname().is_empty());
+    }
+
+    #[test]
+    fn replace_in_place_keeps_a_backup() {
+        let temp = TempDir::new();
+        let file = temp.path().join("users.txt");
+        fs::write(&file, "ada@old cat\ngrace@old cat\n").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        shell.execute_command("replace -i cat dog users.txt");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "ada@old dog\ngrace@old dog\n");
+        assert_eq!(fs::read_to_string(temp.path().join("users.txt.bak")).unwrap(), "ada@old cat\ngrace@old cat\n");
+
+        shell.execute_command(r"replace -i (\w+)@old $1@new users.txt");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "ada@old dog\ngrace@old dog\n");
+
+        shell.execute_command(r"replace --regex -i (\w+)@old $1@new users.txt");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "ada@new dog\ngrace@new dog\n");
+        assert_eq!(fs::read_to_string(temp.path().join("users.txt.bak")).unwrap(), "ada@old dog\ngrace@old dog\n");
+    }
+
+    #[test]
+    fn git_statuses_mark_untracked_files() {
+        let temp = TempDir::new();
+        let git = |args: &[&str]| Command::new("git").args(args).current_dir(temp.path()).output().is_ok_and(|output| output.status.success());
+        if !git(&["init", "--quiet"]) {
+            eprintln!("git isn't available; skipping");
+            return;
+        }
+        fs::write(temp.path().join("tracked.txt"), "").unwrap();
+        assert!(git(&["add", "tracked.txt"]));
+        fs::write(temp.path().join("new.txt"), "").unwrap();
+
+        let statuses = shell_in(temp.path()).git_statuses().unwrap();
+        assert_eq!(git_annotation(&statuses, "new.txt"), Some(("untracked", "31")));
+        assert_eq!(git_annotation(&statuses, "tracked.txt"), Some(("staged", "32")));
+        // Outside a repository there's nothing to annotate.
+        let elsewhere = TempDir::new();
+        assert!(shell_in(elsewhere.path()).git_statuses().is_none());
+    }
+
+    #[test]
+    fn git_annotation_covers_directories() {
+        let statuses = vec![("??".to_string(), "new.txt".to_string()), (" M".to_string(), "src/main.rs".to_string())];
+        assert_eq!(git_annotation(&statuses, "new.txt"), Some(("untracked", "31")));
+        assert_eq!(git_annotation(&statuses, "src"), Some(("modified", "33")));
+        assert_eq!(git_annotation(&statuses, "README.md"), None);
+    }
+
+    #[test]
+    fn undo_reverses_a_rename() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("before.txt"), "contents").unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("rename before.txt after.txt");
+        assert!(temp.path().join("after.txt").exists());
+        shell.execute_command("undo");
+        assert_eq!(fs::read_to_string(temp.path().join("before.txt")).unwrap(), "contents");
+        assert!(!temp.path().join("after.txt").exists());
+        assert!(shell.undo_stack.is_empty());
+    }
+
+    #[test]
+    fn undo_removes_created_directories() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("mkdir -p a/b/c");
+        assert!(temp.path().join("a/b/c").is_dir());
+        shell.execute_command("undo");
+        assert!(!temp.path().join("a").exists());
+    }
+
+    #[test]
+    fn completion_spec_parsing() {
+        assert!(matches!(CompletionSpec::parse("words:staging,production"), Some(CompletionSpec::Words(words)) if words == ["staging", "production"]));
+        assert!(matches!(CompletionSpec::parse("dir:deploy"), Some(CompletionSpec::Dir(dir)) if dir == "deploy"));
+        assert!(CompletionSpec::parse("words:").is_none());
+        assert!(CompletionSpec::parse("dir:").is_none());
+        assert!(CompletionSpec::parse("files").is_none());
+    }
+
+    #[test]
+    fn completer_consults_a_registered_spec() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("targets")).unwrap();
+        fs::write(temp.path().join("targets/prod.toml"), "").unwrap();
+        fs::write(temp.path().join("targets/preview.toml"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("cc create deploy echo Deploys");
+        shell.execute_command("cc complete deploy words:staging,production,preview");
+        assert_eq!(custom_arg_candidates(&shell.custom_commands, &shell.current_dir, "deploy", "pr"), ["preview", "production"]);
+        assert!(custom_arg_candidates(&shell.custom_commands, &shell.current_dir, "deploy", "x").is_empty());
+
+        shell.execute_command("cc complete deploy dir:targets");
+        assert_eq!(custom_arg_candidates(&shell.custom_commands, &shell.current_dir, "deploy", "pr"), ["preview.toml", "prod.toml"]);
+        assert!(custom_arg_candidates(&shell.custom_commands, &shell.current_dir, "unknown", "").is_empty());
+    }
+
+    #[test]
+    fn safe_mode_blocks_overwrites_until_overridden() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("new.txt"), "new").unwrap();
+        fs::write(temp.path().join("old.txt"), "old").unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.safe_mode = true;
+
+        shell.execute_command("copy new.txt old.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "old");
+        shell.execute_command("copy new.txt old.txt --override");
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "new");
+        // The override only lasts for the command it was given to.
+        fs::write(temp.path().join("new.txt"), "newer").unwrap();
+        shell.execute_command("copy new.txt old.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "new");
+
+        shell.execute_command("setenv RUBIN_SAFE_MODE off");
+        shell.execute_command("copy new.txt old.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "newer");
+    }
+
+    #[test]
+    fn safe_mode_blocks_paths_outside_the_current_directory() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("inner")).unwrap();
+        fs::write(temp.path().join("outside.txt"), "").unwrap();
+        let mut shell = shell_in(&temp.path().join("inner"));
+        shell.safe_mode = true;
+
+        shell.execute_command("move ../outside.txt here.txt");
+        assert!(temp.path().join("outside.txt").exists());
+        shell.execute_command("move ../outside.txt here.txt --override");
+        assert!(temp.path().join("inner/here.txt").exists());
+    }
+
+    #[test]
+    fn cc_export_then_import_round_trips() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("cc create greet echo Greets");
+        shell.execute_command("cc create bye echo Leaves");
+        shell.execute_command("cc export shared.json");
+
+        let mut other = shell_in(temp.path());
+        other.execute_command("cc import shared.json");
+        let names: Vec<&str> = other.custom_commands.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["greet", "bye"]);
+        assert_eq!(other.custom_commands[1].description, "Leaves");
+    }
+
+    #[test]
+    fn cc_import_skips_collisions_and_bad_names() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("cc create greet imported Imported");
+        shell.execute_command("cc export shared.json");
+        let exported = fs::read_to_string(temp.path().join("shared.json")).unwrap();
+        fs::write(temp.path().join("bad.json"), exported.replace("\"greet\"", "\"has space\"")).unwrap();
+
+        shell.custom_commands.clear();
+        shell.execute_command("cc create greet local Local");
+        shell.execute_command("cc import shared.json");
+        assert_eq!(shell.custom_commands[0].definition, "local");
+        shell.execute_command("cc import shared.json --overwrite");
+        assert_eq!(shell.custom_commands.len(), 1);
+        assert_eq!(shell.custom_commands[0].definition, "imported");
+
+        shell.execute_command("cc import bad.json");
+        assert_eq!(shell.custom_commands.len(), 1);
+    }
+
+    #[test]
+    fn size_and_age_suffixes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10k"), Some(10 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("k"), None);
+        assert_eq!(parse_age("3h"), Some(Duration::from_secs(3 * 60 * 60)));
+        assert_eq!(parse_age("2d"), Some(Duration::from_secs(2 * 24 * 60 * 60)));
+        assert_eq!(parse_age("2"), None);
+        assert_eq!(parse_age("2y"), None);
+    }
+
+    #[test]
+    fn set_toggles_options_all_or_nothing() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("set -ex");
+        assert!(shell.options.errexit && shell.options.xtrace && !shell.options.nounset);
+        assert_eq!(shell.sh_option_flags(), ["-e", "-x"]);
+        shell.execute_command("set +x -u");
+        assert_eq!(shell.sh_option_flags(), ["-e", "-u"]);
+        shell.execute_command("set +e -q");
+        assert!(shell.options.errexit);
+    }
+
+    #[test]
+    fn cd_records_history_and_drops_the_forward_part() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("a/b")).unwrap();
+        fs::create_dir(temp.path().join("c")).unwrap();
+        fs::write(temp.path().join("file.txt"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        shell.execute_command("cd a/b");
+        assert_eq!(shell.current_dir, temp.path().join("a/b"));
+        shell.execute_command("cd ..");
+        assert_eq!(shell.current_dir, temp.path().join("a"));
+        shell.execute_command("<-");
+        shell.execute_command("<-");
+        assert_eq!(shell.current_dir, temp.path());
+        shell.execute_command("cd c");
+        assert_eq!(shell.history, [temp.path().to_path_buf(), temp.path().join("c")]);
+        shell.execute_command("->");
+        assert_eq!(shell.current_dir, temp.path().join("c"));
+
+        shell.execute_command("cd ../file.txt");
+        shell.execute_command("cd ../missing");
+        assert_eq!(shell.current_dir, temp.path().join("c"));
+        shell.execute_command("cd /");
+        assert_eq!(shell.current_dir.parent(), None);
+    }
+
+    #[test]
+    fn positional_args_are_substituted() {
+        assert_eq!(substitute_positional_args("echo hello $1", &["world"]), "echo hello world");
+        assert_eq!(substitute_positional_args("cp $2 $1 $3", &["a", "b"]), "cp b a ");
+        assert_eq!(substitute_positional_args("echo $@!", &["a", "b"]), "echo a b!");
+        assert_eq!(substitute_positional_args("cost $0 $$", &["a"]), "cost $0 $$");
+    }
+
+    #[test]
+    fn variables_expand_from_the_shell_then_the_environment() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.env_vars.insert("DIR".to_string(), "projects".to_string());
+        let path = env::var("PATH").unwrap();
+
+        let word = |text: &str| Token::Word(text.to_string());
+
+        assert_eq!(shell.parse_line("cd $DIR/${DIR}x $PATH").unwrap(), [word("cd"), word("projects/projectsx"), word(&path)]);
+        assert_eq!(shell.parse_line("echo $RUBIN_TEST_UNSET").unwrap(), [word("echo"), word("$RUBIN_TEST_UNSET")]);
+        shell.options.nounset = true;
+        assert_eq!(shell.parse_line("echo ${RUBIN_TEST_UNSET}"), Err("RUBIN_TEST_UNSET: unbound variable".to_string()));
+    }
+
+    #[test]
+    fn cd_follows_an_expanded_variable() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("projects")).unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("setenv DIR projects");
+        shell.execute_command("cd $DIR");
+        assert_eq!(shell.current_dir, temp.path().join("projects"));
+    }
+
+    #[test]
+    fn redirection_truncates_or_appends() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        fs::write(temp.path().join("who.txt"), "stale\n").unwrap();
+        shell.execute_command("whoami > who.txt");
+        shell.execute_command("hostname >> who.txt");
+        let expected = format!("{}\n{}\n", current_username(), current_hostname());
+        assert_eq!(fs::read_to_string(temp.path().join("who.txt")).unwrap(), expected);
+
+        shell.execute_command("whoami > missing/who.txt");
+        shell.execute_command("whoami >");
+        assert!(!temp.path().join("missing").exists());
+        assert!(matches!(shell.out, Output::Buffer(_)));
+    }
+
+    #[test]
+    fn pipeline_splitting_rejects_empty_stages() {
+        let stages = split_pipeline(tokenize_words("type a.txt | nl > out.txt")).unwrap();
+        let words: Vec<&[String]> = stages.iter().map(|stage| stage.words.as_slice()).collect();
+        assert_eq!(words, [&["type", "a.txt"][..], &["nl"][..]]);
+        assert_eq!(stages[1].redirect, Some(("out.txt".to_string(), false)));
+
+        assert!(split_pipeline(tokenize_words("| nl")).is_err());
+        assert!(split_pipeline(tokenize_words("type a.txt |")).is_err());
+    }
+
+    fn tokenize_words(line: &str) -> Vec<Token> {
+        parser::tokenize(line, |_| Ok(None)).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pipelines_chain_builtins_and_external_commands() {
+        let temp = TempDir::new();
+        // `type` ends its output with a newline of its own.
+        fs::write(temp.path().join("names.txt"), "pear\napple").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        shell.execute_command("type names.txt | nl > numbered.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("numbered.txt")).unwrap(), "     1\tpear\n     2\tapple\n");
+        shell.execute_command("type names.txt | sort | nl > sorted.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("sorted.txt")).unwrap(), "     1\tapple\n     2\tpear\n");
+        shell.execute_command("type names.txt | rubin-no-such-program | nl > empty.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("empty.txt")).unwrap(), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_surfaces_a_script_exit_status() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("ok.sh"), "echo \"ran with $1\"\n").unwrap();
+        fs::write(temp.path().join("fails.sh"), "#!/bin/sh\nexit 3\n").unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("run ok.sh first > out.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("out.txt")).unwrap(), "ran with first\n");
+        assert_eq!(shell.run_script("fails.sh", &[]), Some(3));
+        assert_eq!(shell.run_script("missing.sh", &[]), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shebang_lines_name_the_interpreter() {
+        let temp = TempDir::new();
+        let script = temp.path().join("script");
+        fs::write(&script, "#!/usr/bin/env python3 -u\nprint(1)\n").unwrap();
+        assert_eq!(script_interpreter(&script), Some(("/usr/bin/env".to_string(), vec!["python3 -u".to_string()])));
+        fs::write(&script, "#!/bin/bash\n").unwrap();
+        assert_eq!(script_interpreter(&script), Some(("/bin/bash".to_string(), Vec::new())));
+        fs::write(&script, "echo no shebang\n").unwrap();
+        assert_eq!(script_interpreter(&script), None);
+    }
+
+    #[test]
+    fn abbreviate_path_replaces_home() {
+        assert_eq!(abbreviate_path("/home/ada", Some("/home/ada"), 40), "~");
+        assert_eq!(abbreviate_path("/home/ada/src", Some("/home/ada/"), 40), "~/src");
+        assert_eq!(abbreviate_path("/home/adam", Some("/home/ada"), 40), "/home/adam");
+        assert_eq!(abbreviate_path(r"C:\Users\ada\src", Some(r"C:\Users\ada"), 40), r"~\src");
+        assert_eq!(abbreviate_path("/tmp", None, 40), "/tmp");
+    }
+
+    #[test]
+    fn abbreviate_path_shortens_long_paths() {
+        assert_eq!(abbreviate_path("/srv/projects/rubin/src/bin", None, 12), ".../src/bin");
+        assert_eq!(abbreviate_path("/home/ada/projects/rubin/src", Some("/home/ada"), 12), ".../rubin/src");
+        assert_eq!(abbreviate_path(r"D:\work\projects\rubin\src", None, 12), r"...\rubin\src");
+        // Two components are as short as it gets.
+        assert_eq!(abbreviate_path("/a-long-directory/another-one", None, 5), "/a-long-directory/another-one");
+    }
+
+    #[test]
+    fn pwd_prints_the_current_directory() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("pwd > here.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("here.txt")).unwrap(), format!("{}\n", temp.path().display()));
+    }
+
+    #[test]
+    fn command_history_skips_blanks_and_repeats() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.command_history.clear();
+        for line in ["dir", "dir", "", "pwd", "dir"] {
+            shell.add_to_command_history(line);
+        }
+        assert_eq!(shell.command_history, ["dir", "pwd", "dir"]);
+        shell.execute_command("history > history.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("history.txt")).unwrap(), "1  dir\n2  pwd\n3  dir\n4  history > history.txt\n");
+    }
+
+    #[test]
+    fn history_references_expand_to_earlier_lines() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.command_history.clear();
+        assert_eq!(shell.expand_history("!!"), Err("!!: history is empty".to_string()));
+        assert_eq!(shell.expand_history("!1"), Err("!1: history is empty".to_string()));
+
+        shell.command_history = vec!["mkdir one".to_string(), "dir --git".to_string()];
+        assert_eq!(shell.expand_history("!!").unwrap(), "dir --git");
+        assert_eq!(shell.expand_history("!1 two").unwrap(), "mkdir one two");
+        assert_eq!(shell.expand_history("!3"), Err("!3: no such entry (history has 2 entries)".to_string()));
+        assert_eq!(shell.expand_history("!0"), Err("!0: no such entry (history has 2 entries)".to_string()));
+        assert!(shell.expand_history("!abc").is_err());
+    }
+
+    #[test]
+    fn history_navigation_with_empty_history_is_a_no_op() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.history.clear();
+        shell.history_index = 0;
+        shell.go_backward();
+        shell.go_forward();
+        assert_eq!(shell.current_dir, temp.path());
+        assert_eq!(shell.history_index, 0);
+    }
+
+    #[test]
+    fn rmdir_needs_r_for_non_empty_directories() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("tree/leaf")).unwrap();
+        fs::write(temp.path().join("tree/leaf/file.txt"), "x").unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.execute_command("rmdir tree");
+        assert!(temp.path().join("tree/leaf/file.txt").exists());
+        shell.execute_command("rmdir -rf tree");
+        assert!(!temp.path().join("tree").exists());
+    }
+
+    #[test]
+    fn copy_expands_wildcards() {
+        let temp = TempDir::new();
+        for name in ["main.rs", "lib.rs", "file1.txt", "file22.txt", "notes.md"] {
+            fs::write(temp.path().join(name), name).unwrap();
+        }
+        fs::create_dir(temp.path().join("out")).unwrap();
+        let mut shell = shell_in(temp.path());
+
+        shell.execute_command("copy *.rs file?.txt out");
+        let mut copied: Vec<String> = fs::read_dir(temp.path().join("out"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        copied.sort();
+        assert_eq!(copied, ["file1.txt", "lib.rs", "main.rs"]);
+
+        assert_eq!(shell.expand_globs(&["*.zip"]).unwrap_err(), "*.zip: pattern matched nothing");
+        shell.execute_command("copy *.rs notes.md");
+        assert_eq!(fs::read_to_string(temp.path().join("notes.md")).unwrap(), "notes.md");
+    }
+
+    #[test]
+    fn find_filters_by_type_and_depth() {
+        let temp = TempDir::new();
+        let results = TempDir::new();
+        fs::create_dir_all(temp.path().join("config/nested/config.d")).unwrap();
+        fs::write(temp.path().join("app.config"), "").unwrap();
+        fs::write(temp.path().join("config/nested/config.d/base.toml"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+        let mut find = |args: &str| {
+            let out = results.path().join("found.txt");
+            shell.execute_command(&format!("find {} > {}", args, out.display()));
+            fs::read_to_string(out).unwrap()
+        };
+
+        assert_eq!(find("config --type d"), "config\nconfig/nested/config.d\n");
+        assert_eq!(find("config --type f"), "app.config\n");
+        assert_eq!(find("*.toml --max-depth 3"), "");
+        assert_eq!(find("*.toml --max-depth 4"), "config/nested/config.d/base.toml\n");
+    }
+
+    #[test]
+    fn grep_numbers_and_labels_matches() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("src/deep")).unwrap();
+        fs::write(temp.path().join("notes.txt"), "TODO: one\ndone\ntodo: two\n").unwrap();
+        fs::write(temp.path().join("src/deep/lib.rs"), "// TODO later\n").unwrap();
+        fs::write(temp.path().join("src/blob.bin"), b"TODO\0binary").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(capture(&mut shell, "grep TODO notes.txt"), "TODO: one\n");
+        assert_eq!(capture(&mut shell, "grep -in todo notes.txt"), "1:TODO: one\n3:todo: two\n");
+        assert_eq!(capture(&mut shell, "grep TODO src"), "");
+        assert_eq!(capture(&mut shell, "grep -r TODO src"), "src/deep/lib.rs:// TODO later\n");
+        assert_eq!(capture(&mut shell, "type notes.txt | grep -n two"), "3:todo: two\n");
+    }
+
+    #[test]
+    fn dispatch_checks_arity_before_running_a_handler() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(capture(&mut shell, "mkdir"), "");
+        shell.execute_command("mkdir one two");
+        assert!(!temp.path().join("one").exists());
+        shell.execute_command("mkdir one");
+        assert!(temp.path().join("one").is_dir());
+
+        let spec = find_command("pwd").unwrap();
+        assert_eq!((spec.min_args, spec.max_args), (0, Some(0)));
+        assert_eq!(capture(&mut shell, "pwd extra"), "");
+        let help = capture(&mut shell, "pwd --help");
+        assert!(help.starts_with(&format!("Usage: {}\n{}\n", spec.usage, spec.description)), "{}", help);
+    }
+
+    #[test]
+    fn help_lists_builtins_and_custom_commands_in_columns() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.custom_commands.clear();
+        shell.execute_command("cc create deploy ./deploy.sh Ships-it");
+
+        let listing = capture(&mut shell, "help");
+        let width = COMMANDS.iter().map(|spec| spec.name.len()).max().unwrap();
+        assert!(listing.contains(&format!("\n  {:<width$}  {}\n", "grep", find_command("grep").unwrap().summary)), "{}", listing);
+        assert!(listing.contains(&format!("Custom commands:\n  {:<width$}  Ships-it\n", "deploy")), "{}", listing);
+
+        let grep = capture(&mut shell, "help grep");
+        assert!(grep.starts_with("Usage: grep "), "{}", grep);
+        assert!(grep.contains("\nExamples:\n  "), "{}", grep);
+        assert_eq!(capture(&mut shell, "help deploy"), "deploy (custom command)\nShips-it\nRuns: ./deploy.sh\n");
+        assert_eq!(capture(&mut shell, "help nope"), "");
+    }
+
+    #[test]
+    fn and_runs_the_next_command_on_success() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("mkdir x && cd x"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("x"));
+        assert_ne!(shell.run_line("cd nowhere && mkdir y"), 0);
+        assert!(!temp.path().join("x/y").exists());
+    }
+
+    #[test]
+    fn or_runs_the_next_command_on_failure() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("cd nowhere || mkdir fallback"), 0);
+        assert!(temp.path().join("fallback").is_dir());
+        assert_eq!(shell.run_line("pwd > here.txt || mkdir skipped"), 0);
+        assert!(!temp.path().join("skipped").exists());
+    }
+
+    #[test]
+    fn mixed_chains() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        let user = current_username();
+        // `;` always runs; each connector looks at the command just before it.
+        assert_eq!(capture(&mut shell, "cd nowhere; whoami && whoami || pwd"), format!("{0}\n{0}\n", user));
+        assert_eq!(capture(&mut shell, "cd nowhere && whoami || pwd; whoami"), format!("{}\n{}\n", temp.path().display(), user));
+        assert_eq!(shell.run_line("whoami > who.txt; cd nowhere"), 1);
+        assert_eq!(shell.run_line("whoami &&"), 2);
+    }
+
+    #[test]
+    fn status_of_the_last_command_is_in_question_mark() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("mkdir after-$?"), 0);
+        assert!(temp.path().join("after-0").is_dir());
+
+        assert_eq!(shell.run_line("cd nowhere"), 1);
+        shell.run_line("mkdir after-$?");
+        assert!(temp.path().join("after-1").is_dir());
+        // Wrong argument counts are usage errors.
+        assert_eq!(shell.run_line("pwd extra"), 2);
+        shell.run_line("mkdir after-$?");
+        assert!(temp.path().join("after-2").is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn question_mark_holds_a_script_exit_code() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("fails.sh"), "exit 3\n").unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("run fails.sh"), 3);
+        shell.run_line("mkdir after-$?");
+        assert!(temp.path().join("after-3").is_dir());
+    }
+
+    #[test]
+    fn aliases_expand_chain_and_stop_at_loops() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.aliases.clear();
+        shell.execute_command("alias mk=\"mkdir\" mkx=\"mk x && cd x\" me=whoami");
+        assert_eq!(shell.run_line("mkx"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("x"));
+        assert_eq!(capture(&mut shell, "me"), format!("{}\n", current_username()));
+
+        // Self-reference stops at the alias itself; mutual recursion stops where it repeats.
+        shell.execute_command("alias pwd=\"pwd\" ping=pong pong=ping");
+        assert_eq!(shell.expand_aliases("pwd"), Some(Ok("pwd".to_string())));
+        assert_eq!(shell.expand_aliases("ping now"), Some(Ok("ping now".to_string())));
+        assert_eq!(shell.expand_aliases("cd x"), None);
+        for depth in 0..=ALIAS_DEPTH_LIMIT {
+            shell.aliases.insert(format!("a{}", depth), format!("a{}", depth + 1));
+        }
+        assert_eq!(shell.expand_aliases("a0"), Some(Err("alias: a0: expansion nested too deeply".to_string())));
+
+        assert_eq!(capture(&mut shell, "alias mk"), format!("{}\n", format_alias("mk", "mkdir")));
+        shell.execute_command("unalias mk me");
+        assert!(!shell.aliases.contains_key("mk") && !shell.aliases.contains_key("me"));
+        assert_eq!(shell.run_line("unalias mk"), 1);
+    }
+
+    #[test]
+    fn type_concatenates_numbers_and_skips_unreadable_files() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("a.txt"), "one\ntwo\n").unwrap();
+        fs::write(temp.path().join("b.txt"), "three").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(capture(&mut shell, "type a.txt"), "one\ntwo\n\n");
+        assert_eq!(capture(&mut shell, "type a.txt b.txt"), "one\ntwo\nthree");
+        assert_eq!(capture(&mut shell, "type -n a.txt b.txt"), "     1\tone\n     2\ttwo\n     3\tthree\n");
+        assert_eq!(capture(&mut shell, "type missing.txt b.txt"), "three");
+        assert_eq!(shell.status, 1);
+    }
+
+    #[test]
+    fn human_size_switches_units_at_1024() {
+        assert_eq!(human_size(0), "0");
+        assert_eq!(human_size(1023), "1023");
+        assert_eq!(human_size(1024), "1.0K");
+        assert_eq!(human_size(1536), "1.5K");
+        assert_eq!(human_size(1024 * 1024), "1.0M");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0G");
+        assert_eq!(human_size(u64::MAX), "16384.0P");
+    }
+
+    #[test]
+    fn format_timestamp_gives_civil_dates_with_their_offset() {
+        assert_eq!(format_timestamp_at_offset(0, 0), "1970-01-01 00:00 +00:00");
+        // A leap day, mid-afternoon.
+        assert_eq!(format_timestamp_at_offset(951_782_400 + 13 * 3600 + 45 * 60, 0), "2000-02-29 13:45 +00:00");
+        assert_eq!(format_timestamp_at_offset(1_704_067_199, 0), "2023-12-31 23:59 +00:00");
+        // Offsets move the clock, and the date with it, either way.
+        assert_eq!(format_timestamp_at_offset(1_704_067_199, 3600), "2024-01-01 00:59 +01:00");
+        assert_eq!(format_timestamp_at_offset(0, -5 * 3600), "1969-12-31 19:00 -05:00");
+        assert_eq!(format_timestamp_at_offset(0, 5 * 3600 + 30 * 60), "1970-01-01 05:30 +05:30");
+        assert_eq!(format_timestamp(SystemTime::UNIX_EPOCH - Duration::from_secs(1)), "?");
+    }
+
+    #[test]
+    fn long_listing_puts_directories_first_and_hides_dotfiles() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("zdir")).unwrap();
+        fs::write(temp.path().join("a.txt"), vec![b'x'; 2048]).unwrap();
+        fs::write(temp.path().join(".hidden"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        let listing = capture(&mut shell, "dir -lh");
+        let names: Vec<&str> = listing.lines().filter_map(|line| line.split_whitespace().last()).collect();
+        assert_eq!(names, ["zdir", "a.txt"], "{}", listing);
+        assert!(listing.lines().nth(1).unwrap().contains("2.0K"), "{}", listing);
+        assert!(capture(&mut shell, "dir -la").contains(".hidden"));
+    }
+
+    #[test]
+    fn cross_device_errors_fall_back_to_copying() {
+        assert!(is_cross_device(&io::Error::from(io::ErrorKind::CrossesDevices)));
+        assert!(is_cross_device(&io::Error::from_raw_os_error(if cfg!(windows) { 17 } else { 18 })));
+        assert!(!is_cross_device(&io::Error::from(io::ErrorKind::PermissionDenied)));
+
+        let temp = TempDir::new();
+        fs::write(temp.path().join("file.txt"), "file").unwrap();
+        fs::create_dir_all(temp.path().join("tree/sub")).unwrap();
+        fs::write(temp.path().join("tree/sub/leaf.txt"), "leaf").unwrap();
+        move_by_copying(&temp.path().join("file.txt"), &temp.path().join("moved.txt")).unwrap();
+        move_by_copying(&temp.path().join("tree"), &temp.path().join("moved-tree")).unwrap();
+        assert_eq!(fs::read_to_string(temp.path().join("moved.txt")).unwrap(), "file");
+        assert_eq!(fs::read_to_string(temp.path().join("moved-tree/sub/leaf.txt")).unwrap(), "leaf");
+        assert!(!temp.path().join("file.txt").exists());
+        assert!(!temp.path().join("tree").exists());
+    }
+
+    #[test]
+    fn move_into_an_existing_directory() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("report.txt"), "report").unwrap();
+        fs::create_dir(temp.path().join("archive")).unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("move report.txt archive"), 0);
+        assert_eq!(fs::read_to_string(temp.path().join("archive/report.txt")).unwrap(), "report");
+        fs::write(temp.path().join("draft.txt"), "draft").unwrap();
+        assert_eq!(shell.run_line("move draft.txt archive/final.txt"), 0);
+        assert_eq!(fs::read_to_string(temp.path().join("archive/final.txt")).unwrap(), "draft");
+    }
+
+    #[test]
+    fn copy_merges_directory_trees_and_n_keeps_existing_files() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("src/sub")).unwrap();
+        fs::write(temp.path().join("src/a.txt"), "new a").unwrap();
+        fs::write(temp.path().join("src/sub/b.txt"), "new b").unwrap();
+        fs::create_dir_all(temp.path().join("dest/src")).unwrap();
+        fs::write(temp.path().join("dest/src/a.txt"), "old a").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        // Copying onto an existing directory puts the tree inside it, merging with what's there.
+        assert_eq!(capture(&mut shell, "copy -n src dest"), "Copied 1 file to dest/src, 1 already existed.\n");
+        assert_eq!(fs::read_to_string(temp.path().join("dest/src/a.txt")).unwrap(), "old a");
+        assert_eq!(fs::read_to_string(temp.path().join("dest/src/sub/b.txt")).unwrap(), "new b");
+        assert_eq!(capture(&mut shell, "copy src dest"), "Copied 2 files to dest/src.\n");
+        assert_eq!(fs::read_to_string(temp.path().join("dest/src/a.txt")).unwrap(), "new a");
+
+        shell.run_line("copy src src/sub/inside");
+        assert!(!temp.path().join("src/sub/inside").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_skips_symlink_loops() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("src/sub")).unwrap();
+        fs::write(temp.path().join("src/sub/file.txt"), "x").unwrap();
+        std::os::unix::fs::symlink("..", temp.path().join("src/sub/up")).unwrap();
+
+        let report = copy_tree(&temp.path().join("src"), &temp.path().join("dest"), true, CopyOptions::default()).unwrap();
+        assert_eq!(report.files, 1);
+        assert_eq!(report.loops, [temp.path().join("src/sub/up")]);
+        assert!(!temp.path().join("dest/sub/up").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn which_reports_what_a_name_resolves_to() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("bin")).unwrap();
+        let tool = temp.path().join("bin/rubin-tool");
+        fs::write(&tool, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(temp.path().join("bin/not-executable"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.aliases.insert("ll".to_string(), "dir -l".to_string());
+        shell.custom_commands.clear();
+        shell.execute_command("cc create greet ./greet.sh Greets");
+        shell.env_vars.insert("PATH".to_string(), temp.path().join("bin").display().to_string());
+
+        let report = capture(&mut shell, "which ll grep greet rubin-tool");
+        let expected = format!("ll: aliased to `dir -l`\ngrep: shell built-in\ngreet: custom command `./greet.sh`\n{}\n", tool.display());
+        assert_eq!(report, expected);
+        assert_eq!(shell.run_line("which not-executable"), 1);
+        assert_eq!(find_in_path("bin/rubin-tool", None, temp.path()), Some(tool));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unknown_names_run_from_path_with_their_status() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.env_vars.insert("RUBIN_GREETING".to_string(), "hi".to_string());
+        assert_eq!(capture(&mut shell, "sh -c 'echo $RUBIN_GREETING from $(pwd)'"), format!("hi from {}\n", temp.path().display()));
+        assert_eq!(shell.run_line("sh -c 'exit 4'"), 4);
+        assert_eq!(shell.run_line("false || true"), 0);
+        assert_eq!(shell.run_line("rubin-no-such-program"), 127);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn background_jobs_are_listed_and_waited_for() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("sh -c 'exit 3' & sleep 5 &"), 0);
+        assert_eq!(shell.run_line("wait 1"), 3);
+        let listing = capture(&mut shell, "jobs");
+        assert!(listing.starts_with("[2] ") && listing.contains("Running  sleep 5"), "{}", listing);
+        assert_eq!(shell.run_line("wait 1"), 1);
+        shell.jobs[0].child.kill().unwrap();
+        assert_ne!(shell.run_line("wait"), 0);
+        assert!(shell.jobs.is_empty());
+    }
+
+    #[test]
+    fn dir_lists_entries() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("a.txt"), "").unwrap();
+        fs::create_dir(temp.path().join("sub")).unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("dir"), 0);
+        // In whatever order the directory gives them.
+        let output = take_output(&mut shell);
+        let mut names: Vec<&str> = output.lines().collect();
+        names.sort_unstable();
+        assert_eq!(names, ["a.txt", "sub"]);
+    }
+
+    #[test]
+    fn cc_list_shows_created_commands() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.custom_commands.clear();
+        shell.run_line("cc list");
+        assert_eq!(take_output(&mut shell), "No custom commands defined.\n");
+
+        assert_eq!(shell.run_line("cc create greet 'echo hi' 'Says hi'"), 0);
+        take_output(&mut shell);
+        shell.run_line("cc list");
+        let listing = take_output(&mut shell);
+        assert!(listing.contains("greet"), "{}", listing);
+        assert!(listing.contains("Says hi"), "{}", listing);
+    }
+
+    #[test]
+    fn end_of_input_ends_reading_cleanly() {
+        let temp = TempDir::new();
+        let mut shell = test_shell(temp.path(), "pwd\nlast line without newline");
+        assert_eq!(shell.read_input_line("").as_deref(), Some("pwd\n"));
+        assert_eq!(shell.read_input_line("").as_deref(), Some("last line without newline"));
+        assert_eq!(shell.read_input_line(""), None);
+        assert_eq!(shell.read_input_line(""), None);
+    }
+
+    #[test]
+    fn head_and_tail_with_more_lines_than_the_file() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("short.txt"), "one\ntwo\nthree\n").unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("head -n 50 short.txt"), 0);
+        assert_eq!(take_output(&mut shell), "one\ntwo\nthree\n");
+        assert_eq!(shell.run_line("tail -n 50 short.txt"), 0);
+        assert_eq!(take_output(&mut shell), "one\ntwo\nthree\n");
+        shell.run_line("head -n 2 short.txt");
+        assert_eq!(take_output(&mut shell), "one\ntwo\n");
+        shell.run_line("tail -n 2 short.txt");
+        assert_eq!(take_output(&mut shell), "two\nthree\n");
+    }
+
+    #[test]
+    fn head_and_tail_without_a_trailing_newline() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("open.txt"), "one\ntwo\nthree").unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.run_line("head -n 5 open.txt");
+        assert_eq!(take_output(&mut shell), "one\ntwo\nthree\n");
+        shell.run_line("tail -n 1 open.txt");
+        assert_eq!(take_output(&mut shell), "three\n");
+        shell.run_line("tail -n 2 open.txt");
+        assert_eq!(take_output(&mut shell), "two\nthree\n");
+    }
+
+    #[test]
+    fn head_and_tail_read_the_input_without_a_file() {
+        let temp = TempDir::new();
+        let mut shell = test_shell(temp.path(), "one\ntwo\nthree\n");
+        assert_eq!(shell.run_line("tail -n 1"), 0);
+        assert_eq!(take_output(&mut shell), "three\n");
+        fs::write(temp.path().join("list.txt"), "first\nsecond\n").unwrap();
+        assert_eq!(capture(&mut shell, "type list.txt | head -n 1"), "first\n");
+    }
+
+    #[test]
+    fn rm_removes_files_and_needs_r_for_directories() {
+        let temp = TempDir::new();
+        for name in ["a.txt", "b.txt", "c.log"] {
+            fs::write(temp.path().join(name), "").unwrap();
+        }
+        fs::create_dir_all(temp.path().join("build/out")).unwrap();
+        let mut shell = test_shell(temp.path(), "n\ny\n");
+        assert_eq!(shell.run_line("rm *.txt"), 0);
+        assert!(!temp.path().join("a.txt").exists() && !temp.path().join("b.txt").exists());
+        assert_eq!(shell.run_line("rm missing.txt"), 1);
+        assert_eq!(shell.run_line("rm -f missing.txt"), 0);
+        assert_eq!(shell.run_line("rm build"), 1);
+        assert!(temp.path().join("build").exists());
+
+        // The answers come from the input: no to c.log, then yes to the tree.
+        shell.run_line("rm -i c.log");
+        assert!(temp.path().join("c.log").exists());
+        shell.run_line("rm -ir build");
+        assert!(!temp.path().join("build").exists());
+    }
+
+    #[test]
+    fn custom_command_names_are_validated() {
+        assert_eq!(validate_custom_command_name(""), Err("the name is empty".to_string()));
+        assert_eq!(validate_custom_command_name("two words"), Err("the name contains whitespace".to_string()));
+        assert_eq!(validate_custom_command_name("a|b"), Err("the name contains '|'".to_string()));
+        assert_eq!(validate_custom_command_name("$x"), Err("the name contains '$'".to_string()));
+        assert_eq!(validate_custom_command_name("!again"), Err("the name starts with '!'".to_string()));
+        assert_eq!(validate_custom_command_name("deploy-staging"), Ok(()));
+    }
+
+    #[test]
+    fn cc_create_refuses_bad_and_shadowing_names() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.custom_commands.clear();
+        assert_ne!(shell.run_line("cc create 'two words' 'echo' 'desc'"), 0);
+        assert_ne!(shell.run_line("cc create copy 'echo' 'desc'"), 0);
+        assert!(shell.custom_commands.is_empty());
+
+        assert_eq!(shell.run_line("cc create greet 'echo hi' 'desc'"), 0);
+        assert_ne!(shell.run_line("cc create greet 'echo hello' 'desc'"), 0);
+        assert_eq!(shell.run_line("cc create copy 'echo replaced' 'desc' --force"), 0);
+        assert_eq!(shell.custom_commands.len(), 2);
+    }
+
+    #[test]
+    fn cc_delete_and_refactor_by_name_or_number() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.custom_commands.clear();
+        for name in ["first", "second", "third"] {
+            shell.run_line(&format!("cc create {} 'echo {}' 'desc'", name, name));
+        }
+
+        assert_eq!(shell.run_line("cc refactor second 'echo 2' 'Number two'"), 0);
+        assert_eq!(shell.custom_commands[1].definition, "echo 2");
+        assert_eq!(shell.custom_commands[1].description, "Number two");
+
+        assert_eq!(shell.run_line("cc delete third"), 0);
+        assert_ne!(shell.run_line("cc delete third"), 0);
+        // A number is still a position in `cc list`.
+        assert_eq!(shell.run_line("cc delete 1"), 0);
+        assert_ne!(shell.run_line("cc delete 5"), 0);
+        let names: Vec<&str> = shell.custom_commands.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["second"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exported_variables_reach_grandchildren() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("export RUBIN_TEST_EXPORTED=inherited"), 0);
+        assert_eq!(env::var("RUBIN_TEST_EXPORTED").as_deref(), Ok("inherited"));
+        take_output(&mut shell);
+        assert_eq!(shell.run_line("sh -c 'sh -c \"echo \\$RUBIN_TEST_EXPORTED\"'"), 0);
+        assert_eq!(take_output(&mut shell), "inherited\n");
+
+        // Plain setenv stays out of the process environment and the export listing.
+        shell.run_line("setenv RUBIN_TEST_LOCAL local");
+        assert!(env::var("RUBIN_TEST_LOCAL").is_err());
+        take_output(&mut shell);
+        shell.run_line("export");
+        let listing = take_output(&mut shell);
+        assert!(listing.contains("export RUBIN_TEST_EXPORTED='inherited'\n"), "{}", listing);
+        assert!(!listing.contains("RUBIN_TEST_LOCAL"), "{}", listing);
+    }
+
+    #[test]
+    fn env_lists_sorted_and_printenv_prints_one_value() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.env_vars.clear();
+        shell.exported.clear();
+        shell.env_vars.insert("RUBIN_B".to_string(), "2".to_string());
+        shell.env_vars.insert("RUBIN_A".to_string(), "1".to_string());
+        shell.env_vars.insert("PATH".to_string(), "shadowed".to_string());
+        shell.exported.insert("RUBIN_B".to_string());
+        assert_eq!(capture(&mut shell, "env"), "PATH=shadowed\nRUBIN_A=1\nRUBIN_B=2\n");
+        let everything = capture(&mut shell, "env -a");
+        let paths: Vec<&str> = everything.lines().filter(|line| line.starts_with("PATH=")).collect();
+        assert_eq!(paths, ["PATH=shadowed"]);
+        assert!(capture(&mut shell, "printenv").contains("RUBIN_B=2  (exported)\n"));
+
+        assert_eq!(capture(&mut shell, "printenv RUBIN_A"), "1\n");
+        assert_eq!(shell.run_line("printenv RUBIN_UNSET"), 1);
+    }
+
+    fn fake_prompt_field(field: char) -> String {
+        match field {
+            'w' => "~/src/rubin".to_string(),
+            'W' => "rubin".to_string(),
+            'u' => "ada".to_string(),
+            'h' => "engine".to_string(),
+            '?' => "1".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn expand_prompt_substitutes_fields() {
+        assert_eq!(expand_prompt("\\u@\\h:\\w$ ", fake_prompt_field), "ada@engine:~/src/rubin$ ");
+        assert_eq!(expand_prompt("[\\$?] \\W\\n> ", fake_prompt_field), "[1] rubin\n> ");
+        assert_eq!(expand_prompt("plain > ", fake_prompt_field), "plain > ");
+    }
+
+    #[test]
+    fn expand_prompt_keeps_unknown_escapes() {
+        assert_eq!(expand_prompt("\\\\ \\x \\$ end\\", fake_prompt_field), "\\ \\x \\$ end\\");
+    }
+
+    #[test]
+    fn prompt_falls_back_to_the_default() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.env_vars.remove("PROMPT");
+        assert!(shell.prompt().ends_with(" $> "));
+        shell.env_vars.insert("PROMPT".to_string(), "\\W [\\$?] ".to_string());
+        shell.last_status = 3;
+        let name = temp.path().file_name().unwrap().to_string_lossy();
+        assert_eq!(shell.prompt(), format!("{} [3] ", name));
+    }
+
+    #[test]
+    fn entries_are_colored_by_type_but_not_off_the_terminal() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("sub")).unwrap();
+        fs::write(temp.path().join("notes.txt"), "").unwrap();
+        assert_eq!(entry_color(&fs::metadata(temp.path().join("sub")).unwrap()), Some("1;34"));
+        assert_eq!(entry_color(&fs::metadata(temp.path().join("notes.txt")).unwrap()), None);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let script = temp.path().join("run.sh");
+            fs::write(&script, "").unwrap();
+            fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+            assert_eq!(entry_color(&fs::metadata(&script).unwrap()), Some("32"));
+            std::os::unix::fs::symlink("sub", temp.path().join("link")).unwrap();
+            assert_eq!(entry_color(&fs::symlink_metadata(temp.path().join("link")).unwrap()), Some("36"));
+        }
+
+        let mut shell = shell_in(temp.path());
+        assert!(!capture(&mut shell, "dir").contains('\x1b'));
+        assert!(!capture(&mut shell, "dir -l").contains('\x1b'));
+    }
+
+    #[test]
+    fn stat_describes_files_and_links_themselves() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("big.bin"), vec![0u8; 2048]).unwrap();
+        let mut shell = shell_in(temp.path());
+        let details = capture(&mut shell, "stat big.bin");
+        assert!(details.starts_with("    File: big.bin\n    Type: regular file\n    Size: 2048 bytes (2.0K)\n"), "{}", details);
+        assert!(details.contains("Modified: "), "{}", details);
+        assert_eq!(shell.run_line("stat missing.txt"), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(temp.path().join("big.bin"), fs::Permissions::from_mode(0o640)).unwrap();
+            assert!(capture(&mut shell, "stat big.bin").contains("  Access: rw-r----- (640)\n"));
+            std::os::unix::fs::symlink("big.bin", temp.path().join("link")).unwrap();
+            let link = capture(&mut shell, "stat link");
+            assert!(link.starts_with("    File: link -> big.bin\n    Type: symbolic link\n"), "{}", link);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ln_creates_a_symlink_stat_reports() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("target.txt"), "target").unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("ln target.txt link"), 0);
+        assert_eq!(fs::read_link(temp.path().join("link")).unwrap(), Path::new("target.txt"));
+        take_output(&mut shell);
+
+        assert_eq!(shell.run_line("stat link"), 0);
+        let stat = take_output(&mut shell);
+        assert!(stat.contains("File: link -> target.txt\n"), "{}", stat);
+        assert!(stat.contains("Type: symbolic link\n"), "{}", stat);
+        assert_ne!(shell.run_line("ln target.txt link"), 0);
+
+        assert_eq!(shell.run_line("ln -h target.txt hard"), 0);
+        assert!(!fs::symlink_metadata(temp.path().join("hard")).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(temp.path().join("hard")).unwrap(), "target");
+    }
+
+    #[test]
+    fn histories_drop_their_oldest_entries() {
+        let temp = TempDir::new();
+        let dirs: Vec<PathBuf> = (1..=5).map(|n| temp.path().join(format!("d{}", n))).collect();
+        for dir in &dirs {
+            fs::create_dir(dir).unwrap();
+        }
+        let mut shell = shell_in(temp.path());
+        shell.env_vars.insert("RUBIN_HISTORY_SIZE".to_string(), "3".to_string());
+        shell.command_history.clear();
+
+        for n in 1..=5 {
+            shell.add_to_command_history(&format!("echo {}", n));
+        }
+        assert_eq!(shell.command_history, ["echo 3", "echo 4", "echo 5"]);
+
+        for dir in &dirs {
+            shell.enter_dir(dir.clone());
+        }
+        assert_eq!(shell.history, dirs[2..]);
+        shell.go_backward();
+        shell.go_backward();
+        assert_eq!(shell.current_dir, dirs[2]);
+        // Already at the oldest entry kept.
+        shell.go_backward();
+        assert_eq!(shell.current_dir, dirs[2]);
+        shell.go_forward();
+        assert_eq!(shell.current_dir, dirs[3]);
+    }
+
+    #[test]
+    fn history_clear_empties_both_histories() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("sub")).unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.add_to_command_history("echo hi");
+        shell.run_line("cd sub");
+        assert_eq!(shell.run_line("history clear"), 0);
+        assert!(shell.command_history.is_empty());
+        assert_eq!(shell.history, [temp.path().join("sub")]);
+        shell.go_backward();
+        assert_eq!(shell.current_dir, temp.path().join("sub"));
+    }
+
+    #[test]
+    fn cd_dash_toggles_between_the_last_two_directories() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("a/b")).unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("cd -"), 1);
+        shell.run_line("cd a");
+        shell.run_line("cd b");
+        assert_eq!(capture(&mut shell, "cd -"), format!("{}\n", temp.path().join("a").display()));
+        assert_eq!(capture(&mut shell, "cd -"), format!("{}\n", temp.path().join("a/b").display()));
+        // Stepping back through the history counts as a change too.
+        shell.go_backward();
+        shell.run_line("cd -");
+        assert_eq!(shell.current_dir, temp.path().join("a/b"));
+    }
+
+    #[test]
+    fn move_refuses_to_clobber_without_force() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("new.txt"), "new").unwrap();
+        fs::write(temp.path().join("old.txt"), "old").unwrap();
+        let mut shell = test_shell(temp.path(), "n\n");
+        assert_ne!(shell.run_line("move new.txt old.txt"), 0);
+        assert_ne!(shell.run_line("rename new.txt old.txt"), 0);
+        shell.run_line("move -i new.txt old.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "old");
+
+        assert_eq!(shell.run_line("move -f new.txt old.txt"), 0);
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "new");
+        assert!(!temp.path().join("new.txt").exists());
+    }
+
+    #[test]
+    fn mkdir_needs_p_for_missing_parents() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_ne!(shell.run_line("mkdir a/b/c"), 0);
+        assert!(!temp.path().join("a").exists());
+
+        assert_eq!(shell.run_line("mkdir -p a/b/c"), 0);
+        assert!(temp.path().join("a/b/c").is_dir());
+        // Existing directories are fine with -p, an error without.
+        assert_eq!(shell.run_line("mkdir -p a/b"), 0);
+        assert_ne!(shell.run_line("mkdir a"), 0);
+    }
+
+    // Runs `source` as a `.rubin` script, returning its status and output.
+    fn run_script_source(source: &str) -> (i32, String) {
+        let temp = TempDir::new();
+        let path = temp.path().join("sample.rubin");
+        fs::write(&path, source).unwrap();
+        let mut shell = shell_in(temp.path());
+        let status = shell.run_command_file(&path);
+        (status, take_output(&mut shell))
+    }
+
+    #[test]
+    fn script_variables_and_loops() {
+        let source = "set greeting=hello\nfor name in ada grace; do\n  echo $greeting $name\ndone\n";
+        assert_eq!(run_script_source(source), (0, "hello ada\nhello grace\n".to_string()));
+    }
+
+    #[test]
+    fn script_conditionals() {
+        let source = "mkdir made\nif cd made; then\n  echo entered\nelse\n  echo missing\nfi\nif cd nowhere; then\n  echo entered\nelse\n  echo missing\nfi\n";
+        assert_eq!(run_script_source(source), (0, "entered\nmissing\n".to_string()));
+    }
+
+    #[test]
+    fn script_errexit_stops_at_the_first_failure() {
+        assert_eq!(run_script_source("set -e\necho before\ncd nowhere\necho after\n"), (1, "before\n".to_string()));
+        assert_eq!(run_script_source("echo before\ncd nowhere\necho after\n"), (0, "before\nafter\n".to_string()));
+        assert_eq!(run_script_source("if true; then\necho a\n").0, 2);
+    }
+
+    #[test]
+    fn comments_and_blank_lines_do_nothing() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.last_status = 4;
+        assert_eq!(shell.execute_command("   "), 4);
+        assert_eq!(shell.execute_command("# just a note"), 4);
+        assert_eq!(take_output(&mut shell), "");
+
+        assert_eq!(shell.run_line("mkdir \"a # b\" # dropped"), 0);
+        assert!(temp.path().join("a # b").is_dir());
+        assert!(!temp.path().join("dropped").exists());
+    }
+
+    #[test]
+    fn tree_draws_branches_and_counts_entries() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("src/bin")).unwrap();
+        fs::write(temp.path().join("src/bin/tool.rs"), "").unwrap();
+        fs::write(temp.path().join("src/main.rs"), "").unwrap();
+        fs::write(temp.path().join("Cargo.toml"), "").unwrap();
+        fs::write(temp.path().join(".hidden"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(
+            capture(&mut shell, "tree"),
+            ".\n├── Cargo.toml\n└── src\n    ├── bin\n    │   └── tool.rs\n    └── main.rs\n\n2 directories, 3 files\n"
+        );
+        assert_eq!(capture(&mut shell, "tree --max-depth 1 src"), "src\n├── bin\n└── main.rs\n\n1 directory, 1 file\n");
+        assert!(capture(&mut shell, "tree -a --max-depth 1").starts_with(".\n├── .hidden\n"));
+        assert_eq!(shell.run_line("tree Cargo.toml"), 1);
+    }
+
+    fn counts_of(text: &str) -> [u64; 3] {
+        count_contents(&mut io::Cursor::new(text.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn count_contents_handles_edge_cases() {
+        assert_eq!(counts_of(""), [0, 0, 0]);
+        assert_eq!(counts_of("one two\nthree\n"), [2, 3, 14]);
+        // Like `wc`, a last line without a newline isn't counted as a line.
+        assert_eq!(counts_of("one two\nthree"), [1, 3, 13]);
+        assert_eq!(counts_of("  spaced \t out  \n\n"), [2, 2, 18]);
+    }
+
+    #[test]
+    fn count_contents_counts_multibyte_words_once() {
+        // "héllo" is 6 bytes, "日本語" 9.
+        assert_eq!(counts_of("héllo 日本語\n"), [1, 2, 17]);
+    }
+
+    #[test]
+    fn wc_prints_counts_for_a_file() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("empty.txt"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("wc empty.txt"), 0);
+        let output = take_output(&mut shell);
+        let fields: Vec<&str> = output.split_whitespace().collect();
+        assert_eq!(fields, ["0", "0", "0", "empty.txt"]);
+
+        fs::write(temp.path().join("two.txt"), "a b\nc\n").unwrap();
+        shell.run_line("wc -l empty.txt two.txt");
+        let output = take_output(&mut shell);
+        let rows: Vec<Vec<&str>> = output.lines().map(|line| line.split_whitespace().collect()).collect();
+        assert_eq!(rows, [vec!["0", "empty.txt"], vec!["2", "two.txt"], vec!["2", "total"]]);
+    }
+
+    #[test]
+    fn interpret_escapes_expands_known_sequences() {
+        assert_eq!(interpret_escapes("a\\tb\\nc\\\\d"), ("a\tb\nc\\d".to_string(), false));
+        assert_eq!(interpret_escapes("\\e[1m\\a\\b\\r"), ("\x1b[1m\x07\x08\r".to_string(), false));
+        assert_eq!(interpret_escapes("keep \\q and trailing \\"), ("keep \\q and trailing \\".to_string(), false));
+        assert_eq!(interpret_escapes("cut\\c here"), ("cut".to_string(), true));
+    }
+
+    #[test]
+    fn echo_flags() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.run_line("echo plain  words");
+        assert_eq!(take_output(&mut shell), "plain words\n");
+        shell.run_line("echo -n no newline");
+        assert_eq!(take_output(&mut shell), "no newline");
+        shell.run_line("echo -e 'tab\\there'");
+        assert_eq!(take_output(&mut shell), "tab\there\n");
+        shell.run_line("echo 'tab\\there'");
+        assert_eq!(take_output(&mut shell), "tab\\there\n");
+        shell.run_line("echo -ne 'stop\\cignored'");
+        assert_eq!(take_output(&mut shell), "stop");
+        shell.run_line("echo");
+        assert_eq!(take_output(&mut shell), "\n");
+    }
+
+    #[test]
+    fn format_duration_switches_to_minutes() {
+        assert_eq!(format_duration(Duration::from_millis(1234)), "1.234s");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "59.999s");
+        assert_eq!(format_duration(Duration::from_millis(123_456)), "2m3.456s");
+    }
+
+    #[test]
+    fn time_keeps_the_status_and_output_of_its_command() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(capture(&mut shell, "time echo timed"), "timed\n");
+        assert_eq!(shell.run_line("time cd nowhere"), 1);
+        assert_eq!(shell.run_line("time mkdir a && cd a"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("a"));
+    }
+
+    #[test]
+    fn search_finds_the_newest_matching_history_entry() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.command_history = ["cargo build", "dir", "Cargo test", "cargo run"].map(String::from).to_vec();
+        assert_eq!(capture(&mut shell, "search cargo"), "4  cargo run\n");
+        assert_eq!(capture(&mut shell, "search -a -i cargo"), "4  cargo run\n3  Cargo test\n1  cargo build\n");
+        assert_eq!(shell.run_line("search nothing-like-it"), 1);
+        // The search just run, now in the history, isn't a match for the next one.
+        assert_eq!(capture(&mut shell, "search -a build"), "1  cargo build\n");
+    }
+
+    #[test]
+    fn bulk_rename_previews_and_skips_collisions() {
+        let temp = TempDir::new();
+        for name in ["a.jpeg", "b.jpeg", "b.jpg"] {
+            fs::write(temp.path().join(name), name).unwrap();
+        }
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("rename --sub .jpeg .jpg '*.jpeg'"), 0);
+        assert_eq!(take_output(&mut shell), "a.jpeg -> a.jpg\n1 rename planned; re-run with --apply to carry out.\n");
+        assert!(temp.path().join("a.jpeg").exists());
+
+        shell.run_line("rename --sub .jpeg .jpg '*.jpeg' --apply");
+        assert_eq!(fs::read_to_string(temp.path().join("a.jpg")).unwrap(), "a.jpeg");
+        // The one whose new name was taken is left alone, as is what took it.
+        assert_eq!(fs::read_to_string(temp.path().join("b.jpeg")).unwrap(), "b.jpeg");
+        assert_eq!(fs::read_to_string(temp.path().join("b.jpg")).unwrap(), "b.jpg");
+    }
+
+    #[test]
+    fn a_deleted_current_dir_falls_back_to_its_parent() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("a/b/c")).unwrap();
+        let mut shell = shell_in(temp.path());
+        shell.run_line("cd a/b/c");
+        fs::remove_dir_all(temp.path().join("a/b")).unwrap();
+
+        shell.recover_current_dir();
+        assert_eq!(shell.current_dir, temp.path().join("a"));
+        assert_eq!(shell.run_line("pwd"), 0);
+        assert_eq!(take_output(&mut shell), format!("{}\n", temp.path().join("a").display()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn external_commands_run_in_the_current_dir() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("sub")).unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("cd sub"), 0);
+        assert_eq!(shell.run_line("sh -c 'pwd -P'"), 0);
+        assert_eq!(take_output(&mut shell), format!("{}\n", temp.path().join("sub").display()));
+    }
+
+    #[test]
+    fn quoted_arguments_read_back_unchanged() {
+        let words = ["plain", "two words", "it's", "$HOME", "a|b", "#tag", ""];
+        let line = words.map(quote_argument).join(" ");
+        assert_eq!(tokenize_words(&line), words.map(|word| Token::Word(word.to_string())));
+        assert_eq!(quote_argument("plain"), "plain");
+    }
+
+    #[test]
+    fn watch_rejects_bad_intervals() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        for command in ["watch -n 0 pwd", "watch -n soon pwd", "watch -n -1 pwd", "watch -n", "watch -n 2"] {
+            assert_eq!(shell.run_line(command), 1, "{}", command);
+        }
+        assert_eq!(take_output(&mut shell), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_is_refused_once_while_jobs_run() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("sleep 30 &"), 0);
+        assert_eq!(shell.jobs.len(), 1);
+        // Refused, so this returns instead of ending the test process.
+        assert_eq!(shell.run_line("exit"), 1);
+        assert!(shell.exit_warned);
+        for job in &mut shell.jobs {
+            job.child.kill().unwrap();
+            job.child.wait().unwrap();
+        }
+    }
+
+    #[test]
+    fn env_file_lines_parse_export_and_quotes() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.env_vars.insert("BASE".to_string(), "/opt".to_string());
+        let parse = |line: &str| shell.parse_env_line(line);
+        assert_eq!(parse("KEY=value"), Ok(("KEY".to_string(), "value".to_string(), false)));
+        assert_eq!(parse("export KEY=value"), Ok(("KEY".to_string(), "value".to_string(), true)));
+        assert_eq!(parse("GREETING=\"hello world\""), Ok(("GREETING".to_string(), "hello world".to_string(), false)));
+        assert_eq!(parse("RAW='$BASE/bin'"), Ok(("RAW".to_string(), "$BASE/bin".to_string(), false)));
+        assert_eq!(parse("BIN=$BASE/bin"), Ok(("BIN".to_string(), "/opt/bin".to_string(), false)));
+        assert_eq!(parse("EMPTY="), Ok(("EMPTY".to_string(), String::new(), false)));
+        assert_eq!(parse("no equals sign"), Err("expected KEY=value".to_string()));
+        assert!(parse("1BAD=x").unwrap_err().starts_with("invalid name '1BAD'"));
+        assert_eq!(parse("A=x | y"), Err("unquoted `|` or `>` in the value".to_string()));
+    }
+
+    #[test]
+    fn source_loads_an_env_file_and_skips_comments() {
+        let temp = TempDir::new();
+        fs::write(
+            temp.path().join(".env"),
+            "# settings\n\nexport RUBIN_TEST_SOURCED=yes # exported\nNAME=\"Ada Lovelace\"\nbroken line\n",
+        )
+        .unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("source .env"), 1);
+        assert_eq!(take_output(&mut shell), "Sourced 2 variables from .env.\n");
+        assert_eq!(shell.env_vars.get("NAME").map(String::as_str), Some("Ada Lovelace"));
+        assert_eq!(env::var("RUBIN_TEST_SOURCED").as_deref(), Ok("yes"));
+    }
+
+    #[test]
+    fn version_info_names_the_version_and_target() {
+        let info = version_info();
+        assert!(info.starts_with(&format!("rubin {} (", env!("CARGO_PKG_VERSION"))), "{}", info);
+        assert!(info.contains(env!("RUBIN_TARGET")), "{}", info);
+    }
+
+    #[test]
+    fn mkcd_creates_and_enters_a_directory() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("taken"), "").unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(shell.run_line("mkcd build/output"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("build/output"));
+        shell.go_backward();
+        assert_eq!(shell.current_dir, temp.path());
+
+        assert_eq!(shell.run_line("mkcd taken/sub"), 1);
+        assert_eq!(shell.current_dir, temp.path());
+    }
+
+    #[test]
+    fn bang_bang_reruns_and_echoes_the_last_line() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        shell.command_history = vec!["mkdir first".to_string()];
+        assert_eq!(capture(&mut shell, "!1"), "mkdir first\n");
+        assert!(temp.path().join("first").is_dir());
+        // The history gets the expanded line, so `!!` repeats the command, not the reference.
+        assert_eq!(shell.command_history, ["mkdir first"]);
+        shell.execute_command("rmdir first");
+        shell.execute_command("!1");
+        assert_eq!(capture(&mut shell, "!!"), "mkdir first\n");
+        assert!(temp.path().join("first").is_dir());
+    }
+
+    #[test]
+    fn pushd_and_popd_walk_the_stack() {
+        let temp = TempDir::new();
+        fs::create_dir_all(temp.path().join("one/two")).unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_ne!(shell.run_line("popd"), 0);
+        assert_ne!(shell.run_line("pushd missing"), 0);
+        assert!(shell.dir_stack.is_empty());
+        assert_eq!(shell.current_dir, temp.path());
+
+        assert_eq!(shell.run_line("pushd one"), 0);
+        assert_eq!(shell.run_line("pushd two"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("one/two"));
+        take_output(&mut shell);
+        shell.run_line("dirs");
+        let (two, one) = (temp.path().join("one/two"), temp.path().join("one"));
+        assert_eq!(take_output(&mut shell), format!("0  {}\n1  {}\n2  {}\n", two.display(), one.display(), temp.path().display()));
+        // With no argument, swaps with the top of the stack.
+        assert_eq!(shell.run_line("pushd"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("one"));
+        assert_eq!(shell.run_line("popd"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("one/two"));
+        assert_eq!(shell.run_line("popd"), 0);
+        assert_eq!(shell.current_dir, temp.path());
+        assert_ne!(shell.run_line("popd"), 0);
+    }
+
+    #[test]
+    fn looks_binary_allows_utf8_only() {
+        assert!(!looks_binary("héllo, 日本語\n".as_bytes()));
+        assert!(!looks_binary(b""));
+        assert!(looks_binary(b"ab\x00cd"));
+        assert!(looks_binary(b"\xff\xfe text"));
+        // "é" cut in half by the end of the sample.
+        assert!(!looks_binary(&"café".as_bytes()[..4]));
+    }
+
+    #[test]
+    fn type_prints_text_and_refuses_binary() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("text.txt"), "héllo\n").unwrap();
+        fs::write(temp.path().join("data.bin"), b"ab\x00\x01cd").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(shell.run_line("type text.txt"), 0);
+        assert_eq!(take_output(&mut shell), "héllo\n\n");
+        assert_eq!(shell.run_line("type data.bin"), 1);
+        assert_eq!(take_output(&mut shell), "");
+        assert_eq!(shell.run_line("type --force data.bin"), 0);
+        assert_eq!(take_output(&mut shell).as_bytes(), b"ab\x00\x01cd\n");
+        assert_eq!(shell.run_line("type --hex data.bin"), 0);
+        assert_eq!(take_output(&mut shell), "00000000  61 62 00 01 63 64                                 |ab..cd|\n");
+    }
+
+    #[test]
+    fn autocd_enters_bare_directory_names_only_when_enabled() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("inner")).unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(shell.run_line("inner"), 127);
+        assert_eq!(shell.current_dir, temp.path());
+
+        shell.run_line("set autocd on");
+        assert_eq!(shell.run_line("inner"), 0);
+        assert_eq!(shell.current_dir, temp.path().join("inner"));
+        // Arguments mean a command was intended, so it isn't a `cd`.
+        shell.run_line("cd ..");
+        assert_eq!(shell.run_line("inner extra"), 127);
+        assert_eq!(shell.current_dir, temp.path());
+    }
+
+    #[test]
+    fn repeat_runs_count_times_and_can_stop_on_error() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("good.txt"), "marker\n").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(shell.run_line("repeat 3 echo hello"), 0);
+        assert_eq!(take_output(&mut shell), "hello\nhello\nhello\n");
+
+        // Each run prints the good file, then fails on the missing one.
+        assert_eq!(shell.run_line("repeat 3 type good.txt missing.txt"), 1);
+        assert_eq!(take_output(&mut shell).matches("marker").count(), 3);
+        assert_eq!(shell.run_line("repeat --stop-on-error 3 type good.txt missing.txt"), 1);
+        assert_eq!(take_output(&mut shell).matches("marker").count(), 1);
+
+        assert_eq!(shell.run_line("repeat 0 echo hello"), 1);
+        assert_eq!(shell.run_line("repeat 2"), 2);
+        assert_eq!(take_output(&mut shell), "");
+    }
+
+    #[test]
+    fn describe_contents_recognizes_each_signature() {
+        for (magic, description) in MAGIC_SIGNATURES {
+            let mut sample = magic.to_vec();
+            sample.extend_from_slice(b"\x00\x01rest of the file");
+            assert_eq!(describe_contents(&sample), *description, "signature {:?}", magic);
+        }
+        assert_eq!(describe_contents(b""), "empty");
+        assert_eq!(describe_contents(b"plain words\n"), "ASCII text");
+        assert_eq!(describe_contents("naïve\n".as_bytes()), "UTF-8 text");
+        assert_eq!(describe_contents(b"\x00\x00\x10\x20"), "data");
+        // A signature only counts at the very start.
+        assert_eq!(describe_contents(b"see %PDF-1.7"), "ASCII text");
+    }
+
+    #[test]
+    fn file_prints_each_path_with_its_type() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("image"), b"\x89PNG\r\n\x1a\n....").unwrap();
+        fs::write(temp.path().join("notes"), "plain words\n").unwrap();
+        let mut shell = shell_in(temp.path());
+        assert_eq!(capture(&mut shell, "file notes"), "notes: ASCII text\n");
+        assert!(capture(&mut shell, "file image").starts_with("image: PNG"));
+        assert_eq!(shell.run_line("file missing"), 1);
+    }
+
+    #[test]
+    fn set_toggles_options() {
+        let temp = TempDir::new();
+        let mut shell = shell_in(temp.path());
+        for name in ShellOptions::NAMES {
+            shell.run_line(&format!("set {} on", name));
+            assert_eq!(shell.options.flag(name), Some(true), "{}", name);
+            shell.run_line(&format!("set {} off", name));
+            assert_eq!(shell.options.flag(name), Some(false), "{}", name);
+        }
+        assert_eq!(shell.options.flag("nonsense"), None);
+        assert_ne!(shell.run_line("set nonsense on"), 0);
+
+        shell.run_line("set noclobber on");
+        shell.run_line("unset noclobber");
+        assert!(!shell.options.noclobber);
+    }
+
+    #[test]
+    fn noclobber_stops_overwriting() {
+        let temp = TempDir::new();
+        let source = temp.path().join("source.txt");
+        let target = temp.path().join("target.txt");
+        let mut shell = shell_in(temp.path());
+
+        shell.run_line("set noclobber on");
+        fs::write(&source, "new").unwrap();
+        fs::write(&target, "old").unwrap();
+        shell.run_line("copy source.txt target.txt");
+        assert_ne!(shell.run_line("echo new > target.txt"), 0);
+        assert_ne!(shell.run_line("move source.txt target.txt"), 0);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "old");
+        assert!(source.exists());
+
+        // `-f` still overwrites for one command.
+        assert_eq!(shell.run_line("copy -f source.txt target.txt"), 0);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+
+        shell.run_line("set noclobber off");
+        fs::write(&target, "old").unwrap();
+        assert_eq!(shell.run_line("copy source.txt target.txt"), 0);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(shell.run_line("echo again > target.txt"), 0);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "again\n");
+    }
+
+    #[test]
+    fn verify_copy_catches_a_corrupted_destination() {
+        let temp = TempDir::new();
+        let (original, copy) = (temp.path().join("original.bin"), temp.path().join("copy.bin"));
+        fs::write(&original, b"abc").unwrap();
+        fs::write(&copy, b"abc").unwrap();
+        assert!(verify_copy(&original, &copy).is_ok());
+
+        fs::write(&copy, b"abd").unwrap();
+        let error = verify_copy(&original, &copy).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let message = error.to_string();
+        assert!(message.contains("sha256 mismatch"), "{}", message);
+        assert!(message.contains("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad expected"), "{}", message);
+    }
+
+    #[test]
+    fn copy_verify_counts_checked_files() {
+        let temp = TempDir::new();
+        fs::create_dir(temp.path().join("src")).unwrap();
+        fs::write(temp.path().join("src/a.txt"), "one").unwrap();
+        fs::write(temp.path().join("src/b.txt"), "two").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(shell.run_line("copy --verify src dest"), 0);
+        assert_eq!(take_output(&mut shell), "Copied 2 files to dest.\nVerified 2 files (sha256).\n");
+        assert_eq!(fs::read_to_string(temp.path().join("dest/b.txt")).unwrap(), "two");
+    }
+
+    #[test]
+    fn part_path_and_parse_size() {
+        assert_eq!(part_path(Path::new("dir/disk.img"), 1), Path::new("dir/disk.img.part001"));
+        assert_eq!(part_path(Path::new("disk.img"), 1234), Path::new("disk.img.part1234"));
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10k"), Some(10 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("k"), None);
+        assert_eq!(parse_size("1.5M"), None);
+        assert_eq!(parse_size("99999999999999999999k"), None);
+    }
+
+    #[test]
+    fn split_then_join_restores_the_file() {
+        let temp = TempDir::new();
+        let original: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        let path = temp.path().join("data.bin");
+        fs::write(&path, &original).unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(shell.run_line("split data.bin 1k"), 0);
+        assert_eq!(take_output(&mut shell), "Split data.bin into 3 parts of up to 1.0K.\n");
+        let sizes: Vec<u64> = (1..=3).map(|n| fs::metadata(part_path(&path, n)).unwrap().len()).collect();
+        assert_eq!(sizes, [1024, 1024, 452]);
+        assert!(!part_path(&path, 4).exists());
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(shell.run_line("join data.bin"), 0);
+        assert_eq!(take_output(&mut shell), "Joined 3 parts into data.bin (2.4K).\n");
+        assert_eq!(fs::read(&path).unwrap(), original);
+
+        assert_eq!(shell.run_line("join missing.bin"), 1);
+        assert_eq!(shell.run_line("split data.bin 0"), 1);
+    }
+
+    #[test]
+    fn hex_dump_row_layout() {
+        assert_eq!(
+            hex_dump_row(0, b"0123456789abcdef"),
+            "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|"
+        );
+        // A short last row is padded so the ASCII column still lines up.
+        assert_eq!(
+            hex_dump_row(0x1f0, b"hi\n\x00 \x7f"),
+            "000001f0  68 69 0a 00 20 7f                                 |hi.. .|"
+        );
+        assert_eq!(hex_dump_row(0x10, b"12345678\xff").len(), hex_dump_row(0x10, b"123456789").len());
+    }
+
+    #[test]
+    fn hexdump_honours_offset_and_limit() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("data"), b"0123456789abcdefghijklmnopqrstuvwxyz").unwrap();
+        let mut shell = test_shell(temp.path(), "piped");
+
+        assert_eq!(shell.run_line("hexdump -s 30 -n 4 data"), 0);
+        assert_eq!(take_output(&mut shell), format!("{}\n", hex_dump_row(30, b"uvwx")));
+        assert_eq!(shell.run_line("hexdump -s 2 -n 2"), 0);
+        assert_eq!(take_output(&mut shell), format!("{}\n", hex_dump_row(2, b"pe")));
+        assert_eq!(shell.run_line("hexdump -n"), 1);
+        assert_eq!(shell.run_line("hexdump missing"), 1);
+    }
+
+    #[test]
+    fn leading_number_takes_the_longest_numeric_prefix() {
+        assert_eq!(leading_number("42 apples"), Some(42.0));
+        assert_eq!(leading_number("  -3.5kg"), Some(-3.5));
+        assert_eq!(leading_number("1.2.3"), Some(1.2));
+        assert_eq!(leading_number("+7"), Some(7.0));
+        assert_eq!(leading_number("apples"), None);
+        assert_eq!(leading_number("-"), None);
+        assert_eq!(leading_number(""), None);
+    }
+
+    #[test]
+    fn sort_numeric_and_lexical() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("numbers"), "10 ten\n9 nine\n100 hundred\nnone\n-1 minus\n").unwrap();
+        let mut shell = shell_in(temp.path());
+
+        assert_eq!(shell.run_line("sort numbers"), 0);
+        assert_eq!(take_output(&mut shell), "-1 minus\n10 ten\n100 hundred\n9 nine\nnone\n");
+        // Lines without a number come first, as in `sort -n`.
+        assert_eq!(shell.run_line("sort -n numbers"), 0);
+        assert_eq!(take_output(&mut shell), "none\n-1 minus\n9 nine\n10 ten\n100 hundred\n");
+        assert_eq!(shell.run_line("sort -rn numbers"), 0);
+        assert_eq!(take_output(&mut shell), "100 hundred\n10 ten\n9 nine\n-1 minus\nnone\n");
+        assert_eq!(shell.run_line("sort -x numbers"), 1);
+    }
+
+    #[test]
+    fn uniq_drops_and_counts_adjacent_repeats() {
+        let temp = TempDir::new();
+        let mut shell = test_shell(temp.path(), "a\na\nb\na\na\na\nc\n");
+
+        assert_eq!(shell.run_line("uniq -c"), 0);
+        assert_eq!(take_output(&mut shell), "      2 a\n      1 b\n      3 a\n      1 c\n");
+        fs::write(temp.path().join("lines"), "x\nx\ny\n").unwrap();
+        assert_eq!(shell.run_line("uniq lines"), 0);
+        assert_eq!(take_output(&mut shell), "x\ny\n");
+    }
+
+    #[test]
+    fn trash_then_restore_puts_things_back() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("note.txt"), "keep me").unwrap();
+        fs::create_dir_all(temp.path().join("project/src")).unwrap();
+        fs::write(temp.path().join("project/src/main.rs"), "fn main() {}").unwrap();
+        // `rmdir -r` asks first, even when it's only going to the trash.
+        let mut shell = test_shell(temp.path(), "y\n");
+
+        shell.run_line("set trash on");
+        assert_eq!(shell.run_line("rm note.txt"), 0);
+        assert_eq!(shell.run_line("rmdir -r project"), 0);
+        assert_eq!(take_output(&mut shell), "Moved note.txt to the trash.\nMoved project to the trash.\n");
+        assert!(!temp.path().join("note.txt").exists());
+        assert!(!temp.path().join("project").exists());
+
+        assert_eq!(shell.run_line("restore"), 0);
+        let listing = take_output(&mut shell);
+        assert!(listing.contains(&format!("  {}\n", temp.path().join("note.txt").display())), "{}", listing);
+
+        assert_eq!(shell.run_line("restore note.txt project"), 0);
+        assert_eq!(take_output(&mut shell), "Restored note.txt.\nRestored project.\n");
+        assert_eq!(fs::read_to_string(temp.path().join("note.txt")).unwrap(), "keep me");
+        assert_eq!(fs::read_to_string(temp.path().join("project/src/main.rs")).unwrap(), "fn main() {}");
+        assert_eq!(shell.run_line("restore note.txt"), 1);
+    }
+
+    #[test]
+    fn match_ranges_skips_empty_matches() {
+        let regex = Regex::new("o+").unwrap();
+        assert_eq!(match_ranges(&regex, "foo boo"), [1..3, 5..7]);
+        assert!(match_ranges(&Regex::new("x*").unwrap(), "abc").is_empty());
+        assert_eq!(match_ranges(&Regex::new("é").unwrap(), "café é"), [3..5, 6..8]);
+    }
+
+    #[test]
+    fn highlight_ranges_paints_only_the_matches() {
+        assert_eq!(highlight_ranges("foo boo", &[1..3, 5..7]), "f\x1b[1;31moo\x1b[0m b\x1b[1;31moo\x1b[0m");
+        assert_eq!(highlight_ranges("abc", &[]), "abc");
+        assert_eq!(highlight_ranges("abc", &[0..1, 1..3]), "\x1b[1;31ma\x1b[0m\x1b[1;31mbc\x1b[0m");
+    }
+
+    #[test]
+    fn grep_only_matching_and_line_numbers_without_color() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("notes"), "error: disk\nfine\nerror: net, error: dns\n").unwrap();
+        let mut shell = test_shell(temp.path(), "");
+
+        assert_eq!(shell.run_line("grep -n error notes"), 0);
+        assert_eq!(take_output(&mut shell), "1:error: disk\n3:error: net, error: dns\n");
+        assert_eq!(shell.run_line("grep -o 'error: [a-z]+' notes"), 0);
+        assert_eq!(take_output(&mut shell), "error: disk\nerror: net\nerror: dns\n");
+        assert_eq!(shell.run_line("grep missing notes"), 1);
+    }
+
+    #[test]
+    fn mode_change_parse_and_apply() {
+        let apply = |text: &str, mode: u32| ModeChange::parse(text).map(|change| change.apply(mode));
+        assert_eq!(apply("644", 0o777), Some(0o644));
+        assert_eq!(apply("4755", 0), Some(0o4755));
+        assert_eq!(apply("+x", 0o644), Some(0o755));
+        assert_eq!(apply("go-w", 0o666), Some(0o644));
+        assert_eq!(apply("u=rw", 0o755), Some(0o655));
+        assert_eq!(apply("a=r", 0o777), Some(0o444));
+        assert_eq!(apply("ug+rwx", 0o000), Some(0o770));
+        assert!(ModeChange::parse("").is_none());
+        assert!(ModeChange::parse("17777").is_none());
+        assert!(ModeChange::parse("899").is_none());
+        assert!(ModeChange::parse("q+x").is_none());
+        assert!(ModeChange::parse("u+z").is_none());
+        assert!(ModeChange::parse("rwx").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chmod_sets_the_mode_stat_reports() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("script.sh"), "echo hi\n").unwrap();
+        let mut shell = test_shell(temp.path(), "");
+
+        assert_eq!(shell.run_line("chmod 640 script.sh"), 0);
+        assert_eq!(shell.run_line("chmod u+x script.sh"), 0);
+        assert_eq!(shell.run_line("stat script.sh"), 0);
+        let report = take_output(&mut shell);
+        assert!(report.contains("  Access: rwxr----- (740)\n"), "{}", report);
+        assert_eq!(shell.run_line("chmod 99 script.sh"), 1);
+        assert_eq!(shell.run_line("chmod 644 missing.sh"), 1);
+    }
+
+    #[test]
+    fn confirm_reads_scripted_answers() {
+        let temp = TempDir::new();
+        assert!(test_shell(temp.path(), "y\n").confirm("Go?"));
+        assert!(test_shell(temp.path(), "YES\n").confirm("Go?"));
+        assert!(!test_shell(temp.path(), "n\n").confirm("Go?"));
+        assert!(test_shell(temp.path(), "maybe\ny\n").confirm("Go?"));
+        assert!(!test_shell(temp.path(), "\n").confirm("Go?"));
+        assert!(!test_shell(temp.path(), "").confirm("Go?"));
+    }
+
+    #[test]
+    fn rm_interactive_removes_only_on_yes() {
+        let temp = TempDir::new();
+        let file = temp.path().join("doomed.txt");
+        fs::write(&file, "x").unwrap();
+        test_shell(temp.path(), "n\n").run_line("rm -i doomed.txt");
+        assert!(file.exists());
+        test_shell(temp.path(), "what\ny\n").run_line("rm -i doomed.txt");
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn move_interactive_overwrites_only_on_yes() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("new.txt"), "new").unwrap();
+        fs::write(temp.path().join("old.txt"), "old").unwrap();
+        test_shell(temp.path(), "n\n").run_line("move -i new.txt old.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "old");
+        assert!(temp.path().join("new.txt").exists());
+        test_shell(temp.path(), "y\n").run_line("move -i new.txt old.txt");
+        assert_eq!(fs::read_to_string(temp.path().join("old.txt")).unwrap(), "new");
+        assert!(!temp.path().join("new.txt").exists());
+    }
+
+    #[test]
+    fn commands_lists_builtins_and_aliases() {
+        let temp = TempDir::new();
+        let mut shell = test_shell(temp.path(), "");
+        shell.run_line("alias ll='dir -l'");
+
+        assert_eq!(shell.run_line("commands"), 0);
+        let listing = take_output(&mut shell);
+        assert!(listing.starts_with("Built-ins:\n"), "{}", listing);
+        for spec in COMMANDS {
+            assert!(listing.contains(&format!("\n  {}\n", spec.name)), "{} missing from\n{}", spec.name, listing);
+        }
+        assert!(listing.contains("\n\nAliases:\n  ll = dir -l\n"), "{}", listing);
+        assert!(!listing.contains("Programs on PATH"));
+        assert_eq!(shell.run_line("commands --bogus"), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_external_adds_path_programs() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = TempDir::new();
+        let bin = temp.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        for (name, mode) in [("tool-a", 0o755), ("not-executable", 0o644)] {
+            fs::write(bin.join(name), "").unwrap();
+            fs::set_permissions(bin.join(name), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let mut shell = test_shell(temp.path(), "");
+        shell.env_vars.insert("PATH".to_string(), bin.display().to_string());
+
+        assert_eq!(shell.run_line("commands --external"), 0);
+        let listing = take_output(&mut shell);
+        assert!(listing.ends_with("\n\nPrograms on PATH:\n  tool-a\n"), "{}", listing);
+    }
+
+    #[test]
+    fn replace_previews_until_applied() {
+        let temp = TempDir::new();
+        fs::write(temp.path().join("a.toml"), "version = 1.0.3\ndep = 1.0.3\n").unwrap();
+        fs::write(temp.path().join("b.toml"), "version = 1.0.3\n").unwrap();
+        fs::write(temp.path().join("c.toml"), "unrelated\n").unwrap();
+        let mut shell = test_shell(temp.path(), "");
+
+        assert_eq!(shell.run_line("replace 1.0.3 1.0.4 *.toml"), 0);
+        assert_eq!(
+            take_output(&mut shell),
+            "a.toml: 2 replacements\nb.toml: 1 replacement\nc.toml: 0 replacements\n\
+             Nothing changed yet; re-run with --apply to write the files.\n"
+        );
+        assert_eq!(fs::read_to_string(temp.path().join("a.toml")).unwrap(), "version = 1.0.3\ndep = 1.0.3\n");
+
+        assert_eq!(shell.run_line("replace -i --no-backup 1.0.3 1.0.4 a.toml b.toml c.toml"), 0);
+        assert_eq!(take_output(&mut shell), "a.toml: 2 replacements\nb.toml: 1 replacement\nc.toml: 0 replacements\n");
+        assert_eq!(fs::read_to_string(temp.path().join("a.toml")).unwrap(), "version = 1.0.4\ndep = 1.0.4\n");
+        assert_eq!(fs::read_to_string(temp.path().join("b.toml")).unwrap(), "version = 1.0.4\n");
+        for name in ["a.toml.bak", "b.toml.bak", "c.toml.bak"] {
+            assert!(!temp.path().join(name).exists(), "{} was written", name);
+        }
+
+        assert_eq!(shell.run_line("replace --regex '(' x a.toml"), 1);
+        assert_eq!(shell.run_line("replace --bogus a b a.toml"), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edit_creates_the_file_and_runs_the_editor_on_it() {
+        use std::os::unix::fs::PermissionsExt;
+        let temp = TempDir::new();
+        let bin = temp.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        // Appends its first argument to the file it's given, so the flag shows it was passed.
+        fs::write(bin.join("fake-editor"), "#!/bin/sh\necho \"$1\" >> \"$2\"\n").unwrap();
+        fs::set_permissions(bin.join("fake-editor"), fs::Permissions::from_mode(0o755)).unwrap();
+        let mut shell = test_shell(temp.path(), "");
+        shell.env_vars.insert("PATH".to_string(), bin.display().to_string());
+        shell.env_vars.insert("EDITOR".to_string(), "fake-editor --wait".to_string());
+
+        assert_eq!(shell.run_line("edit new.txt"), 0);
+        assert_eq!(fs::read_to_string(temp.path().join("new.txt")).unwrap(), "--wait\n");
+
+        shell.env_vars.insert("EDITOR".to_string(), "no-such-editor".to_string());
+        assert_eq!(shell.run_line("edit other.txt"), 1);
+        assert!(!temp.path().join("other.txt").exists());
+    }
+
+    #[test]
+    fn hooks_see_the_command_and_its_status() {
+        let temp = TempDir::new();
+        let mut shell = test_shell(temp.path(), "");
+        assert_eq!(shell.run_line(r#"hook add pre 'echo "pre $RUBIN_LAST_CMD"'"#), 0);
+        assert_eq!(shell.run_line(r#"hook add post 'echo "post $RUBIN_LAST_CMD -> $?"'"#), 0);
+
+        assert_eq!(shell.execute_command("type missing.txt"), 1);
+        assert_eq!(take_output(&mut shell), "pre type missing.txt\npost type missing.txt -> 1\n");
+        // The hook's own success doesn't replace the command's status.
+        assert_eq!(shell.run_line("echo $?"), 0);
+        assert_eq!(take_output(&mut shell), "1\n");
+
+        assert_eq!(shell.execute_command("echo hi"), 0);
+        assert_eq!(take_output(&mut shell), "pre echo hi\nhi\npost echo hi -> 0\n");
+
+        // Only lines typed at the prompt run them.
+        assert_eq!(shell.run_line("echo quiet"), 0);
+        assert_eq!(take_output(&mut shell), "quiet\n");
+        assert_eq!(shell.run_line("hook remove post 1"), 0);
+        assert_eq!(shell.execute_command("echo again"), 0);
+        assert_eq!(take_output(&mut shell), "pre echo again\nagain\n");
+    }
+
+    #[test]
+    fn log_entry_is_one_json_line() {
+        let entry = LogEntry { time: 1_700_000_000, cwd: "/tmp/work", command: "echo \"hi\"", status: 1 };
+        let mut written = Vec::new();
+        write_log_entry(&mut written, &entry).unwrap();
+        let text = String::from_utf8(written).unwrap();
+        let line = text.strip_suffix('\n').expect("the entry ends with a newline");
+        assert!(!line.contains('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(line).unwrap();
+        let object = value.as_object().unwrap();
+        let mut keys: Vec<&str> = object.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["command", "cwd", "status", "time"]);
+        assert_eq!(object["time"], 1_700_000_000);
+        assert_eq!(object["cwd"], "/tmp/work");
+        assert_eq!(object["command"], "echo \"hi\"");
+        assert_eq!(object["status"], 1);
+    }
+
+    #[test]
+    fn undoing_a_restore_puts_it_back_in_the_trash() {
+        let temp = TempDir::new();
+        let file = temp.path().join("draft.txt");
+        fs::write(&file, "v1").unwrap();
+        let mut shell = test_shell(temp.path(), "");
+
+        assert_eq!(shell.run_line("rm --trash draft.txt"), 0);
+        assert_eq!(shell.run_line("restore draft.txt"), 0);
+        assert!(file.exists());
+        take_output(&mut shell);
+
+        shell.run_line("undo");
+        assert_eq!(take_output(&mut shell), format!("Moved {} back to the trash.\n", file.display()));
+        assert!(!file.exists());
+        // The trash still knows where it came from, so it can be restored again.
+        assert_eq!(shell.run_line("restore draft.txt"), 0);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "v1");
+    }
 }
@@ -0,0 +1,431 @@
+// Splits a command line into words and operators, honouring quotes, escapes, `$VAR`
+// expansion and a leading `~`.
+
+#[derive(Debug, PartialEq)]
+pub enum Token {
+    Word(String),
+    // `>` truncates the target, `>>` appends to it.
+    Redirect { append: bool },
+    Pipe,
+}
+
+// Outside quotes a backslash only escapes these; anywhere else it's kept as-is so
+// Windows paths like `C:\Users` survive unquoted.
+pub const ESCAPABLE: &[char] = &[' ', '\t', '\'', '"', '\\', '$', '>', '|', ';', '&'];
+
+// How a command in a chain depends on the one before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    // `;`, and the first command of a line: always runs.
+    Always,
+    // `&&`: runs only if the previous command succeeded.
+    And,
+    // `||`: runs only if the previous command failed.
+    Or,
+}
+
+pub struct ChainCommand<'a> {
+    pub connector: Connector,
+    pub text: &'a str,
+    // Ended by a single `&`: started without waiting for it to finish.
+    pub background: bool,
+}
+
+// Splits a line on unquoted `;`, `&`, `&&` and `||`, before any expansion happens, so
+// each command sees variables (and `$?`) as left by the ones before it.
+pub fn split_chain(input: &str) -> Result<Vec<ChainCommand<'_>>, String> {
+    let mut commands = Vec::new();
+    let mut connector = Connector::Always;
+    let mut start = 0;
+    let mut quote = None;
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                chars.next();
+            }
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ';' | '&') | (None, '|') if c != '|' || chars.peek().is_some_and(|(_, next)| *next == c) => {
+                let doubled = c != ';' && chars.next_if(|(_, next)| *next == c).is_some();
+                let command = input[start..index].trim();
+                if command.is_empty() {
+                    let operator = match (c, doubled) {
+                        (';', _) => ";",
+                        ('&', false) => "&",
+                        ('&', true) => "&&",
+                        _ => "||",
+                    };
+                    return Err(format!("parse error: missing command before `{}`", operator));
+                }
+                commands.push(ChainCommand { connector, text: command, background: c == '&' && !doubled });
+                connector = match (c, doubled) {
+                    ('&', true) => Connector::And,
+                    ('|', _) => Connector::Or,
+                    _ => Connector::Always,
+                };
+                start = index + if doubled { 2 } else { 1 };
+            }
+            _ => {}
+        }
+    }
+
+    let command = input[start..].trim();
+    if !command.is_empty() {
+        commands.push(ChainCommand { connector, text: command, background: false });
+    } else if connector != Connector::Always {
+        return Err("parse error: missing command at end of line".to_string());
+    }
+    Ok(commands)
+}
+
+// Cuts off an unquoted `#` comment, which starts at the beginning of the line or after
+// whitespace; a `#` inside a word, like `issue#4`, is kept.
+pub fn strip_comment(input: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    let mut chars = input.char_indices();
+
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                chars.next();
+            }
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') if previous.is_whitespace() => return &input[..index],
+            _ => {}
+        }
+        previous = c;
+    }
+    input
+}
+
+// `lookup` resolves a variable name to its value. `Ok(None)` keeps the reference
+// literally; an `Err` aborts parsing (used for `set -u`).
+pub fn tokenize<F>(input: &str, lookup: F) -> Result<Vec<Token>, String>
+where
+    F: Fn(&str) -> Result<Option<String>, String>,
+{
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    // Distinguishes an empty quoted word (`""`) from no word at all.
+    let mut in_word = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                    in_word = false;
+                }
+            }
+            // `->` on its own is the go-forward command, not `-` redirected.
+            '>' if word == "-" && in_word && tokens.is_empty() && chars.peek().is_none_or(|c| c.is_whitespace()) => {
+                word.push('>');
+            }
+            '>' => {
+                if in_word {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                    in_word = false;
+                }
+                let append = chars.next_if_eq(&'>').is_some();
+                tokens.push(Token::Redirect { append });
+            }
+            '|' => {
+                if in_word {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                    in_word = false;
+                }
+                tokens.push(Token::Pipe);
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("parse error: unterminated single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next_if(|c| matches!(c, '"' | '\\' | '$')) {
+                            Some(escaped) => word.push(escaped),
+                            None => word.push('\\'),
+                        },
+                        Some('$') => expand_variable(&mut chars, &mut word, &lookup)?,
+                        Some(c) => word.push(c),
+                        None => return Err("parse error: unterminated double quote".to_string()),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next_if(|c| ESCAPABLE.contains(c)) {
+                    Some(escaped) => word.push(escaped),
+                    None => word.push('\\'),
+                }
+            }
+            '$' => {
+                in_word = true;
+                expand_variable(&mut chars, &mut word, &lookup)?;
+            }
+            '~' if !in_word => {
+                in_word = true;
+                expand_tilde(&mut chars, &mut word, &lookup)?;
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+// Called for a `~` that starts an unquoted word. `~` and `~/...` are the home directory
+// (`$HOME`, or `$USERPROFILE` on Windows); `~name` is that user's, where it can be found.
+// Anything else, or an unknown user, stays literal.
+fn expand_tilde<F>(chars: &mut std::iter::Peekable<std::str::Chars<'_>>, word: &mut String, lookup: &F) -> Result<(), String>
+where
+    F: Fn(&str) -> Result<Option<String>, String>,
+{
+    let mut ahead = chars.clone();
+    let mut user = String::new();
+    while let Some(c) = ahead.next_if(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        user.push(c);
+    }
+    if !ahead.peek().is_none_or(|c| c.is_whitespace() || matches!(c, '/' | '\\' | '|' | '>')) {
+        word.push('~');
+        return Ok(());
+    }
+    let home = if user.is_empty() {
+        match lookup("HOME")? {
+            Some(home) => Some(home),
+            None => lookup("USERPROFILE")?,
+        }
+    } else {
+        user_home(&user)
+    };
+    match home {
+        Some(home) => {
+            *chars = ahead;
+            word.push_str(&home);
+        }
+        None => word.push('~'),
+    }
+    Ok(())
+}
+
+// Another user's home directory, from the password database.
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<String> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        (fields.len() > 5 && fields[0] == user).then(|| fields[5].to_string())
+    })
+}
+
+#[cfg(not(unix))]
+fn user_home(_user: &str) -> Option<String> {
+    None
+}
+
+// Called just after a `$`. Handles `$NAME`, `${NAME}`, `$?` and `$$` (a literal dollar sign);
+// a `$` followed by anything else is kept as-is.
+fn expand_variable<I, F>(chars: &mut std::iter::Peekable<I>, word: &mut String, lookup: &F) -> Result<(), String>
+where
+    I: Iterator<Item = char>,
+    F: Fn(&str) -> Result<Option<String>, String>,
+{
+    let (name, literal) = match chars.peek().copied() {
+        Some('$') => {
+            chars.next();
+            word.push('$');
+            return Ok(());
+        }
+        Some('{') => {
+            chars.next();
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err("parse error: unterminated ${".to_string()),
+                }
+            }
+            let literal = format!("${{{}}}", name);
+            (name, literal)
+        }
+        // The last command's exit status.
+        Some('?') => {
+            chars.next();
+            ("?".to_string(), "$?".to_string())
+        }
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            let literal = format!("${}", name);
+            (name, literal)
+        }
+        _ => {
+            word.push('$');
+            return Ok(());
+        }
+    };
+
+    match lookup(&name)? {
+        Some(value) => word.push_str(&value),
+        None => word.push_str(&literal),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_variables(_: &str) -> Result<Option<String>, String> {
+        Ok(None)
+    }
+
+    fn words(input: &str) -> Vec<String> {
+        let tokens = tokenize(input, |name| Ok((name == "NAME").then(|| "rubin".to_string()))).unwrap();
+        tokens
+            .into_iter()
+            .map(|token| match token {
+                Token::Word(word) => word,
+                other => panic!("expected only words, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quotes_keep_spaces_in_one_word() {
+        assert_eq!(words("touch \"my file.txt\" 'other file'"), ["touch", "my file.txt", "other file"]);
+        assert_eq!(words("echo \"\""), ["echo", ""]);
+    }
+
+    #[test]
+    fn quotes_nest_inside_the_other_kind() {
+        assert_eq!(words("echo \"it's\" 'say \"hi\"'"), ["echo", "it's", "say \"hi\""]);
+        assert_eq!(words("echo \"a \\\"b\\\" c\""), ["echo", "a \"b\" c"]);
+    }
+
+    #[test]
+    fn unmatched_quotes_are_errors() {
+        assert_eq!(tokenize("echo 'oops", no_variables), Err("parse error: unterminated single quote".to_string()));
+        assert_eq!(tokenize("echo \"oops", no_variables), Err("parse error: unterminated double quote".to_string()));
+    }
+
+    #[test]
+    fn escaped_space_joins_a_word() {
+        assert_eq!(words("touch my\\ file.txt"), ["touch", "my file.txt"]);
+        assert_eq!(words("dir C:\\Users"), ["dir", "C:\\Users"]);
+    }
+
+    #[test]
+    fn variables_expand_in_double_quotes_only() {
+        assert_eq!(words("echo \"$NAME\" '$NAME' $NAME"), ["echo", "rubin", "$NAME", "rubin"]);
+        assert_eq!(words("echo $UNSET $$NAME ${NAME}s"), ["echo", "$UNSET", "$NAME", "rubins"]);
+    }
+
+    #[test]
+    fn redirections_split_words_unless_quoted() {
+        let tokens = tokenize("dir>out.txt '>' x\\>y >> log", no_variables).unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("dir".to_string()),
+                Token::Redirect { append: false },
+                Token::Word("out.txt".to_string()),
+                Token::Word(">".to_string()),
+                Token::Word("x>y".to_string()),
+                Token::Redirect { append: true },
+                Token::Word("log".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pipes_separate_commands_unless_quoted() {
+        let tokens = tokenize("type a.txt|nl '|' \\|", no_variables).unwrap();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("type".to_string()),
+                Token::Word("a.txt".to_string()),
+                Token::Pipe,
+                Token::Word("nl".to_string()),
+                Token::Word("|".to_string()),
+                Token::Word("|".to_string()),
+            ]
+        );
+    }
+
+    fn chain(input: &str) -> Vec<(Connector, &str, bool)> {
+        split_chain(input).unwrap().into_iter().map(|command| (command.connector, command.text, command.background)).collect()
+    }
+
+    #[test]
+    fn chains_split_on_connectors() {
+        assert_eq!(
+            chain("mkdir x && cd x; false || echo fallback"),
+            [
+                (Connector::Always, "mkdir x", false),
+                (Connector::And, "cd x", false),
+                (Connector::Always, "false", false),
+                (Connector::Or, "echo fallback", false),
+            ]
+        );
+        assert_eq!(chain("sleep 5 & echo started"), [(Connector::Always, "sleep 5", true), (Connector::Always, "echo started", false)]);
+        // Quoted or escaped operators are part of the command, and `|` on its own is a pipe.
+        assert_eq!(chain(r"echo 'a && b' \; c | wc"), [(Connector::Always, r"echo 'a && b' \; c | wc", false)]);
+    }
+
+    #[test]
+    fn chains_need_a_command_on_each_side() {
+        assert_eq!(split_chain("&& ls").err().unwrap(), "parse error: missing command before `&&`");
+        assert_eq!(split_chain("ls ;; ls").err().unwrap(), "parse error: missing command before `;`");
+        assert_eq!(split_chain("ls ||").err().unwrap(), "parse error: missing command at end of line");
+        assert_eq!(split_chain("& ls").err().unwrap(), "parse error: missing command before `&`");
+        assert!(split_chain("ls;").is_ok());
+    }
+
+    #[test]
+    fn comments_are_cut_outside_quotes() {
+        assert_eq!(strip_comment("# whole line"), "");
+        assert_eq!(strip_comment("echo hi # trailing"), "echo hi ");
+        assert_eq!(strip_comment("echo \"a # b\""), "echo \"a # b\"");
+        assert_eq!(strip_comment("echo 'a # b' # c"), "echo 'a # b' ");
+        assert_eq!(strip_comment("echo issue#4 \\#5"), "echo issue#4 \\#5");
+    }
+
+    fn words_with_home(input: &str) -> Vec<String> {
+        let tokens = tokenize(input, |name| Ok((name == "HOME").then(|| "/home/ada".to_string()))).unwrap();
+        tokens.into_iter().filter_map(|token| if let Token::Word(word) = token { Some(word) } else { None }).collect()
+    }
+
+    #[test]
+    fn tilde_expands_at_the_start_of_unquoted_words() {
+        assert_eq!(words_with_home("cd ~"), ["cd", "/home/ada"]);
+        assert_eq!(words_with_home("cd ~/sub"), ["cd", "/home/ada/sub"]);
+        assert_eq!(words_with_home("echo \"~\" '~/x' a~b"), ["echo", "~", "~/x", "a~b"]);
+        assert_eq!(words_with_home("echo ~nobody-here-at-all"), ["echo", "~nobody-here-at-all"]);
+    }
+}
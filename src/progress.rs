@@ -0,0 +1,101 @@
+// A running count on stderr for slow recursive commands, like "find: scanned 12,340
+// entries...". Nothing is drawn for the first half second, or ever unless stderr is a
+// terminal, so quick commands and redirected output stay clean. The line is erased
+// again when the reporter is dropped.
+
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const DELAY: Duration = Duration::from_millis(500);
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Progress {
+    // What's being counted, as in "find: scanned" ... "entries".
+    verb: &'static str,
+    noun: &'static str,
+    count: u64,
+    started: Instant,
+    last_drawn: Option<Instant>,
+    // Whether the line is on screen right now.
+    shown: bool,
+    enabled: bool,
+}
+
+impl Progress {
+    // `quiet` is the command's `--quiet`, for turning it off on a terminal too.
+    pub fn new(verb: &'static str, noun: &'static str, quiet: bool) -> Self {
+        let enabled = !quiet && io::stderr().is_terminal();
+        Progress { verb, noun, count: 0, started: Instant::now(), last_drawn: None, shown: false, enabled }
+    }
+
+    // Counts one more item, redrawing the line if it's due.
+    pub fn tick(&mut self) {
+        self.count += 1;
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        if now.duration_since(self.started) < DELAY || self.last_drawn.is_some_and(|drawn| now.duration_since(drawn) < REDRAW_INTERVAL) {
+            return;
+        }
+        eprint!("\r\x1b[K{} {} {}...", self.verb, group_digits(self.count), self.noun);
+        let _ = io::stderr().flush();
+        self.last_drawn = Some(now);
+        self.shown = true;
+    }
+
+    // Erases the line, so other output can be printed; the next due tick redraws it.
+    pub fn clear(&mut self) {
+        if self.shown {
+            eprint!("\r\x1b[K");
+            self.shown = false;
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+// `12340` as `12,340`.
+pub fn group_digits(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_digits_in_threes() {
+        assert_eq!(group_digits(0), "0");
+        assert_eq!(group_digits(999), "999");
+        assert_eq!(group_digits(1000), "1,000");
+        assert_eq!(group_digits(12340), "12,340");
+        assert_eq!(group_digits(1234567), "1,234,567");
+        assert_eq!(group_digits(u64::MAX), "18,446,744,073,709,551,615");
+    }
+
+    #[test]
+    fn counts_without_drawing_when_disabled() {
+        let mut progress = Progress::new("find: scanned", "entries", true);
+        assert!(!progress.enabled);
+        for _ in 0..5 {
+            progress.tick();
+        }
+        assert_eq!(progress.count, 5);
+        assert!(!progress.shown);
+        progress.clear();
+        assert!(!progress.shown);
+    }
+}
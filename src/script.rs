@@ -0,0 +1,152 @@
+// Parses `.rubin` scripts into the statements the shell runs. Commands stay as text, so
+// variables are expanded when each one runs rather than when the script is read.
+
+use crate::parser;
+
+pub enum Statement {
+    Command(String),
+    // `if <condition>; then ... [else ...] fi`: picks a branch by the condition's status.
+    If { condition: String, then_branch: Vec<Statement>, else_branch: Vec<Statement> },
+    // `for <variable> in <words>; do ... done`.
+    For { line: usize, variable: String, words: String, body: Vec<Statement> },
+}
+
+// A syntax error and the (1-based) line it was found on.
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+pub fn parse(source: &str) -> Result<Vec<Statement>, ScriptError> {
+    let lines: Vec<(usize, &str)> =
+        source.lines().enumerate().map(|(index, line)| (index + 1, parser::strip_comment(line).trim())).filter(|(_, line)| !line.is_empty()).collect();
+    let mut position = 0;
+    Ok(parse_block(&lines, &mut position, &[])?.0)
+}
+
+// Statements, and the keyword that ended them.
+type Block = (Vec<Statement>, Option<&'static str>);
+
+// Parses statements up to one of the `terminators`. Only the top level, which has none,
+// may run out of lines instead.
+fn parse_block(lines: &[(usize, &str)], position: &mut usize, terminators: &[&'static str]) -> Result<Block, ScriptError> {
+    let mut statements = Vec::new();
+    while let Some(&(line, text)) = lines.get(*position) {
+        *position += 1;
+        if let Some(keyword) = ["fi", "else", "done", "then", "do"].into_iter().find(|keyword| text == *keyword) {
+            if terminators.contains(&keyword) {
+                return Ok((statements, Some(keyword)));
+            }
+            return Err(ScriptError { line, message: format!("unexpected `{}`", keyword) });
+        }
+
+        if let Some(rest) = strip_keyword(text, "if") {
+            let condition = header(lines, position, line, rest, "if", "then")?;
+            let (then_branch, end) = parse_block(lines, position, &["else", "fi"])?;
+            let else_branch = match end {
+                Some("else") => parse_block(lines, position, &["fi"])?.0,
+                _ => Vec::new(),
+            };
+            statements.push(Statement::If { condition, then_branch, else_branch });
+        } else if let Some(rest) = strip_keyword(text, "for") {
+            let header = header(lines, position, line, rest, "for", "do")?;
+            let Some((variable, words)) = header.split_once(char::is_whitespace).and_then(|(variable, rest)| {
+                let words = rest.trim_start().strip_prefix("in")?;
+                (words.is_empty() || words.starts_with(char::is_whitespace)).then(|| (variable, words.trim()))
+            }) else {
+                return Err(ScriptError { line, message: "expected `for <name> in <words>; do`".to_string() });
+            };
+            if !is_variable_name(variable) {
+                return Err(ScriptError { line, message: format!("`{}` is not a valid variable name", variable) });
+            }
+            let (body, _) = parse_block(lines, position, &["done"])?;
+            statements.push(Statement::For { line, variable: variable.to_string(), words: words.to_string(), body });
+        } else {
+            statements.push(Statement::Command(text.to_string()));
+        }
+    }
+    if let Some(expected) = terminators.last() {
+        let line = lines.last().map_or(0, |(line, _)| *line);
+        return Err(ScriptError { line, message: format!("unexpected end of script (expected `{}`)", expected) });
+    }
+    Ok((statements, None))
+}
+
+// `text` without its leading `keyword`, if that is the first word.
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let rest = text.strip_prefix(keyword)?;
+    rest.starts_with(char::is_whitespace).then(|| rest.trim_start())
+}
+
+// The part of an `if`/`for` line before `; then`/`; do`, which may instead be on a line
+// of its own straight after.
+fn header(
+    lines: &[(usize, &str)],
+    position: &mut usize,
+    line: usize,
+    rest: &str,
+    keyword: &str,
+    opener: &str,
+) -> Result<String, ScriptError> {
+    let head = match rest.strip_suffix(opener).and_then(|head| head.trim_end().strip_suffix(';')) {
+        Some(head) => head.trim_end(),
+        None if lines.get(*position).is_some_and(|(_, text)| *text == opener) => {
+            *position += 1;
+            rest
+        }
+        None => return Err(ScriptError { line, message: format!("expected `{}` after `{} ...`", opener, keyword) }),
+    };
+    if head.is_empty() {
+        return Err(ScriptError { line, message: format!("`{}` is missing its header", keyword) });
+    }
+    Ok(head.to_string())
+}
+
+// The names `$NAME` expansion can refer to.
+pub fn is_variable_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> (usize, String) {
+        match parse(source) {
+            Ok(_) => panic!("expected a syntax error"),
+            Err(e) => (e.line, e.message),
+        }
+    }
+
+    #[test]
+    fn parses_nested_blocks() {
+        let source = "set n=1\n# comment\n\nif test -f x; then\n  for f in a b; do\n    echo $f\n  done\nelse\n  echo none\nfi\n";
+        let statements = parse(source).unwrap_or_else(|e| panic!("line {}: {}", e.line, e.message));
+        let [Statement::Command(first), Statement::If { condition, then_branch, else_branch }] = &statements[..] else {
+            panic!("expected a command and an if");
+        };
+        assert_eq!(first, "set n=1");
+        assert_eq!(condition, "test -f x");
+        let [Statement::For { line: 5, variable, words, body }] = &then_branch[..] else {
+            panic!("expected a for loop");
+        };
+        assert_eq!((variable.as_str(), words.as_str()), ("f", "a b"));
+        assert!(matches!(&body[..], [Statement::Command(command)] if command == "echo $f"));
+        assert!(matches!(&else_branch[..], [Statement::Command(command)] if command == "echo none"));
+    }
+
+    #[test]
+    fn openers_may_be_on_their_own_line() {
+        let statements = parse("if true\nthen\necho yes\nfi\nfor x in 1\ndo\necho $x\ndone\n").unwrap_or_else(|e| panic!("{}", e.message));
+        assert_eq!(statements.len(), 2);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(error("echo a\nfi\n"), (2, "unexpected `fi`".to_string()));
+        assert_eq!(error("if true; then\necho a\n"), (2, "unexpected end of script (expected `fi`)".to_string()));
+        assert_eq!(error("if true\necho a\nfi\n"), (1, "expected `then` after `if ...`".to_string()));
+        assert_eq!(error("for 1x in a; do\ndone\n"), (1, "`1x` is not a valid variable name".to_string()));
+        assert_eq!(error("for x a b; do\ndone\n"), (1, "expected `for <name> in <words>; do`".to_string()));
+    }
+}
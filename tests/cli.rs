@@ -0,0 +1,516 @@
+// Runs the built shell the way a user or script would, with its home directory in a
+// throwaway directory.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A fresh directory under the system temp dir, removed again when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("rubin-cli-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = env::temp_dir().join(name);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path.canonicalize().unwrap())
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// The shell, started in `home` and treating it as the home directory.
+fn rubin(home: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_Rubin"));
+    command.current_dir(home).env("HOME", home).env_remove("RUBIN_CONFIG").env_remove("RUBIN_SAFE_MODE").stderr(Stdio::piped());
+    command
+}
+
+// Feeds `script` to the shell's stdin and returns what it printed.
+fn run_piped(command: &mut Command, script: &str) -> (std::process::ExitStatus, String) {
+    let (status, stdout, _) = run_piped_with_stderr(command, script);
+    (status, stdout)
+}
+
+// Like `run_piped`, but also returns what went to stderr.
+fn run_piped_with_stderr(command: &mut Command, script: &str) -> (std::process::ExitStatus, String, String) {
+    use std::io::Write;
+    let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    (output.status, stdout, String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+#[test]
+fn help_flag_prints_usage_without_running() {
+    let home = TempDir::new();
+    fs::write(home.path().join("a.txt"), "a").unwrap();
+    let (_, output) = run_piped(&mut rubin(home.path()), "copy --help a.txt b.txt\nexit\n");
+    assert!(output.contains("Usage: copy [-n | -f] [-v] [--verify] [--quiet] <source>... <destination>\n"), "{}", output);
+    assert!(!home.path().join("b.txt").exists());
+}
+
+#[test]
+fn replace_without_apply_only_counts() {
+    let home = TempDir::new();
+    fs::write(home.path().join("notes.txt"), "v1.0.3 and v1.0.3\n").unwrap();
+    let (_, output) = run_piped(&mut rubin(home.path()), "replace 1.0.3 1.0.4 notes.txt\nexit\n");
+    assert!(output.contains("notes.txt: 2 replacements\n"), "{}", output);
+    assert_eq!(fs::read_to_string(home.path().join("notes.txt")).unwrap(), "v1.0.3 and v1.0.3\n");
+    assert!(!home.path().join("notes.txt.bak").exists());
+}
+
+#[test]
+fn nl_numbers_non_blank_lines() {
+    let home = TempDir::new();
+    fs::write(home.path().join("list.txt"), "one\n\ntwo\n").unwrap();
+    let (_, output) = run_piped(&mut rubin(home.path()), "nl list.txt\nexit\n");
+    assert!(output.contains("     1\tone\n       \n     2\ttwo\n"), "{:?}", output);
+}
+
+#[test]
+fn nl_takes_a_separator_and_width() {
+    let home = TempDir::new();
+    fs::write(home.path().join("list.txt"), "one\n\ntwo\n").unwrap();
+    let (_, output) = run_piped(&mut rubin(home.path()), "nl -w 2 -s : -b a list.txt\nexit\n");
+    assert!(output.contains(" 1:one\n 2:\n 3:two\n"), "{:?}", output);
+}
+
+#[cfg(unix)]
+fn terminate(child: &std::process::Child) {
+    assert!(Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn sigterm_at_the_prompt_exits_143() {
+    use std::thread;
+    use std::time::Duration;
+
+    let home = TempDir::new();
+    let mut child = rubin(home.path()).stdin(Stdio::piped()).stdout(Stdio::null()).spawn().unwrap();
+    // Held open, so it's the signal that ends the shell rather than the end of input.
+    let stdin = child.stdin.take().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    terminate(&child);
+    assert_eq!(child.wait().unwrap().code(), Some(143));
+    drop(stdin);
+}
+
+#[cfg(unix)]
+#[test]
+fn sigterm_during_a_command_lets_it_finish_then_exits() {
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    let home = TempDir::new();
+    fs::write(home.path().join("slow.sh"), "sleep 1\ntouch finished\n").unwrap();
+    let mut child = rubin(home.path()).stdin(Stdio::piped()).stdout(Stdio::null()).spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "run slow.sh").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    terminate(&child);
+    // Queued behind the running command, so it never gets to run.
+    writeln!(stdin, "mkdir after").unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(143));
+    drop(stdin);
+    assert!(home.path().join("finished").exists());
+    assert!(!home.path().join("after").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn sigint_abandons_the_rest_of_the_line_but_keeps_the_shell() {
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    let home = TempDir::new();
+    let mut child = rubin(home.path()).stdin(Stdio::piped()).stdout(Stdio::null()).spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "sleep 1; mkdir skipped").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap().success());
+    writeln!(stdin, "mkdir after").unwrap();
+    drop(stdin);
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    assert!(!home.path().join("skipped").exists());
+    assert!(home.path().join("after").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn watch_reruns_until_interrupted() {
+    use std::thread;
+    use std::time::Duration;
+
+    let home = TempDir::new();
+    let child = rubin(home.path()).args(["--norc", "-c", "watch -n 0.1 echo tick"]).stdout(Stdio::piped()).spawn().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap().success());
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("Every 0.1s: echo tick    "), "{}", stdout);
+    assert!(stdout.matches("tick\n").count() >= 2, "{}", stdout);
+}
+
+#[cfg(unix)]
+#[test]
+fn tail_follow_prints_appended_lines() {
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    let home = TempDir::new();
+    let log = home.path().join("log.txt");
+    fs::write(&log, "old\n").unwrap();
+    let mut child = rubin(home.path()).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "tail -f log.txt").unwrap();
+    thread::sleep(Duration::from_millis(700));
+    fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"new line\n").unwrap();
+    // Follow mode polls twice a second.
+    thread::sleep(Duration::from_millis(1200));
+    fs::write(&log, "fresh\n").unwrap();
+    thread::sleep(Duration::from_millis(1200));
+    terminate(&child);
+
+    let output = child.wait_with_output().unwrap();
+    drop(stdin);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.ends_with("old\nnew line\nfresh\n"), "{}", stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("tail: {}: file truncated\n", log.display())), "{}", stderr);
+}
+
+#[test]
+fn clipboard_round_trip() {
+    let home = TempDir::new();
+    fs::write(home.path().join("clip.txt"), "round trip\n").unwrap();
+    let (_, _, errors) = run_piped_with_stderr(&mut rubin(home.path()), "copy-clip clip.txt\npaste-clip pasted.txt\nexit\n");
+    if errors.contains("No clipboard available") {
+        eprintln!("no clipboard available; skipping");
+        return;
+    }
+    assert_eq!(fs::read_to_string(home.path().join("pasted.txt")).unwrap(), "round trip\n");
+}
+
+#[test]
+fn find_combines_name_and_size_filters() {
+    let home = TempDir::new();
+    fs::create_dir_all(home.path().join("src/deep")).unwrap();
+    fs::write(home.path().join("src/big.log"), vec![0; 3000]).unwrap();
+    fs::write(home.path().join("src/small.log"), "x").unwrap();
+    fs::write(home.path().join("src/deep/huge.log"), vec![0; 5000]).unwrap();
+    fs::write(home.path().join("src/deep/huge.txt"), vec![0; 5000]).unwrap();
+    let (_, output) = run_piped(&mut rubin(home.path()), "find --name *.log --size +2k\nexit\n");
+    assert!(output.contains("src/big.log\n"), "{}", output);
+    assert!(output.contains("src/deep/huge.log\n"), "{}", output);
+    assert!(!output.contains("small.log"), "{}", output);
+    assert!(!output.contains("huge.txt"), "{}", output);
+}
+
+#[test]
+fn set_x_traces_commands_on_stderr() {
+    use std::io::Write;
+    let home = TempDir::new();
+    let mut child = rubin(home.path()).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"set -x\nwhoami\nexit\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("+ whoami\n"), "{}", stderr);
+    assert!(!stderr.contains("+ set -x"), "{}", stderr);
+}
+
+#[test]
+fn set_u_makes_scripts_fail_on_unset_variables() {
+    let home = TempDir::new();
+    fs::write(home.path().join("vars.sh"), "echo before\necho $RUBIN_TEST_UNSET\necho after\n").unwrap();
+    let (_, output) = run_piped(rubin(home.path()).env_remove("RUBIN_TEST_UNSET"), "set -u\nrun vars.sh\nexit\n");
+    assert!(output.contains("before\n"), "{}", output);
+    assert!(!output.contains("after"), "{}", output);
+
+    let (_, output) = run_piped(rubin(home.path()).env_remove("RUBIN_TEST_UNSET"), "run vars.sh\nexit\n");
+    assert!(output.contains("after\n"), "{}", output);
+}
+
+#[cfg(unix)]
+#[test]
+fn clear_without_a_clear_program_reports_instead_of_panicking() {
+    let home = TempDir::new();
+    let (status, _, errors) = run_piped_with_stderr(rubin(home.path()).env("PATH", ""), "clear\nmkdir made\nexit\n");
+    assert!(status.success());
+    assert!(errors.contains("Failed to clear the screen"), "{}", errors);
+    assert!(home.path().join("made").is_dir());
+}
+
+#[test]
+fn custom_commands_and_env_vars_persist_between_sessions() {
+    let home = TempDir::new();
+    run_piped(&mut rubin(home.path()), "setenv GREETING hi\ncc create greet echo Greets\nexit\n");
+    let state = fs::read_to_string(home.path().join(".rubin/state.json")).unwrap();
+    assert!(state.contains("\"GREETING\": \"hi\""), "{}", state);
+
+    let (_, output) = run_piped(&mut rubin(home.path()), "cc list\nexit\n");
+    assert!(output.contains("greet"), "{}", output);
+
+    let config = home.path().join("elsewhere.json");
+    run_piped(rubin(home.path()).env("RUBIN_CONFIG", &config), "setenv OTHER 1\nexit\n");
+    assert!(fs::read_to_string(&config).unwrap().contains("OTHER"));
+}
+
+#[test]
+fn corrupt_state_file_is_reported_and_ignored() {
+    let home = TempDir::new();
+    fs::create_dir(home.path().join(".rubin")).unwrap();
+    fs::write(home.path().join(".rubin/state.json"), "{not json").unwrap();
+    let (status, _, errors) = run_piped_with_stderr(&mut rubin(home.path()), "cc list\nexit\n");
+    assert!(status.success());
+    assert!(errors.contains("Warning: corrupt state file"), "{}", errors);
+}
+
+#[cfg(unix)]
+#[test]
+fn custom_commands_run_with_their_arguments() {
+    let home = TempDir::new();
+    fs::create_dir(home.path().join(".rubin")).unwrap();
+    let state = r#"{"custom_commands": [
+        {"name": "greet", "definition": "echo hello $1", "description": "Greets", "completion": null},
+        {"name": "fail", "definition": "false", "description": "Fails", "completion": null}
+    ]}"#;
+    fs::write(home.path().join(".rubin/state.json"), state).unwrap();
+    let (_, output, errors) = run_piped_with_stderr(&mut rubin(home.path()), "greet world\nfail\nexit\n");
+    assert!(output.contains("hello world\n"), "{}", output);
+    assert!(errors.contains("Command exited with status 1\n"), "{}", errors);
+}
+
+#[cfg(unix)]
+#[test]
+fn setenv_and_unsetenv_reach_child_processes() {
+    let home = TempDir::new();
+    fs::write(home.path().join("env.sh"), "echo \"[$RUBIN_TEST_CHILD_VAR]\"\n").unwrap();
+    let script = "setenv RUBIN_TEST_CHILD_VAR propagated\nrun env.sh\nunsetenv RUBIN_TEST_CHILD_VAR\nrun env.sh\nexit\n";
+    let (_, output) = run_piped(rubin(home.path()).env_remove("RUBIN_TEST_CHILD_VAR"), script);
+    assert!(output.contains("[propagated]\n"), "{}", output);
+    assert!(output.contains("[]\n"), "{}", output);
+}
+
+#[cfg(unix)]
+#[test]
+fn redirection_captures_child_output() {
+    let home = TempDir::new();
+    fs::write(home.path().join("hello.sh"), "echo from the script\n").unwrap();
+    let (_, output) = run_piped(&mut rubin(home.path()), "run hello.sh > out.txt\nexit\n");
+    assert!(!output.contains("from the script"), "{}", output);
+    assert_eq!(fs::read_to_string(home.path().join("out.txt")).unwrap(), "from the script\n");
+}
+
+#[test]
+fn command_history_is_loaded_but_piped_lines_are_not_added() {
+    let home = TempDir::new();
+    fs::create_dir(home.path().join(".rubin")).unwrap();
+    fs::write(home.path().join(".rubin/history"), "pwd\nwhoami\n").unwrap();
+    let (_, output) = run_piped(&mut rubin(home.path()), "history\nmkdir x\nexit\n");
+    assert_eq!(output, "1  pwd\n2  whoami\n");
+    assert_eq!(fs::read_to_string(home.path().join(".rubin/history")).unwrap(), "pwd\nwhoami\n");
+}
+
+#[test]
+fn rmdir_r_asks_before_deleting_a_tree() {
+    let home = TempDir::new();
+    fs::create_dir_all(home.path().join("tree/leaf")).unwrap();
+    let (_, _, errors) = run_piped_with_stderr(&mut rubin(home.path()), "rmdir -r tree\nn\nrmdir missing\nexit\n");
+    assert!(home.path().join("tree/leaf").is_dir());
+    assert!(errors.contains("Remove tree and everything in it? [y/N] rmdir: tree: not removed\n"), "{}", errors);
+    assert!(errors.contains("rmdir: missing: No such directory\n"), "{}", errors);
+
+    run_piped(&mut rubin(home.path()), "rmdir -r tree\ny\nexit\n");
+    assert!(!home.path().join("tree").exists());
+}
+
+#[test]
+fn aliases_persist_between_sessions() {
+    let home = TempDir::new();
+    run_piped(&mut rubin(home.path()), "alias made=\"mkdir made\"\nexit\n");
+    run_piped(&mut rubin(home.path()), "made\nexit\n");
+    assert!(home.path().join("made").is_dir());
+}
+
+#[cfg(unix)]
+#[test]
+fn reload_picks_up_the_state_file_and_keeps_memory_when_it_is_corrupt() {
+    let home = TempDir::new();
+    fs::write(home.path().join("new.json"), r#"{"aliases": {"made": "mkdir made"}}"#).unwrap();
+    fs::write(home.path().join("bad.json"), "garbage").unwrap();
+    let script = "alias old=\"mkdir old\"\nsh -c 'cp new.json .rubin/state.json'\nreload\nmade\nold\n\
+                  sh -c 'cp bad.json .rubin/state.json'\nreload\nrmdir made\nmade\n";
+    let (_, stdout, errors) = run_piped_with_stderr(rubin(home.path()).arg("--norc"), script);
+    assert!(stdout.contains("Reloaded 1 alias, 0 custom commands and 0 variables."), "{}", stdout);
+    assert!(!home.path().join("old").exists());
+    assert!(errors.contains("reload: corrupt state file"), "{}", errors);
+    // Still defined after the failed reload.
+    assert!(home.path().join("made").is_dir());
+}
+
+#[test]
+fn dash_c_runs_one_command_and_exits_with_its_status() {
+    let home = TempDir::new();
+    let output = rubin(home.path()).args(["-c", "mkdir x && cd x; pwd"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}\n", home.path().join("x").display()));
+
+    let output = rubin(home.path()).args(["-c", "cd nowhere"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cd: nowhere: No such directory"));
+
+    let output = rubin(home.path()).arg("-c").output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn a_command_file_runs_line_by_line() {
+    let home = TempDir::new();
+    fs::write(home.path().join("setup.rubin"), "mkdir build\ncd build\nmkdir out\n").unwrap();
+    let output = rubin(home.path()).arg("setup.rubin").output().unwrap();
+    assert!(output.status.success());
+    assert!(home.path().join("build/out").is_dir());
+}
+
+#[test]
+fn rubinrc_runs_at_startup_unless_norc() {
+    let rc = "# startup\nsetenv FROM_RC yes\ncd nowhere\nalias made=\"mkdir made-$FROM_RC\"\n";
+    let home = TempDir::new();
+    fs::write(home.path().join(".rubinrc"), rc).unwrap();
+    let (status, _, errors) = run_piped_with_stderr(&mut rubin(home.path()), "made\nexit\n");
+    assert!(status.success());
+    assert!(home.path().join("made-yes").is_dir());
+    let warning = format!("Warning: {} line 3 failed with status 1", home.path().join(".rubinrc").display());
+    assert!(errors.contains(&warning), "{}", errors);
+
+    // A fresh home, since what the rc file set was saved with the rest of the state.
+    let home = TempDir::new();
+    fs::write(home.path().join(".rubinrc"), rc).unwrap();
+    run_piped(rubin(home.path()).arg("--norc"), "mkdir made-$FROM_RC\nexit\n");
+    assert!(home.path().join("made-$FROM_RC").is_dir());
+}
+
+#[test]
+fn end_of_input_exits_with_the_last_status() {
+    let home = TempDir::new();
+    let (status, _) = run_piped(rubin(home.path()).arg("--norc"), "mkdir first\nmkdir last");
+    assert!(status.success());
+    assert!(home.path().join("last").is_dir());
+
+    let (status, _) = run_piped(rubin(home.path()).arg("--norc"), "cd nowhere\n");
+    assert_eq!(status.code(), Some(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn a_deleted_working_directory_starts_the_shell_at_home() {
+    let home = TempDir::new();
+    let script = format!("mkdir gone && cd gone && rmdir ../gone && exec '{}' --norc", env!("CARGO_BIN_EXE_Rubin"));
+    // `rubin()` can't be used: the shell has to be started from inside the removed directory.
+    let mut shell = Command::new("sh");
+    shell.args(["-c", &script]).current_dir(home.path()).env("HOME", home.path()).env_remove("RUBIN_CONFIG").stderr(Stdio::piped());
+    let (status, stdout, errors) = run_piped_with_stderr(&mut shell, "pwd\n");
+    assert!(status.success());
+    assert!(errors.contains("Warning: current directory is unavailable"), "{}", errors);
+    assert!(stdout.contains(&format!("{}\n", home.path().display())), "{}", stdout);
+}
+
+#[test]
+fn copy_verbose_lists_every_file() {
+    let home = TempDir::new();
+    let src = home.path().join("src");
+    fs::create_dir_all(src.join("deep")).unwrap();
+    for name in ["a.txt", "b.txt", "deep/c.txt"] {
+        fs::write(src.join(name), name).unwrap();
+    }
+    let output = rubin(home.path()).args(["--norc", "-c", "copy -v src out"]).output().unwrap();
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut listed: Vec<&str> = stderr.lines().filter(|line| line.contains(" -> ")).collect();
+    listed.sort_unstable();
+    let out = home.path().join("out");
+    let expected: Vec<String> = ["a.txt", "b.txt", "deep/c.txt"]
+        .iter()
+        .map(|name| format!("{} -> {}", src.join(name).display(), out.join(name).display()))
+        .collect();
+    assert_eq!(listed, expected);
+    assert!(stderr.contains("copy: 3 files, 20 bytes copied"), "{}", stderr);
+}
+
+#[test]
+fn time_reports_on_stderr() {
+    let home = TempDir::new();
+    let output = rubin(home.path()).args(["--norc", "-c", "time echo done"]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "done\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("real "), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn second_exit_kills_running_jobs_and_quits() {
+    use std::time::{Duration, Instant};
+
+    let home = TempDir::new();
+    let started = Instant::now();
+    let mut command = rubin(home.path());
+    command.arg("--norc");
+    let (status, stdout) = run_piped(&mut command, "sleep 30 &\nexit\necho still here\nexit\necho not reached\n");
+    assert!(status.success());
+    assert!(stdout.contains("still here\n") && !stdout.contains("not reached"), "{}", stdout);
+    assert!(started.elapsed() < Duration::from_secs(20));
+}
+
+#[cfg(unix)]
+#[test]
+fn exit_f_quits_straight_away_with_running_jobs() {
+    let home = TempDir::new();
+    let (status, stdout) = run_piped(rubin(home.path()).arg("--norc"), "sleep 30 &\nexit -f\necho not reached\n");
+    assert!(status.success());
+    assert!(!stdout.contains("not reached"), "{}", stdout);
+}
+
+#[test]
+fn source_reports_the_line_of_a_malformed_entry() {
+    let home = TempDir::new();
+    fs::write(home.path().join(".env"), "# settings\nGOOD=1\n\nnot an assignment\n").unwrap();
+    let output = rubin(home.path()).args(["--norc", "-c", "source .env"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("source: .env: line 4: expected KEY=value"));
+}
+
+#[test]
+fn version_flag_prints_version_info() {
+    let home = TempDir::new();
+    let output = rubin(home.path()).arg("--version").output().unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with(&format!("rubin {} (", env!("CARGO_PKG_VERSION"))));
+}
+
+#[test]
+fn commands_piped_into_stdin_run_without_a_prompt() {
+    let home = TempDir::new();
+    let script = "mkdir work\ncd work\n# a comment\n\npwd\necho done\n";
+    let (status, stdout) = run_piped(rubin(home.path()).arg("--norc"), script);
+    assert!(status.success());
+    assert_eq!(stdout, format!("{}\ndone\n", home.path().join("work").display()));
+}